@@ -28,7 +28,7 @@ use {
         send_transaction_until_expired,
         token::*,
         vendor::{
-            kamino, marginfi_v2,
+            drift, kamino, marginfi_v2,
             solend::{self, math::TryMul},
         },
         *,
@@ -77,6 +77,12 @@ lazy_static::lazy_static! {
             Token::WIF,
             Token::BONK,
         ])),
+        ("drift", HashSet::from([
+            Token::USDC,
+            Token::USDT,
+            Token::JitoSOL,
+            Token::wSOL,
+        ])),
     ]);
 }
 
@@ -324,6 +330,8 @@ fn pool_supply_apr(
         solend_apr(pool, token, account_data_cache)?
     } else if pool == "mfi" {
         mfi_apr(token, account_data_cache)?
+    } else if pool == "drift" {
+        drift_apr(token, account_data_cache)?
     } else {
         unreachable!()
     })
@@ -356,6 +364,8 @@ async fn pool_supply_balance(
         solend_deposited_amount(pool, address, token, account_data_cache)?
     } else if pool == "mfi" {
         mfi_deposited_amount(address, token, account_data_cache).await?
+    } else if pool == "drift" {
+        drift_deposited_amount(address, token, account_data_cache)?
     } else {
         unreachable!()
     })
@@ -434,6 +444,8 @@ async fn build_instructions_for_ops(
             solend_deposit_or_withdraw(*op, pool, address, token, amount, account_data_cache)?
         } else if *pool == "mfi" {
             mfi_deposit_or_withdraw(*op, address, token, amount, false, account_data_cache).await?
+        } else if *pool == "drift" {
+            drift_deposit_or_withdraw(*op, address, token, amount, account_data_cache)?
         } else {
             unreachable!();
         };
@@ -2907,3 +2919,338 @@ fn solend_deposit_or_withdraw(
         address_lookup_table: Some(pubkey!["89ig7Cu6Roi9mJMqpY8sBkPYL2cnqzpgP16sJxSUbvct"]),
     })
 }
+
+/*
+//////////////////////////////////////////////////////////////////////////////
+///[ Drift Stuff ] ///////////////////////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////
+*/
+
+const DRIFT_PROGRAM: Pubkey = pubkey!["dRiftyHA39MWEi3m9aunc5MzRF1JYuBsbn6VPcn33UH"];
+
+fn drift_spot_market_index(token: Token) -> Result<u16, Box<dyn std::error::Error>> {
+    match token {
+        Token::USDC => Some(0),
+        Token::wSOL => Some(1),
+        Token::USDT => Some(5),
+        Token::JitoSOL => Some(6),
+        _ => None,
+    }
+    .ok_or_else(|| format!("drift: {token} is not supported").into())
+}
+
+fn drift_find_state_address() -> Pubkey {
+    Pubkey::find_program_address(&[b"drift_state"], &DRIFT_PROGRAM).0
+}
+
+fn drift_find_signer_address() -> Pubkey {
+    Pubkey::find_program_address(&[b"drift_signer"], &DRIFT_PROGRAM).0
+}
+
+fn drift_find_spot_market_address(market_index: u16) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"spot_market", &market_index.to_le_bytes()],
+        &DRIFT_PROGRAM,
+    )
+    .0
+}
+
+fn drift_find_user_address(wallet_address: Pubkey) -> Pubkey {
+    let sub_account_id: u16 = 0;
+    Pubkey::find_program_address(
+        &[
+            b"user",
+            &wallet_address.to_bytes(),
+            &sub_account_id.to_le_bytes(),
+        ],
+        &DRIFT_PROGRAM,
+    )
+    .0
+}
+
+fn drift_find_user_stats_address(wallet_address: Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"user_stats", &wallet_address.to_bytes()], &DRIFT_PROGRAM).0
+}
+
+fn drift_load_spot_market(
+    market_index: u16,
+    account_data_cache: &mut AccountDataCache,
+) -> Result<drift::spot_market::SpotMarket, Box<dyn std::error::Error>> {
+    let spot_market_address = drift_find_spot_market_address(market_index);
+    let (account_data, _context_slot) = account_data_cache.get(spot_market_address)?;
+
+    const LEN: usize = std::mem::size_of::<drift::spot_market::SpotMarket>();
+    if account_data.len() < LEN + 8 {
+        return Err(format!("Invalid Drift spot market account: {spot_market_address}").into());
+    }
+    let account_data: [u8; LEN] = account_data[8..LEN + 8].try_into().unwrap();
+    let spot_market =
+        unsafe { std::mem::transmute::<[u8; LEN], drift::spot_market::SpotMarket>(account_data) };
+    Ok(spot_market)
+}
+
+fn drift_unsafe_load_user(
+    user_address: Pubkey,
+    account_data_cache: &mut AccountDataCache,
+) -> Result<Option<drift::user::User>, Box<dyn std::error::Error>> {
+    let (account_data, _context_slot) = account_data_cache.get(user_address)?;
+
+    if account_data.is_empty() {
+        return Ok(None);
+    }
+
+    const LEN: usize = std::mem::size_of::<drift::user::User>();
+    if account_data.len() < LEN + 8 {
+        return Err(format!("Invalid Drift user account: {user_address}").into());
+    }
+    let account_data: [u8; LEN] = account_data[8..LEN + 8].try_into().unwrap();
+    let user = unsafe { std::mem::transmute::<[u8; LEN], drift::user::User>(account_data) };
+    Ok(Some(user))
+}
+
+fn drift_apr(
+    token: Token,
+    account_data_cache: &mut AccountDataCache,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    let market_index = drift_spot_market_index(token)?;
+    let spot_market = drift_load_spot_market(market_index, account_data_cache)?;
+    Ok(drift::calculate_accumulated_interest(&spot_market).deposit_rate)
+}
+
+fn drift_deposited_amount(
+    wallet_address: Pubkey,
+    token: Token,
+    account_data_cache: &mut AccountDataCache,
+) -> Result<(/*balance: */ u64, /* available_balance: */ u64), Box<dyn std::error::Error>> {
+    let market_index = drift_spot_market_index(token)?;
+    let spot_market = drift_load_spot_market(market_index, account_data_cache)?;
+    let remaining_outflow = u64::MAX;
+
+    let user_address = drift_find_user_address(wallet_address);
+
+    let deposited_amount = match drift_unsafe_load_user(user_address, account_data_cache)? {
+        None => 0,
+        Some(user) => user
+            .spot_positions
+            .iter()
+            .find(|spot_position| {
+                spot_position.market_index == market_index
+                    && spot_position.balance_type == drift::user::SpotBalanceType::Deposit
+                    && spot_position.scaled_balance > 0
+            })
+            .map(|spot_position| {
+                drift::scaled_balance_to_token_amount(
+                    spot_position.scaled_balance as u128,
+                    &spot_market,
+                    drift::user::SpotBalanceType::Deposit,
+                )
+            })
+            .unwrap_or_default(),
+    };
+
+    Ok((deposited_amount, deposited_amount.min(remaining_outflow)))
+}
+
+fn drift_deposit_or_withdraw(
+    op: Operation,
+    wallet_address: Pubkey,
+    token: Token,
+    amount: u64,
+    account_data_cache: &mut AccountDataCache,
+) -> Result<DepositOrWithdrawResult, Box<dyn std::error::Error>> {
+    let market_index = drift_spot_market_index(token)?;
+    let spot_market = drift_load_spot_market(market_index, account_data_cache)?;
+
+    let state_address = drift_find_state_address();
+    let user_address = drift_find_user_address(wallet_address);
+    let user_stats_address = drift_find_user_stats_address(wallet_address);
+    let user = drift_unsafe_load_user(user_address, account_data_cache)?;
+
+    let mut instructions = vec![];
+    let mut required_compute_units = 0;
+
+    if user.is_none() {
+        if op == Operation::Withdraw {
+            return Err(format!("No Drift user account found for {wallet_address}").into());
+        }
+
+        if account_data_cache.get(user_stats_address)?.0.is_empty() {
+            // Instruction: Drift: Initialize User Stats
+            instructions.push(Instruction::new_with_bytes(
+                DRIFT_PROGRAM,
+                &[0xfe, 0xf3, 0x48, 0x62, 0xfb, 0x82, 0xa8, 0xd5],
+                vec![
+                    // User Stats
+                    AccountMeta::new(user_stats_address, false),
+                    // State
+                    AccountMeta::new(state_address, false),
+                    // Authority
+                    AccountMeta::new_readonly(wallet_address, true),
+                    // Payer
+                    AccountMeta::new(wallet_address, true),
+                    // Rent
+                    AccountMeta::new_readonly(sysvar::rent::ID, false),
+                    // System Program
+                    AccountMeta::new_readonly(system_program::ID, false),
+                ],
+            ));
+            required_compute_units += 50_000;
+        }
+
+        // Instruction: Drift: Initialize User
+        let drift_initialize_user_data = {
+            let mut v = vec![0x6f, 0x11, 0xb9, 0xfa, 0x3c, 0x7a, 0x26, 0xfe];
+            v.extend(0u16.to_le_bytes()); // sub_account_id
+            let mut name = [b' '; 32];
+            name[..12].copy_from_slice(b"Main Account");
+            v.extend(name);
+            v
+        };
+        instructions.push(Instruction::new_with_bytes(
+            DRIFT_PROGRAM,
+            &drift_initialize_user_data,
+            vec![
+                // User
+                AccountMeta::new(user_address, false),
+                // User Stats
+                AccountMeta::new(user_stats_address, false),
+                // State
+                AccountMeta::new(state_address, false),
+                // Authority
+                AccountMeta::new_readonly(wallet_address, true),
+                // Payer
+                AccountMeta::new(wallet_address, true),
+                // Rent
+                AccountMeta::new_readonly(sysvar::rent::ID, false),
+                // System Program
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+        ));
+        required_compute_units += 50_000;
+    }
+
+    // Drift requires the oracle and spot market of every market the user holds a position in to
+    // follow the instruction accounts: all oracles first, then all spot markets
+    let mut market_indexes = vec![market_index];
+    if let Some(user) = &user {
+        for spot_position in &user.spot_positions {
+            if spot_position.scaled_balance > 0
+                && !market_indexes.contains(&spot_position.market_index)
+            {
+                market_indexes.push(spot_position.market_index);
+            }
+        }
+    }
+
+    let mut oracle_account_metas = vec![];
+    let mut spot_market_account_metas = vec![];
+    for index in market_indexes {
+        let (oracle, spot_market_address) = if index == market_index {
+            (spot_market.oracle, spot_market.pubkey)
+        } else {
+            let other_spot_market = drift_load_spot_market(index, account_data_cache)?;
+            (other_spot_market.oracle, other_spot_market.pubkey)
+        };
+
+        if !oracle_account_metas
+            .iter()
+            .any(|account_meta: &AccountMeta| account_meta.pubkey == oracle)
+        {
+            oracle_account_metas.push(AccountMeta::new_readonly(oracle, false));
+        }
+        spot_market_account_metas.push(if index == market_index {
+            AccountMeta::new(spot_market_address, false)
+        } else {
+            AccountMeta::new_readonly(spot_market_address, false)
+        });
+    }
+
+    let user_token_account =
+        spl_associated_token_account::get_associated_token_address(&wallet_address, &token.mint());
+
+    let amount = match op {
+        Operation::Deposit => {
+            // Instruction: Drift: Deposit
+            let drift_deposit_data = {
+                let mut v = vec![0xf2, 0x23, 0xc6, 0x89, 0x52, 0xe1, 0xf2, 0xb6];
+                v.extend(market_index.to_le_bytes());
+                v.extend(amount.to_le_bytes());
+                v.push(/* reduce_only = */ 0);
+                v
+            };
+
+            let mut account_metas = vec![
+                // State
+                AccountMeta::new_readonly(state_address, false),
+                // User
+                AccountMeta::new(user_address, false),
+                // User Stats
+                AccountMeta::new(user_stats_address, false),
+                // Authority
+                AccountMeta::new_readonly(wallet_address, true),
+                // Spot Market Vault
+                AccountMeta::new(spot_market.vault, false),
+                // User Token Account
+                AccountMeta::new(user_token_account, false),
+                // Token Program
+                AccountMeta::new_readonly(token.program_id(), false),
+            ];
+            account_metas.extend(oracle_account_metas);
+            account_metas.extend(spot_market_account_metas);
+
+            instructions.push(Instruction::new_with_bytes(
+                DRIFT_PROGRAM,
+                &drift_deposit_data,
+                account_metas,
+            ));
+            required_compute_units += 100_000;
+            amount
+        }
+        Operation::Withdraw => {
+            // Instruction: Drift: Withdraw
+            let drift_withdraw_data = {
+                let mut v = vec![0xb7, 0x12, 0x46, 0x9c, 0x94, 0x6d, 0xa1, 0x22];
+                v.extend(market_index.to_le_bytes());
+                v.extend(amount.to_le_bytes());
+                v.push(/* reduce_only = */ 1); // Never borrow
+                v
+            };
+
+            let mut account_metas = vec![
+                // State
+                AccountMeta::new_readonly(state_address, false),
+                // User
+                AccountMeta::new(user_address, false),
+                // User Stats
+                AccountMeta::new(user_stats_address, false),
+                // Authority
+                AccountMeta::new_readonly(wallet_address, true),
+                // Spot Market Vault
+                AccountMeta::new(spot_market.vault, false),
+                // Drift Signer
+                AccountMeta::new_readonly(drift_find_signer_address(), false),
+                // User Token Account
+                AccountMeta::new(user_token_account, false),
+                // Token Program
+                AccountMeta::new_readonly(token.program_id(), false),
+            ];
+            account_metas.extend(oracle_account_metas);
+            account_metas.extend(spot_market_account_metas);
+
+            instructions.push(Instruction::new_with_bytes(
+                DRIFT_PROGRAM,
+                &drift_withdraw_data,
+                account_metas,
+            ));
+            required_compute_units += 150_000;
+            amount
+        }
+    };
+
+    Ok(DepositOrWithdrawResult {
+        instructions,
+        required_compute_units,
+        amount,
+        address_lookup_table: None,
+    })
+}
@@ -8,14 +8,22 @@ use {
     console::{style, Style},
     db::*,
     itertools::{izip, Itertools},
-    rpc_client_utils::{get_signature_date, get_stake_activation_state, StakeActivationState},
+    rpc_client_utils::{
+        get_block_fee_reward, get_leader_slots, get_signature_date, get_stake_activation_state,
+        get_stake_unlock_date, StakeActivationState,
+    },
     rust_decimal::prelude::*,
     separator::FixedPlaceSeparatable,
     solana_clap_utils::{self, input_parsers::*, input_validators::*},
-    solana_client::{rpc_client::RpcClient, rpc_config::RpcTransactionConfig},
+    solana_client::{
+        rpc_client::{GetConfirmedSignaturesForAddress2Config, RpcClient},
+        rpc_config::RpcTransactionConfig,
+        rpc_response::RpcInflationReward,
+    },
     solana_pubkey::Pubkey,
     solana_sdk::{
-        clock::Slot,
+        clock::{Epoch, Slot},
+        commitment_config::CommitmentConfig,
         compute_budget,
         message::Message,
         native_token::{sol_to_lamports, Sol},
@@ -26,7 +34,7 @@ use {
         transaction::Transaction,
     },
     std::{
-        collections::{BTreeMap, HashSet},
+        collections::{BTreeMap, HashMap, HashSet},
         fs,
         path::PathBuf,
         process::exit,
@@ -36,12 +44,19 @@ use {
     },
     sys::{
         amount::Amount,
+        credentials_db::CredentialsKey,
         exchange::{self, *},
         get_transaction_balance_change::*,
         metrics::{self, dp, MetricsConfig},
         notifier::*,
+        price_cache,
+        price_source::{self, PriceSourceKind, POSSIBLE_PRICE_SOURCE_VALUES},
         priority_fee::{apply_priority_fee, PriorityFee},
         send_transaction_until_expired,
+        tax_export::{self, CsvFormat, POSSIBLE_CSV_FORMAT_VALUES},
+        tax_jurisdiction::{
+            self, HoldingPeriod, TaxJurisdiction, POSSIBLE_TAX_JURISDICTION_VALUES,
+        },
         token::*,
         *,
     },
@@ -75,6 +90,17 @@ fn is_long_term_cap_gain(acquisition: NaiveDate, disposal: Option<NaiveDate>) ->
     hold_time >= chrono::Duration::try_days(365).unwrap()
 }
 
+fn println_estimated_tax(db: &Db, token: MaybeToken, lots: &[Lot], disposal: LotDisposalEstimate) {
+    if let Some(tax_rate) = db.get_tax_rate() {
+        let tax = tax_rate.estimated_tax(token, lots, disposal);
+        println!(
+            "Estimated tax impact: {}${}",
+            if tax < 0. { "-" } else { "" },
+            tax.abs().separated_string_with_fixed_place(2)
+        );
+    }
+}
+
 fn format_order_side(order_side: OrderSide) -> String {
     match order_side {
         OrderSide::Buy => style(" Buy").green(),
@@ -103,22 +129,22 @@ async fn get_block_date_and_price(
     rpc_client: &RpcClient,
     slot: Slot,
     token: MaybeToken,
-) -> Result<(NaiveDate, Decimal), Box<dyn std::error::Error>> {
+) -> Result<(NaiveDate, Decimal, Option<PriceSourceKind>), Box<dyn std::error::Error>> {
     let block_date = rpc_client_utils::get_block_date(rpc_client, slot).await?;
-    Ok((
-        block_date,
-        retry_get_historical_price(rpc_client, block_date, token).await?,
-    ))
+    let (price, price_source) = retry_get_historical_price(rpc_client, block_date, token).await?;
+    Ok((block_date, price, price_source))
 }
 
 async fn retry_get_historical_price(
     rpc_client: &RpcClient,
     block_date: NaiveDate,
     token: MaybeToken,
-) -> Result<Decimal, Box<dyn std::error::Error>> {
+) -> Result<(Decimal, Option<PriceSourceKind>), Box<dyn std::error::Error>> {
     const NUM_RETRIES: usize = 20;
     for _ in 1..NUM_RETRIES {
-        let price = token.get_historical_price(rpc_client, block_date).await;
+        let price = token
+            .get_historical_price_with_source(rpc_client, block_date)
+            .await;
         if price.is_ok() {
             return price;
         }
@@ -128,7 +154,88 @@ async fn retry_get_historical_price(
         // HTTP `Retry-After:` response header from Coin Gecko
         sleep(Duration::from_secs(5));
     }
-    token.get_historical_price(rpc_client, block_date).await
+    token
+        .get_historical_price_with_source(rpc_client, block_date)
+        .await
+}
+
+// Backfilling many epochs of rewards easily runs into RPC rate limits, so back off and retry
+#[allow(clippy::type_complexity)]
+fn retry_get_inflation_rewards_with_dates(
+    rpc_client: &RpcClient,
+    addresses: &[Pubkey],
+    epoch: Epoch,
+) -> Result<(Vec<Option<RpcInflationReward>>, HashMap<Slot, NaiveDate>), String> {
+    const NUM_RETRIES: usize = 5;
+    let mut delay = Duration::from_secs(2);
+    for _ in 1..NUM_RETRIES {
+        match rpc_client_utils::get_inflation_rewards_with_dates(rpc_client, addresses, epoch) {
+            Ok(rewards) => return Ok(rewards),
+            Err(err) => {
+                println!(
+                    "Warning: Failed to fetch epoch {epoch} rewards, retrying in {}s: {err}",
+                    delay.as_secs()
+                );
+                sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+    rpc_client_utils::get_inflation_rewards_with_dates(rpc_client, addresses, epoch)
+        .map_err(|err| format!("Failed to fetch epoch {epoch} rewards: {err}"))
+}
+
+// Records the fee of a confirmed transaction as a disposal from the fee payer's lots. The
+// transaction is already recorded, so failing to record its fee is only a warning
+async fn record_transaction_fee_disposal(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    signature: Signature,
+    fee_payer: Pubkey,
+    fee: u64,
+    when: NaiveDate,
+) {
+    if db.get_account(fee_payer, MaybeToken::SOL()).is_none() {
+        return;
+    }
+    let disposed_lots = async {
+        let (decimal_price, _price_source) =
+            retry_get_historical_price(rpc_client, when, MaybeToken::SOL()).await?;
+        let disposed_lots =
+            db.record_transaction_fee(signature, fee_payer, fee, when, decimal_price)?;
+        Ok::<_, Box<dyn std::error::Error>>(disposed_lots)
+    }
+    .await;
+
+    match disposed_lots {
+        Ok(disposed_lots) => {
+            for disposed_lot in disposed_lots {
+                println!(
+                    "Transaction fee: {}{} from lot {} ({fee_payer})",
+                    MaybeToken::SOL().symbol(),
+                    MaybeToken::SOL().ui_amount(disposed_lot.lot.amount),
+                    disposed_lot.lot.lot_number,
+                );
+            }
+        }
+        Err(err) => println!("Warning: Unable to record the transaction fee of {signature}: {err}"),
+    }
+}
+
+async fn record_transaction_fee(db: &mut Db, rpc_client: &RpcClient, signature: Signature) {
+    let fee = async {
+        let (fee_payer, fee) = rpc_client_utils::get_transaction_fee(rpc_client, signature)?;
+        let when = get_signature_date(rpc_client, signature).await?;
+        Ok::<_, Box<dyn std::error::Error>>((fee_payer, fee, when))
+    }
+    .await;
+
+    match fee {
+        Ok((fee_payer, fee, when)) => {
+            record_transaction_fee_disposal(db, rpc_client, signature, fee_payer, fee, when).await
+        }
+        Err(err) => println!("Warning: Unable to record the transaction fee of {signature}: {err}"),
+    }
 }
 
 fn add_exchange_deposit_address_to_db(
@@ -146,6 +253,7 @@ fn add_exchange_deposit_address_to_db(
             token,
             description: format!("{exchange:?} {exchange_account}"),
             last_update_epoch: epoch,
+            last_balance_scan_epoch: None,
             last_update_balance: 0,
             lots: vec![],
             no_sync: Some(true),
@@ -257,7 +365,7 @@ async fn process_sync_exchange(
             match recent_deposits.as_ref() {
                 None => {
                     if token.fiat_fungible() {
-                        db.drop_deposit(pending_deposit.transfer.signature)?;
+                        db.drop_deposit(pending_deposit.transfer.signature, today())?;
 
                         let msg = format!(
                             "{} {}{} BLIND deposit successful ({})",
@@ -305,6 +413,12 @@ async fn process_sync_exchange(
                                 get_signature_date(rpc_client, pending_deposit.transfer.signature)
                                     .await?;
                             db.confirm_deposit(pending_deposit.transfer.signature, when)?;
+                            record_transaction_fee(
+                                db,
+                                rpc_client,
+                                pending_deposit.transfer.signature,
+                            )
+                            .await;
 
                             let msg = format!(
                                 "{} {}{} deposit successful ({})",
@@ -902,11 +1016,16 @@ async fn process_exchange_sell(
         }
     }
 
-    let order_lots = deposit_account.extract_lots(
+    let disposal = LotDisposalEstimate {
+        price: Decimal::from_f64(price).unwrap(),
+        when: today(),
+    };
+    let order_lots = deposit_account.extract_lots_for_disposal(
         db,
         token.amount(amount),
         lot_selection_method,
         lot_numbers,
+        Some(disposal),
     )?;
     if if_price_over_basis {
         if let Some(basis) = order_lots.iter().find_map(|lot| {
@@ -948,6 +1067,7 @@ async fn process_exchange_sell(
         )
         .await;
     }
+    println_estimated_tax(db, deposit_account.token, &order_lots, disposal);
 
     let order_id = exchange_client
         .place_order(&pair, OrderSide::Sell, price, amount)
@@ -1043,7 +1163,8 @@ async fn process_jup_swap<T: Signers>(
         .ok_or_else(|| format!("{from_token} account does not exist for {address}"))?;
 
     let from_token_price = from_token.get_current_price(rpc_client).await?;
-    let to_token_price = to_token.get_current_price(rpc_client).await?;
+    let (to_token_price, to_token_price_source) =
+        to_token.get_current_price_with_source(rpc_client).await?;
 
     if let Some(existing_signature) = existing_signature {
         db.record_swap(
@@ -1054,6 +1175,7 @@ async fn process_jup_swap<T: Signers>(
             from_token_price,
             to_token,
             to_token_price,
+            to_token_price_source,
             lot_selection_method,
             lot_numbers,
         )?;
@@ -1115,6 +1237,30 @@ async fn process_jup_swap<T: Signers>(
 
         println_jup_quote(from_token, to_token, &quote);
 
+        if lot_selection_method == LotSelectionMethod::TaxOptimal {
+            if db.get_tax_jurisdiction() != TaxJurisdiction::UnitedStates {
+                return Err(DbError::TaxOptimalNotSupported(db.get_tax_jurisdiction()).into());
+            }
+            if db.get_tax_rate().is_none() {
+                return Err(DbError::TaxRateNotSet.into());
+            }
+        }
+        if let Some(tax_rate) = db.get_tax_rate() {
+            let disposal = LotDisposalEstimate {
+                price: from_token_price,
+                when: today(),
+            };
+            let (swap_lots, _) = select_lots(
+                from_account.lots.clone(),
+                quote.in_amount.min(from_account.last_update_balance),
+                lot_selection_method,
+                lot_numbers.clone(),
+                Some(disposal),
+                Some(tax_rate),
+            );
+            println_estimated_tax(db, from_token, &swap_lots, disposal);
+        }
+
         let from_value =
             from_token_price * Decimal::from_f64(from_token.ui_amount(quote.in_amount)).unwrap();
         let min_to_value = to_token_price
@@ -1222,6 +1368,7 @@ async fn process_jup_swap<T: Signers>(
                 token: to_token,
                 description: from_account.description,
                 last_update_epoch: epoch,
+                last_balance_scan_epoch: None,
                 last_update_balance: 0,
                 lots: vec![],
                 no_sync: None,
@@ -1235,6 +1382,7 @@ async fn process_jup_swap<T: Signers>(
             from_token_price,
             to_token,
             to_token_price,
+            to_token_price_source,
             lot_selection_method,
             lot_numbers,
         )?;
@@ -1431,6 +1579,8 @@ async fn process_sync_swaps(
                         from_token.symbol(),
                     );
                     db.confirm_swap(signature, when, from_amount, to_amount)?;
+                    record_transaction_fee_disposal(db, rpc_client, signature, address, fee, when)
+                        .await;
                     notifier.send(&msg).await;
                     println!("{msg}");
                 } else {
@@ -1739,12 +1889,17 @@ async fn process_account_add(
 
     println!("Adding {address} (token: {token})");
 
-    let current_price = token.get_current_price(rpc_client).await?;
-    let decimal_price = match price {
-        Some(price) => Decimal::from_f64(price).unwrap(),
+    let (current_price, current_price_source) =
+        token.get_current_price_with_source(rpc_client).await?;
+    let (decimal_price, price_source) = match price {
+        Some(price) => (Decimal::from_f64(price).unwrap(), None),
         None => match when {
-            Some(when) => token.get_historical_price(rpc_client, when).await?,
-            None => current_price,
+            Some(when) => {
+                token
+                    .get_historical_price_with_source(rpc_client, when)
+                    .await?
+            }
+            None => (current_price, current_price_source),
         },
     };
 
@@ -1752,7 +1907,12 @@ async fn process_account_add(
     if amount > 0 {
         let lot = Lot {
             lot_number: db.next_lot_number(),
-            acquisition: LotAcquistion::new(when.unwrap_or_else(today), decimal_price, kind),
+            acquisition: LotAcquistion::new(
+                when.unwrap_or_else(today),
+                decimal_price,
+                kind,
+                price_source,
+            ),
             amount,
         };
         maybe_println_lot(
@@ -1779,6 +1939,7 @@ async fn process_account_add(
         token,
         description,
         last_update_epoch,
+        last_balance_scan_epoch: None,
         last_update_balance: amount,
         lots,
         no_sync: Some(no_sync),
@@ -1809,24 +1970,34 @@ async fn process_account_dispose(
         },
     };
 
+    let when = when.unwrap_or_else(today);
     let disposed_lots = db.record_disposal(
         address,
         token,
         token.amount(ui_amount),
         description,
-        when.unwrap_or_else(today),
+        when,
         price,
         lot_selection_method,
         lot_numbers,
     )?;
     if !disposed_lots.is_empty() {
         println!("Disposed Lots:");
-        for disposed_lot in disposed_lots {
+        for disposed_lot in &disposed_lots {
             println!(
                 "{}",
-                format_disposed_lot(&disposed_lot, &mut 0., &mut 0., &mut false, &mut 0., true)
+                format_disposed_lot(disposed_lot, &mut 0., &mut 0., &mut false, &mut 0., true)
             );
         }
+        println_estimated_tax(
+            db,
+            token,
+            &disposed_lots
+                .into_iter()
+                .map(|disposed_lot| disposed_lot.lot)
+                .collect::<Vec<_>>(),
+            LotDisposalEstimate { price, when },
+        );
         println!();
     }
     Ok(())
@@ -1866,6 +2037,77 @@ impl AnnualRealizedGain {
     }
 }
 
+fn process_account_discounted_basis(db: &Db) {
+    match db.get_fmv_discount_model() {
+        Some(fmv_discount_model) => println!("FMV discount: {fmv_discount_model}"),
+        None => println!("FMV discount: disabled"),
+    }
+
+    let held_lots = db
+        .get_accounts()
+        .into_iter()
+        .flat_map(|account| {
+            let token = account.token;
+            account.lots.into_iter().map(move |lot| (token, lot, None))
+        })
+        .chain(db.disposed_lots().into_iter().map(|disposed_lot| {
+            (
+                disposed_lot.token,
+                disposed_lot.lot,
+                Some(disposed_lot.when),
+            )
+        }));
+
+    let mut total_undiscounted_basis = 0.;
+    let mut total_discounted_basis = 0.;
+    let mut lots = held_lots
+        .filter(|(_, lot, _)| lot.acquisition.fmv_discount.is_some())
+        .collect::<Vec<_>>();
+    lots.sort_by_key(|(_, lot, _)| lot.acquisition.when);
+
+    if lots.is_empty() {
+        println!("No discounted lots");
+        return;
+    }
+
+    for (token, lot, disposed) in lots {
+        let fmv_discount = lot.acquisition.fmv_discount.as_ref().unwrap();
+        let ui_amount = Decimal::from_f64(token.ui_amount(lot.amount)).unwrap();
+        let undiscounted_basis =
+            f64::try_from(fmv_discount.undiscounted_price * ui_amount).unwrap();
+        let discounted_basis = lot.basis(token);
+        total_undiscounted_basis += undiscounted_basis;
+        total_discounted_basis += discounted_basis;
+
+        println!(
+            "{:>5}. {} | {:>20} | unlocks {} | undiscounted: ${:<14} | discounted ({:.2}%): ${:<14}{}",
+            lot.lot_number,
+            lot.acquisition.when,
+            token.format_amount(lot.amount),
+            fmv_discount.unlock_date,
+            undiscounted_basis.separated_string_with_fixed_place(2),
+            fmv_discount.discount * 100.,
+            discounted_basis.separated_string_with_fixed_place(2),
+            disposed
+                .map(|when| format!(" | disposed {when}"))
+                .unwrap_or_default(),
+        );
+    }
+    println!();
+    println!(
+        "Undiscounted basis: ${}",
+        total_undiscounted_basis.separated_string_with_fixed_place(2)
+    );
+    println!(
+        "Discounted basis:   ${}",
+        total_discounted_basis.separated_string_with_fixed_place(2)
+    );
+    println!(
+        "Discount:           ${}",
+        (total_undiscounted_basis - total_discounted_basis).separated_string_with_fixed_place(2)
+    );
+}
+
 async fn process_account_cost_basis(
     db: &Db,
     when: NaiveDate,
@@ -1930,6 +2172,418 @@ async fn process_account_cost_basis(
     Ok(())
 }
 
+#[derive(Default)]
+struct UnrealizedGain {
+    short_term_cap_gain: f64,
+    long_term_cap_gain: f64,
+}
+
+impl UnrealizedGain {
+    fn add(&mut self, other: &UnrealizedGain) {
+        self.short_term_cap_gain += other.short_term_cap_gain;
+        self.long_term_cap_gain += other.long_term_cap_gain;
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "short-term: ${}, long-term: ${}",
+            self.short_term_cap_gain
+                .separated_string_with_fixed_place(2),
+            self.long_term_cap_gain.separated_string_with_fixed_place(2)
+        )
+    }
+}
+
+// Holding periods, lot matching and tax rates of these reports follow US rules
+fn require_us_tax_jurisdiction(db: &Db, what: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let tax_jurisdiction = db.get_tax_jurisdiction();
+    if tax_jurisdiction != TaxJurisdiction::UnitedStates {
+        return Err(format!(
+            "{what} assumes US tax rules and is not available for the {tax_jurisdiction} tax \
+            jurisdiction. Use `sys account tax-report` instead"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+async fn process_account_unrealized(
+    db: &Db,
+    rpc_client: &RpcClient,
+    when: NaiveDate,
+) -> Result<(), Box<dyn std::error::Error>> {
+    require_us_tax_jurisdiction(db, "The unrealized gain report")?;
+    let mut held_lots = BTreeMap::<MaybeToken, BTreeMap<String, Vec<Lot>>>::default();
+
+    for account in db.get_accounts() {
+        let lots = account
+            .lots
+            .into_iter()
+            .filter(|lot| lot.acquisition.when <= when)
+            .collect::<Vec<_>>();
+        if !lots.is_empty() {
+            held_lots.entry(account.token).or_default().insert(
+                format!("{} ({})", account.address, account.description),
+                lots,
+            );
+        }
+    }
+
+    // Lots in open orders and pending transfers, deposits and withdrawals
+    for (token, lot) in db.in_flight_lots() {
+        if lot.acquisition.when <= when {
+            held_lots
+                .entry(token)
+                .or_default()
+                .entry("In open orders or pending transfers".into())
+                .or_default()
+                .push(lot);
+        }
+    }
+
+    // Lots that were still held on `when` but have since been disposed
+    for disposed_lot in db.disposed_lots() {
+        if disposed_lot.lot.acquisition.when <= when && disposed_lot.when > when {
+            held_lots
+                .entry(disposed_lot.token)
+                .or_default()
+                .entry(format!("Disposed after {when}"))
+                .or_default()
+                .push(disposed_lot.lot);
+        }
+    }
+
+    println!("Unrealized Gain/Loss on {when}");
+    let mut total_unrealized_gain = UnrealizedGain::default();
+    for (token, accounts) in held_lots {
+        if token.fiat_fungible() {
+            continue;
+        }
+
+        let (price, price_source) = if when == today() {
+            token.get_current_price_with_source(rpc_client).await?
+        } else {
+            retry_get_historical_price(rpc_client, when, token).await?
+        };
+        println!();
+        println!(
+            "{} at ${}{}",
+            token.name(),
+            f64::try_from(price)
+                .unwrap()
+                .separated_string_with_fixed_place(2),
+            price_source
+                .map(|price_source| format!(" ({price_source})"))
+                .unwrap_or_default()
+        );
+
+        let mut token_unrealized_gain = UnrealizedGain::default();
+        for (account, mut lots) in accounts {
+            lots.sort_by_key(|lot| lot.acquisition.when);
+
+            println!("  {account}");
+            let mut account_unrealized_gain = UnrealizedGain::default();
+            for lot in lots {
+                let cap_gain = lot.cap_gain(token, price);
+                let hold_days = (when - lot.acquisition.when).num_days();
+                let term = if is_long_term_holding(lot.acquisition.when, when) {
+                    account_unrealized_gain.long_term_cap_gain += cap_gain;
+                    "long-term".to_string()
+                } else {
+                    account_unrealized_gain.short_term_cap_gain += cap_gain;
+                    format!("short-term, long-term in {} days", 365 - hold_days)
+                };
+                println!(
+                    "  {:>5}. {} | {:>20} at ${:<6} | gain: ${:<14} | {}",
+                    lot.lot_number,
+                    lot.acquisition.when,
+                    token.format_amount(lot.amount),
+                    f64::try_from(lot.acquisition.price())
+                        .unwrap()
+                        .separated_string_with_fixed_place(2),
+                    cap_gain.separated_string_with_fixed_place(2),
+                    term,
+                );
+            }
+            println!("         Account {}", account_unrealized_gain.summary());
+            token_unrealized_gain.add(&account_unrealized_gain);
+        }
+        println!("  {} {}", token.name(), token_unrealized_gain.summary());
+        total_unrealized_gain.add(&token_unrealized_gain);
+    }
+
+    println!();
+    println!("Total unrealized {}", total_unrealized_gain.summary());
+    if let Some(tax_rate) = db.get_tax_rate() {
+        println!(
+            "Estimated tax if all holdings were sold on {when}: ${}",
+            tax_rate
+                .estimated_cap_gain_tax(
+                    total_unrealized_gain.short_term_cap_gain,
+                    total_unrealized_gain.long_term_cap_gain
+                )
+                .separated_string_with_fixed_place(2)
+        );
+    }
+    Ok(())
+}
+
+struct HarvestCandidate {
+    address: Pubkey,
+    token: MaybeToken,
+    exchange: Option<Exchange>,
+    // Whether the lot can be swapped from `address` directly
+    swappable: bool,
+    lot: Lot,
+    cap_gain: f64,
+    long_term: bool,
+    tax_benefit: f64,
+}
+
+async fn harvest_candidates(
+    db: &Db,
+    rpc_client: &RpcClient,
+    min_loss: f64,
+    token_filter: Option<MaybeToken>,
+) -> Result<Vec<HarvestCandidate>, Box<dyn std::error::Error>> {
+    require_us_tax_jurisdiction(db, "Tax-loss harvesting")?;
+    let tax_rate = db.get_tax_rate().ok_or(DbError::TaxRateNotSet)?;
+    let when = today();
+
+    let accounts = db
+        .get_accounts()
+        .into_iter()
+        .filter(|account| {
+            !account.token.fiat_fungible() && token_filter.is_none_or(|t| t == account.token)
+        })
+        .collect::<Vec<_>>();
+
+    // Lots in the deposit account of a configured exchange can only be sold on that exchange
+    let mut exchange_deposit_accounts = HashMap::<(Pubkey, MaybeToken), Exchange>::default();
+    let exchange_tokens = accounts
+        .iter()
+        .filter(|account| account.no_sync == Some(true))
+        .map(|account| account.token)
+        .collect::<HashSet<_>>();
+    if !exchange_tokens.is_empty() {
+        for (exchange, exchange_credentials, _exchange_account) in
+            db.get_default_accounts_from_configured_exchanges()?
+        {
+            let exchange_client = exchange_client_new(exchange, exchange_credentials)?;
+            for token in &exchange_tokens {
+                if let Ok(deposit_address) = exchange_client.deposit_address(*token).await {
+                    exchange_deposit_accounts.insert((deposit_address, *token), exchange);
+                }
+            }
+        }
+    }
+
+    let mut current_prices = BTreeMap::<MaybeToken, Decimal>::default();
+    let mut candidates = vec![];
+    for account in accounts {
+        let current_price = match current_prices.get(&account.token) {
+            Some(current_price) => *current_price,
+            None => {
+                let current_price = account.token.get_current_price(rpc_client).await?;
+                current_prices.insert(account.token, current_price);
+                current_price
+            }
+        };
+
+        let exchange = exchange_deposit_accounts
+            .get(&(account.address, account.token))
+            .copied();
+
+        // SOL in stake and vote accounts, and the lots of exchanges that aren't configured, must
+        // be withdrawn to a wallet first
+        let swappable = exchange.is_none()
+            && account.no_sync != Some(true)
+            && (!account.token.is_sol()
+                || rpc_client
+                    .get_account_with_commitment(&account.address, rpc_client.commitment())?
+                    .value
+                    .is_none_or(|account| account.owner == system_program::id()));
+
+        for lot in account.lots {
+            let cap_gain = lot.cap_gain(account.token, current_price);
+            if cap_gain > -min_loss {
+                continue;
+            }
+            let long_term = is_long_term_holding(lot.acquisition.when, when);
+            let tax_benefit = -cap_gain
+                * if long_term {
+                    tax_rate.long_term_gain
+                } else {
+                    tax_rate.short_term_gain
+                };
+            candidates.push(HarvestCandidate {
+                address: account.address,
+                token: account.token,
+                exchange,
+                swappable,
+                lot,
+                cap_gain,
+                long_term,
+                tax_benefit,
+            });
+        }
+    }
+
+    candidates.sort_by(|a, b| b.tax_benefit.total_cmp(&a.tax_benefit));
+    Ok(candidates)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_account_harvest(
+    db: &mut Db,
+    rpc_clients: &RpcClients,
+    min_loss: f64,
+    token_filter: Option<MaybeToken>,
+    into_token: MaybeToken,
+    signer: Option<(Box<dyn Signer>, Pubkey)>,
+    confirm: bool,
+    slippage_bps: u64,
+    priority_fee: PriorityFee,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rpc_client = rpc_clients.default();
+    let candidates = harvest_candidates(db, rpc_client, min_loss, token_filter).await?;
+    if candidates.is_empty() {
+        println!("No lots with an unrealized loss of at least ${min_loss}");
+        return Ok(());
+    }
+
+    println!("Tax-loss harvesting candidates, by estimated tax benefit:");
+    for candidate in &candidates {
+        println!(
+            "{:>5}. {} | {:>20} | {} ({}) | {} loss: ${:<14} | tax benefit: ${}",
+            candidate.lot.lot_number,
+            candidate.lot.acquisition.when,
+            candidate.token.format_amount(candidate.lot.amount),
+            candidate.address,
+            candidate.token,
+            if candidate.long_term {
+                " long"
+            } else {
+                "short"
+            },
+            (-candidate.cap_gain).separated_string_with_fixed_place(2),
+            candidate.tax_benefit.separated_string_with_fixed_place(2),
+        );
+    }
+    println!();
+
+    // Harvest each account's candidate lots together, highest tax benefit first
+    let mut harvests = Vec::<(Pubkey, MaybeToken, Option<Exchange>, bool, Vec<Lot>)>::new();
+    for candidate in candidates {
+        match harvests
+            .iter_mut()
+            .find(|(address, token, ..)| *address == candidate.address && *token == candidate.token)
+        {
+            Some((.., lots)) => lots.push(candidate.lot),
+            None => harvests.push((
+                candidate.address,
+                candidate.token,
+                candidate.exchange,
+                candidate.swappable,
+                vec![candidate.lot],
+            )),
+        }
+    }
+
+    if confirm {
+        if let Some((address, token, Some(exchange), ..)) = harvests
+            .iter()
+            .find(|(_, _, exchange, ..)| exchange.is_some())
+        {
+            return Err(format!(
+                "{token} lots in {address} are held on {exchange:?} and cannot be harvested with \
+                 --confirm. Sell them on {exchange:?} or use --token to exclude them"
+            )
+            .into());
+        }
+    }
+
+    for (address, token, exchange, swappable, lots) in harvests {
+        let amount = lots.iter().map(|lot| lot.amount).sum::<u64>();
+        let lot_numbers = lots
+            .iter()
+            .map(|lot| lot.lot_number)
+            .collect::<HashSet<_>>();
+        let lot_args = lots
+            .iter()
+            .map(|lot| format!("--lot {}", lot.lot_number))
+            .join(" ");
+
+        if let Some(exchange) = exchange {
+            println!(
+                "To harvest via {exchange}: sys {} sell {} {lot_args} --at <PRICE>",
+                format!("{exchange:?}").to_lowercase(),
+                token.ui_amount(amount),
+            );
+            continue;
+        }
+
+        if !swappable {
+            println!(
+                "To harvest: withdraw {} {token} from {address} to a wallet, then swap it ({lot_args})",
+                token.ui_amount(amount),
+            );
+            continue;
+        }
+
+        if token == into_token {
+            continue;
+        }
+
+        let signer = match &signer {
+            Some((signer, signer_address)) if *signer_address == address => signer,
+            _ => {
+                println!(
+                    "To harvest: sys jup swap {address} {token} {into_token} {} {lot_args}",
+                    token.ui_amount(amount),
+                );
+                continue;
+            }
+        };
+
+        if !confirm {
+            println!(
+                "Add --confirm to swap {}{} from {address} into {into_token} ({lot_args})",
+                token.symbol(),
+                token.ui_amount(amount),
+            );
+            continue;
+        }
+
+        let jup_api_key = std::env::var("JUP_API_KEY").map_err(|_| {
+            "JUP_API_KEY env var not set. Get one from https://portal.jup.ag".to_string()
+        })?;
+        process_jup_swap(
+            db,
+            rpc_clients,
+            address,
+            token,
+            into_token,
+            Some(token.ui_amount(amount)),
+            slippage_bps,
+            LotSelectionMethod::default(),
+            Some(lot_numbers),
+            vec![signer.as_ref()],
+            None,
+            None,
+            None,
+            5., // max_coingecko_value_percentage_loss
+            priority_fee,
+            notifier,
+            jup_api_key,
+        )
+        .await?;
+        process_sync_swaps(db, rpc_client, notifier).await?;
+    }
+    Ok(())
+}
+
 fn print_current_holdings(
     held_tokens: &BTreeMap<
         MaybeToken,
@@ -2044,11 +2698,14 @@ async fn process_account_list(
         ),
     >::default();
 
+    let tax_rules = db.get_tax_jurisdiction().tax_rules();
+
     // hacky: display a unified rate if the long and short term rate is equal
-    let unified_tax_rate = db
-        .get_tax_rate()
-        .map(|tax_rate| tax_rate.short_term_gain - tax_rate.long_term_gain <= f64::EPSILON)
-        .unwrap_or(false);
+    let unified_tax_rate = !tax_rules.has_long_term_rate()
+        || db
+            .get_tax_rate()
+            .map(|tax_rate| tax_rate.short_term_gain - tax_rate.long_term_gain <= f64::EPSILON)
+            .unwrap_or(false);
 
     let mut accounts = db.get_accounts();
     accounts.sort_by(|a, b| {
@@ -2065,6 +2722,7 @@ async fn process_account_list(
         println!("No accounts");
     } else {
         let mut total_income = 0.;
+        let mut total_mev_income = 0.;
         let mut total_unrealized_short_term_gain = 0.;
         let mut total_unrealized_long_term_gain = 0.;
         let mut total_current_basis = 0.;
@@ -2165,7 +2823,16 @@ async fn process_account_list(
                 let mut account_basis = 0.;
                 let mut account_income = 0.;
                 let mut account_current_value = 0.;
-                let mut account_unrealized_short_term_gain = 0.;
+
+                // MEV tips are income as well, but reported apart from inflation rewards
+                let account_mev_income = lots
+                    .iter()
+                    .filter(|lot| {
+                        matches!(lot.acquisition.kind, LotAcquistionKind::MevReward { .. })
+                    })
+                    .map(|lot| lot.income(account.token))
+                    .sum::<f64>();
+                let mut account_unrealized_short_term_gain = 0.;
                 let mut account_unrealized_long_term_gain = 0.;
 
                 if !show_all_lots && lots.len() > 5 {
@@ -2275,8 +2942,16 @@ async fn process_account_list(
                                 .separated_string_with_fixed_place(2),
                             if account_income > 0. {
                                 format!(
-                                    "income: ${}, ",
-                                    account_income.separated_string_with_fixed_place(2)
+                                    "income: ${}{}, ",
+                                    account_income.separated_string_with_fixed_place(2),
+                                    if account_mev_income > 0. {
+                                        format!(
+                                            " (MEV tips: ${})",
+                                            account_mev_income.separated_string_with_fixed_place(2)
+                                        )
+                                    } else {
+                                        "".into()
+                                    }
                                 )
                             } else {
                                 "".into()
@@ -2301,6 +2976,7 @@ async fn process_account_list(
                 total_unrealized_short_term_gain += account_unrealized_short_term_gain;
                 total_unrealized_long_term_gain += account_unrealized_long_term_gain;
                 total_income += account_income;
+                total_mev_income += account_mev_income;
                 total_current_value += account_current_value;
                 if account.token.fiat_fungible() {
                     total_current_fiat_value += account_current_value;
@@ -2343,13 +3019,11 @@ async fn process_account_list(
             let mut disposed_value = 0.;
 
             for (i, disposed_lot) in disposed_lots.iter().enumerate() {
-                let mut long_term_cap_gain = false;
-                let mut disposed_cap_gain = 0.;
                 let msg = format_disposed_lot(
                     disposed_lot,
                     &mut disposed_income,
-                    &mut disposed_cap_gain,
-                    &mut long_term_cap_gain,
+                    &mut 0.,
+                    &mut false,
                     &mut disposed_value,
                     verbose,
                 );
@@ -2372,23 +3046,31 @@ async fn process_account_list(
                         disposed_lot.lot.acquisition.when.month0() as usize,
                         disposed_lot.lot.income(disposed_lot.token),
                     );
+            }
 
+            // Realized capital gains follow the lot matching rules of the tax jurisdiction
+            for taxable_disposal in tax_jurisdiction::taxable_disposals(db)? {
+                let cap_gain = tax_rules.taxable_gain(taxable_disposal.gain());
                 let annual_realized_gain = annual_realized_gains
-                    .entry(disposed_lot.when.year() as usize)
+                    .entry(taxable_disposal.when.year() as usize)
                     .or_default();
 
-                if long_term_cap_gain {
-                    disposed_long_term_cap_gain += disposed_cap_gain;
-                    annual_realized_gain.record_long_term_cap_gain(
-                        disposed_lot.when.month0() as usize,
-                        disposed_cap_gain,
-                    );
-                } else {
-                    disposed_short_term_cap_gain += disposed_cap_gain;
-                    annual_realized_gain.record_short_term_cap_gain(
-                        disposed_lot.when.month0() as usize,
-                        disposed_cap_gain,
-                    );
+                match taxable_disposal.holding_period {
+                    HoldingPeriod::LongTerm => {
+                        disposed_long_term_cap_gain += cap_gain;
+                        annual_realized_gain.record_long_term_cap_gain(
+                            taxable_disposal.when.month0() as usize,
+                            cap_gain,
+                        );
+                    }
+                    HoldingPeriod::Exempt => {}
+                    HoldingPeriod::ShortTerm | HoldingPeriod::NotApplicable => {
+                        disposed_short_term_cap_gain += cap_gain;
+                        annual_realized_gain.record_short_term_cap_gain(
+                            taxable_disposal.when.month0() as usize,
+                            cap_gain,
+                        );
+                    }
                 }
             }
             println!(
@@ -2512,6 +3194,12 @@ async fn process_account_list(
                 total_income.separated_string_with_fixed_place(2)
             );
         }
+        if total_mev_income > 0. {
+            println!(
+                "    MEV tips:          ${} (realized)",
+                total_mev_income.separated_string_with_fixed_place(2)
+            );
+        }
         if unified_tax_rate {
             println!(
                 "  Cap gain:            ${} (unrealized)",
@@ -2561,6 +3249,8 @@ async fn process_account_xls(
 ) -> Result<(), Box<dyn std::error::Error>> {
     use simple_excel_writer::*;
 
+    require_us_tax_jurisdiction(db, "The spreadsheet export")?;
+
     let mut workbook = Workbook::create(outfile);
 
     let mut sheet = workbook.create_sheet(&match filter_by_year {
@@ -2635,14 +3325,14 @@ async fn process_account_xls(
                     cap_gain,
                     if long_term_cap_gain { "Long" } else { "Short" },
                     disposed_lot.when.to_string(),
-                    disposed_lot.price().to_string(),
+                    disposed_lot.gross_price().to_string(),
                     disposed_lot
                         .kind
                         .fee()
-                        .map(|(amount, currency)| {
-                            assert_eq!(currency, "USD");
-                            *amount
+                        .and_then(|fee| {
+                            exchange_fee_in_usd(fee, disposed_lot.token, disposed_lot.gross_price())
                         })
+                        .map(|fee| f64::try_from(fee).unwrap())
                         .unwrap_or_default(),
                     disposed_lot.kind.to_string()
                 ])?;
@@ -2772,56 +3462,349 @@ async fn process_account_xls(
 async fn process_account_csv(
     db: &Db,
     outfile: &str,
+    format: CsvFormat,
     filter_by_year: Option<i32>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    use csv::Writer;
+    if format == CsvFormat::TurboTax {
+        require_us_tax_jurisdiction(db, "The TurboTax export")?;
+    }
+    let records = tax_export::export_csv(db, format, outfile, filter_by_year)?;
+    println!("Wrote {records} {format} records to {outfile}");
+    Ok(())
+}
+
+// A Form 8949 row
+struct Form8949Entry {
+    long_term: bool,
+    description: String,
+    acquired: NaiveDate,
+    sold: NaiveDate,
+    proceeds: Decimal,
+    basis: Decimal,
+    adjustment: Decimal, // Negative, exchange fees paid on the sale
+}
+
+impl Form8949Entry {
+    fn gain(&self) -> Decimal {
+        self.proceeds - self.basis + self.adjustment
+    }
+
+    // Digital assets without a Form 1099-B are reported in Box C (short-term) or Box F (long-term)
+    fn form_box(&self) -> &'static str {
+        if self.long_term {
+            "F"
+        } else {
+            "C"
+        }
+    }
+
+    fn schedule_d_line(&self) -> &'static str {
+        if self.long_term {
+            "10"
+        } else {
+            "3"
+        }
+    }
+
+    // TXF reference number for Form 8949 Box C and Box F
+    fn txf_refnum(&self) -> u32 {
+        if self.long_term {
+            716
+        } else {
+            713
+        }
+    }
+}
+
+fn form_8949_entries(db: &Db, year: i32) -> Vec<Form8949Entry> {
+    let round = |value: Decimal| value.round_dp(2);
 
-    let mut wtr = Writer::from_path(outfile)?;
     let mut disposed_lots = db.disposed_lots();
-    disposed_lots.sort_by_key(|lot| lot.when);
+    disposed_lots.retain(|disposed_lot| disposed_lot.when.year() == year);
+
+    disposed_lots
+        .into_iter()
+        .map(|disposed_lot| {
+            let ui_amount =
+                Decimal::from_f64(disposed_lot.token.ui_amount(disposed_lot.lot.amount)).unwrap();
+
+            // Form 8949 reports gross proceeds with exchange fees as an adjustment
+            let adjustment = match disposed_lot.kind.fee() {
+                Some(fee @ (fee_amount, fee_coin)) => {
+                    match exchange_fee_in_usd(fee, disposed_lot.token, disposed_lot.gross_price()) {
+                        Some(fee) => -fee,
+                        None => {
+                            eprintln!(
+                                "Warning: Lot {} fee of {fee_amount} {fee_coin} is not in USD and \
+                                 was ignored",
+                                disposed_lot.lot.lot_number
+                            );
+                            Decimal::ZERO
+                        }
+                    }
+                }
+                None => Decimal::ZERO,
+            };
 
-    if let Some(year) = filter_by_year {
-        // Exclude disposed lots that were neither acquired nor disposed of in the filter year
-        disposed_lots.retain(|disposed_lot| {
-            (disposed_lot.lot.acquisition.when.year() == year
-                && disposed_lot.lot.income(disposed_lot.token) > 0.)
-                || disposed_lot.when.year() == year
+            Form8949Entry {
+                long_term: is_long_term_cap_gain(
+                    disposed_lot.lot.acquisition.when,
+                    Some(disposed_lot.when),
+                ),
+                description: format!("{} {}", ui_amount.normalize(), disposed_lot.token.name()),
+                acquired: disposed_lot.lot.acquisition.when,
+                sold: disposed_lot.when,
+                proceeds: round(disposed_lot.gross_price() * ui_amount),
+                basis: round(disposed_lot.lot.acquisition.price() * ui_amount),
+                adjustment: round(adjustment),
+            }
         })
+        .collect()
+}
+
+fn process_account_tax_report(db: &Db, year: i32) -> Result<(), Box<dyn std::error::Error>> {
+    let tax_rules = db.get_tax_jurisdiction().tax_rules();
+    let taxable_disposals = tax_jurisdiction::taxable_disposals(db)?
+        .into_iter()
+        .filter(|taxable_disposal| taxable_disposal.when.year() == year)
+        .collect::<Vec<_>>();
+
+    println!(
+        "{year} capital gains, {} tax jurisdiction",
+        tax_rules.jurisdiction()
+    );
+    for taxable_disposal in &taxable_disposals {
+        println!(
+            "  {} | {:>20} | acquired: {:<10} | proceeds: ${:<14} | basis: ${:<14} | gain: ${:<14} | {}{}",
+            taxable_disposal.when,
+            taxable_disposal
+                .token
+                .format_amount(taxable_disposal.amount),
+            taxable_disposal
+                .acquired
+                .map(|acquired| acquired.to_string())
+                .unwrap_or_else(|| "pooled".into()),
+            taxable_disposal
+                .proceeds
+                .separated_string_with_fixed_place(2),
+            taxable_disposal.basis.separated_string_with_fixed_place(2),
+            taxable_disposal.gain().separated_string_with_fixed_place(2),
+            taxable_disposal.rule,
+            match taxable_disposal.holding_period {
+                HoldingPeriod::NotApplicable => "",
+                HoldingPeriod::ShortTerm => ", short-term",
+                HoldingPeriod::LongTerm => ", long-term",
+                HoldingPeriod::Exempt => ", exempt",
+            }
+        );
     }
-    wtr.write_record([
-        "Transaction Type",
-        "Asset Name",
-        "Amount",
-        "Date Acquired",
-        "Cost basis (USD)",
-        "Date of Disposition",
-        "Proceeds (USD)",
-    ])?;
-
-    for disposed_lot in disposed_lots {
-        let cost = Decimal::from_u64(disposed_lot.lot.amount).unwrap()
-            * disposed_lot.lot.acquisition.price()
-            / Decimal::from_f64(1e9).unwrap();
-        let proceedings = Decimal::from_u64(disposed_lot.lot.amount).unwrap()
-            * disposed_lot.price()
-            / Decimal::from_f64(1e9).unwrap();
-        wtr.write_record(&[
-            "Sell".to_string(),
-            disposed_lot.token.to_string(),
-            format!(
-                "{:.9}",
-                disposed_lot.token.ui_amount(disposed_lot.lot.amount)
-            ),
-            disposed_lot.lot.acquisition.when.to_string(),
-            format!("{cost:.9}"),
-            disposed_lot.when.to_string(),
-            format!("{proceedings:.9}"),
-        ])?;
+    println!();
+
+    for (label, value) in tax_rules.report_summary(&taxable_disposals) {
+        if label.starts_with("Number of") {
+            println!("  {label}: {value}");
+        } else {
+            println!("  {label}: ${}", value.separated_string_with_fixed_place(2));
+        }
+    }
+    Ok(())
+}
+
+// IRS estimated tax payment periods of the annualized income installment method, and the date
+// each payment is due
+fn estimated_tax_periods(year: i32) -> [(NaiveDate, NaiveDate, NaiveDate); 4] {
+    let ymd = |year, month, day| NaiveDate::from_ymd_opt(year, month, day).unwrap();
+    [
+        (ymd(year, 1, 1), ymd(year, 3, 31), ymd(year, 4, 15)),
+        (ymd(year, 4, 1), ymd(year, 5, 31), ymd(year, 6, 15)),
+        (ymd(year, 6, 1), ymd(year, 8, 31), ymd(year, 9, 15)),
+        (ymd(year, 9, 1), ymd(year, 12, 31), ymd(year + 1, 1, 15)),
+    ]
+}
+
+fn process_account_estimated_tax(
+    db: &Db,
+    year: i32,
+    quarter: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    require_us_tax_jurisdiction(db, "The estimated tax calculation")?;
+    let tax_rate = db
+        .get_tax_rate()
+        .ok_or("Tax rate not set, run `sys account set-tax-rate` first")?;
+
+    let periods = estimated_tax_periods(year);
+    let quarter = quarter.unwrap_or_else(|| {
+        let today = today();
+        periods
+            .iter()
+            .position(|(_start, end, _due)| today <= *end)
+            .map(|i| i + 1)
+            .unwrap_or(4)
+    });
+
+    let mut income_lots = db.held_lots();
+    income_lots.extend(
+        db.disposed_lots()
+            .into_iter()
+            .map(|disposed_lot| (disposed_lot.token, disposed_lot.lot)),
+    );
+    let taxable_disposals = tax_jurisdiction::taxable_disposals(db)?;
+
+    println!("{year} estimated tax payments");
+    println!(
+        "  Tax rates: income {:.2}%, short-term gains {:.2}%, long-term gains {:.2}%",
+        tax_rate.income * 100.,
+        tax_rate.short_term_gain * 100.,
+        tax_rate.long_term_gain * 100.
+    );
+    println!();
+
+    // Income, short-term and long-term gains realized from `start` through `end`
+    let totals = |start: NaiveDate, end: NaiveDate| {
+        let period = |when: NaiveDate| when >= start && when <= end;
+
+        let income: f64 = income_lots
+            .iter()
+            .filter(|(_token, lot)| period(lot.acquisition.when))
+            .map(|(token, lot)| lot.income(*token))
+            .sum();
+        let (mut short_term_gain, mut long_term_gain) = (0., 0.);
+        for taxable_disposal in taxable_disposals
+            .iter()
+            .filter(|taxable_disposal| period(taxable_disposal.when))
+        {
+            match taxable_disposal.holding_period {
+                HoldingPeriod::LongTerm => long_term_gain += taxable_disposal.gain(),
+                HoldingPeriod::Exempt => {}
+                HoldingPeriod::NotApplicable | HoldingPeriod::ShortTerm => {
+                    short_term_gain += taxable_disposal.gain()
+                }
+            }
+        }
+        (income, short_term_gain, long_term_gain)
+    };
+
+    let mut reported_tax = 0.;
+    for (i, (start, end, due)) in periods.iter().enumerate().take(quarter) {
+        let (income, short_term_gain, long_term_gain) = totals(*start, *end);
+
+        // Capital losses offset gains across the whole year, so the tax due each quarter is the
+        // estimated tax of the year to date less what was reported for earlier quarters
+        let (ytd_income, ytd_short_term_gain, ytd_long_term_gain) = totals(periods[0].0, *end);
+        let ytd_tax = ytd_income * tax_rate.income
+            + tax_rate.estimated_cap_gain_tax(ytd_short_term_gain, ytd_long_term_gain);
+        let quarter_tax = (ytd_tax - reported_tax).max(0.);
+
+        println!(
+            "  Q{} ({start} - {end}): income ${}, short-term gain ${}, long-term gain ${}",
+            i + 1,
+            income.separated_string_with_fixed_place(2),
+            short_term_gain.separated_string_with_fixed_place(2),
+            long_term_gain.separated_string_with_fixed_place(2),
+        );
+        println!(
+            "      Estimated tax due {due}: ${} (year to date: ${}, reported for earlier quarters: ${})",
+            quarter_tax.separated_string_with_fixed_place(2),
+            ytd_tax.separated_string_with_fixed_place(2),
+            reported_tax.separated_string_with_fixed_place(2),
+        );
+        reported_tax += quarter_tax;
     }
+    Ok(())
+}
 
-    wtr.flush()?;
+async fn process_account_form_8949(
+    db: &Db,
+    outfile: &str,
+    year: i32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    require_us_tax_jurisdiction(db, "Form 8949")?;
+    let mut entries = form_8949_entries(db, year);
+    entries.sort_by_key(|entry| (entry.long_term, entry.sold, entry.acquired));
+
+    let txf = outfile.to_lowercase().ends_with(".txf");
+    if txf {
+        use std::io::Write;
+
+        let mut file = fs::File::create(outfile)?;
+        writeln!(file, "V042")?;
+        writeln!(file, "Asys {}", app_version())?;
+        writeln!(file, "D{}", today().format("%m/%d/%Y"))?;
+        writeln!(file, "^")?;
+        for entry in &entries {
+            writeln!(file, "TD")?;
+            writeln!(file, "N{}", entry.txf_refnum())?;
+            writeln!(file, "C1")?;
+            writeln!(file, "L1")?;
+            writeln!(file, "P{}", entry.description)?;
+            writeln!(file, "D{}", entry.acquired.format("%m/%d/%Y"))?;
+            writeln!(file, "D{}", entry.sold.format("%m/%d/%Y"))?;
+            writeln!(file, "${:.2}", entry.basis)?;
+            writeln!(file, "${:.2}", entry.proceeds + entry.adjustment)?;
+            writeln!(file, "^")?;
+        }
+    } else {
+        let mut wtr = csv::Writer::from_path(outfile)?;
+        wtr.write_record([
+            "Box",
+            "(a) Description of property",
+            "(b) Date acquired",
+            "(c) Date sold or disposed of",
+            "(d) Proceeds",
+            "(e) Cost or other basis",
+            "(f) Code(s)",
+            "(g) Amount of adjustment",
+            "(h) Gain or (loss)",
+        ])?;
+        for entry in &entries {
+            wtr.write_record(&[
+                entry.form_box().to_string(),
+                entry.description.clone(),
+                entry.acquired.format("%m/%d/%Y").to_string(),
+                entry.sold.format("%m/%d/%Y").to_string(),
+                format!("{:.2}", entry.proceeds),
+                format!("{:.2}", entry.basis),
+                if entry.adjustment.is_zero() {
+                    String::new()
+                } else {
+                    "E".into()
+                },
+                if entry.adjustment.is_zero() {
+                    String::new()
+                } else {
+                    format!("{:.2}", entry.adjustment)
+                },
+                format!("{:.2}", entry.gain()),
+            ])?;
+        }
+        wtr.flush()?;
+    }
     println!("Wrote {outfile}");
 
+    println!("\nSchedule D ({year}):");
+    for long_term in [false, true] {
+        let entries = entries
+            .iter()
+            .filter(|entry| entry.long_term == long_term)
+            .collect::<Vec<_>>();
+        let Some(first_entry) = entries.first() else {
+            continue;
+        };
+
+        let sum =
+            |f: fn(&Form8949Entry) -> Decimal| entries.iter().copied().map(f).sum::<Decimal>();
+        println!(
+            "  Line {:>2} (Form 8949 Box {}, {}): proceeds ${}, cost ${}, adjustments ${}, gain ${}",
+            first_entry.schedule_d_line(),
+            first_entry.form_box(),
+            if long_term { "long-term" } else { "short-term" },
+            f64::try_from(sum(|e| e.proceeds))?.separated_string_with_fixed_place(2),
+            f64::try_from(sum(|e| e.basis))?.separated_string_with_fixed_place(2),
+            f64::try_from(sum(|e| e.adjustment))?.separated_string_with_fixed_place(2),
+            f64::try_from(sum(|e| e.gain()))?.separated_string_with_fixed_place(2),
+        );
+    }
+
     Ok(())
 }
 
@@ -2944,6 +3927,7 @@ async fn process_account_merge<T: Signers>(
         }
         let when = get_signature_date(rpc_client, signature).await?;
         db.confirm_transfer(signature, when)?;
+        record_transaction_fee(db, rpc_client, signature).await;
         db.remove_account(from_address, token)?;
     }
     Ok(())
@@ -3318,6 +4302,7 @@ async fn process_account_sweep<T: Signers>(
     println!("Confirming sweep: {signature}");
     let when = get_signature_date(rpc_client, signature).await?;
     db.confirm_transfer(signature, when)?;
+    record_transaction_fee(db, rpc_client, signature).await;
 
     notifier.send(&msg).await;
     println!("{msg}");
@@ -3431,6 +4416,7 @@ async fn process_account_split<T: Signers>(
         token,
         description,
         last_update_epoch: epoch.saturating_sub(1),
+        last_balance_scan_epoch: None,
         last_update_balance: 0,
         lots: vec![],
         no_sync: from_account.no_sync,
@@ -3457,6 +4443,7 @@ async fn process_account_split<T: Signers>(
     println!("Split confirmed: {signature}");
     let when = get_signature_date(rpc_client, signature).await?;
     db.confirm_transfer(signature, when)?;
+    record_transaction_fee(db, rpc_client, signature).await;
     if split_all {
         // TODO: This `remove_account` is racy and won't work in all cases. Consider plumbing the
         // removal through `confirm_transfer` instead
@@ -3548,6 +4535,7 @@ async fn process_account_redelegate<T: Signers>(
         token: MaybeToken::SOL(),
         description: from_account.description,
         last_update_epoch: epoch.saturating_sub(1),
+        last_balance_scan_epoch: None,
         last_update_balance: 0,
         lots: vec![],
         no_sync: None,
@@ -3574,10 +4562,12 @@ async fn process_account_redelegate<T: Signers>(
     println!("Redelegation confirmed: {signature}");
     let when = get_signature_date(rpc_client, signature).await?;
     db.confirm_transfer(signature, when)?;
+    record_transaction_fee(db, rpc_client, signature).await;
 
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn process_account_sync(
     db: &mut Db,
     rpc_clients: &RpcClients,
@@ -3585,11 +4575,13 @@ async fn process_account_sync(
     max_epochs_to_process: Option<u64>,
     reconcile_no_sync_account_balances: bool,
     force_rescan_balances: bool,
+    lot_selection_method: LotSelectionMethod,
     notifier: &Notifier,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let rpc_client = rpc_clients.default();
     process_account_sync_pending_transfers(db, rpc_client).await?;
     process_account_sync_sweep(db, rpc_clients, notifier).await?;
+    process_account_sync_block_rewards(db, rpc_client, address, notifier).await?;
 
     let (mut accounts, mut no_sync_accounts): (_, Vec<_>) = match address {
         Some(address) => {
@@ -3674,7 +4666,12 @@ async fn process_account_sync(
         .unwrap_or(&stop_epoch)
         + 1;
 
-    if start_epoch > stop_epoch && !force_rescan_balances {
+    // An interrupted sync may have processed epoch rewards but not balance changes
+    let balances_scanned = accounts
+        .iter()
+        .all(|account| account.balance_scan_epoch() >= stop_epoch);
+
+    if start_epoch > stop_epoch && balances_scanned && !force_rescan_balances {
         println!("Processed up to epoch {stop_epoch}");
         return Ok(());
     }
@@ -3686,48 +4683,351 @@ async fn process_account_sync(
         stop_epoch = stop_epoch.min(start_epoch.saturating_add(max_epochs_to_process - 1));
     }
 
-    // Look for inflationary rewards
-    for epoch in start_epoch..=stop_epoch {
-        let msg = format!("Processing epoch: {epoch}");
+    // Rewards that land in a locked stake account are discounted from their spot price. Only
+    // the current lockup is known, which may have been added, extended or expired since earlier
+    // epochs, so rewards of those aren't discounted
+    let fmv_discount_model = db.get_fmv_discount_model().copied();
+    let lockup_known_from_epoch = epoch_info.epoch.saturating_sub(1);
+    let mut undiscounted_rewards = HashSet::<Pubkey>::default();
+    let mut unlock_dates = HashMap::<Pubkey, NaiveDate>::default();
+    if fmv_discount_model.is_some() {
+        for account in &accounts {
+            if account.token.is_sol() {
+                if let Some(unlock_date) = get_stake_unlock_date(rpc_client, &account.address)? {
+                    unlock_dates.insert(account.address, unlock_date);
+                }
+            }
+        }
+    }
+
+    // Where to look for unexpected balance changes from
+    let epoch_schedule = rpc_client.get_epoch_schedule()?;
+    let min_slots = accounts
+        .iter()
+        .map(|account| epoch_schedule.get_first_slot_in_epoch(account.balance_scan_epoch() + 1))
+        .collect::<Vec<_>>();
+
+    // Look for inflationary rewards, fetching up to `MAX_CONCURRENT_EPOCHS` epochs at once off the
+    // runtime thread. The accounts are saved as each epoch completes, in order, so an interrupted
+    // sync resumes where it stopped
+    const MAX_CONCURRENT_EPOCHS: usize = 8;
+    let epochs = (start_epoch..=stop_epoch).collect::<Vec<_>>();
+    for batch in epochs.chunks(MAX_CONCURRENT_EPOCHS) {
+        let msg = match batch {
+            [epoch] => format!("Processing epoch: {epoch}"),
+            _ => format!("Processing epochs: {}-{}", batch[0], batch[batch.len() - 1]),
+        };
         notifier.send(&msg).await;
         println!("{msg}");
 
-        let inflation_rewards = rpc_client.get_inflation_reward(&addresses, Some(epoch))?;
+        let fetches = batch
+            .iter()
+            .map(|&epoch| {
+                let rpc_client =
+                    RpcClient::new_with_commitment(rpc_client.url(), rpc_client.commitment());
+                let addresses = addresses.clone();
+                tokio::task::spawn_blocking(move || {
+                    retry_get_inflation_rewards_with_dates(&rpc_client, &addresses, epoch)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (epoch, fetch) in batch.iter().copied().zip(fetches) {
+            let (inflation_rewards, block_dates) = fetch.await??;
+
+            // Rewards of an epoch are all credited in the same block, so there's about one price
+            // to fetch per epoch
+            let mut prices = HashMap::default();
+            for when in block_dates.values().copied().collect::<HashSet<_>>() {
+                prices.insert(
+                    when,
+                    retry_get_historical_price(rpc_client, when, MaybeToken::SOL()).await?,
+                );
+            }
+
+            for (inflation_reward, address, account) in
+                itertools::izip!(inflation_rewards, addresses.iter(), accounts.iter_mut(),)
+            {
+                assert_eq!(*address, account.address);
+                if account.last_update_epoch >= epoch {
+                    continue;
+                }
+                account.last_balance_scan_epoch = Some(account.balance_scan_epoch());
+                account.last_update_epoch = epoch;
+
+                if let Some(inflation_reward) = inflation_reward {
+                    assert!(!account.token.is_token()); // Only SOL accounts can receive inflationary rewards
+
+                    account.last_update_balance += inflation_reward.amount;
+
+                    let slot = inflation_reward.effective_slot;
+                    let when = block_dates[&slot];
+                    let (price, price_source) = prices[&when];
+                    let mut lot = Lot {
+                        lot_number: db.next_lot_number(),
+                        acquisition: LotAcquistion::new(
+                            when,
+                            price,
+                            LotAcquistionKind::EpochReward { epoch, slot },
+                            price_source,
+                        ),
+                        amount: inflation_reward.amount,
+                    };
+                    if let (Some(fmv_discount_model), Some(unlock_date)) =
+                        (fmv_discount_model, unlock_dates.get(address))
+                    {
+                        if epoch >= lockup_known_from_epoch {
+                            lot.acquisition
+                                .apply_fmv_discount(&fmv_discount_model, *unlock_date);
+                        } else if undiscounted_rewards.insert(*address) {
+                            println!(
+                                "Warning: The lockup of {address} before epoch \
+                                 {lockup_known_from_epoch} is unknown, its rewards of earlier \
+                                 epochs are not discounted"
+                            );
+                        }
+                    }
+
+                    let msg = format!("{}: {}", account.address, account.description);
+                    notifier.send(&msg).await;
+                    println!("{msg}");
+
+                    maybe_println_lot(
+                        account.token,
+                        &lot,
+                        Some(current_sol_price),
+                        None,
+                        &mut 0.,
+                        &mut 0.,
+                        &mut 0.,
+                        &mut false,
+                        &mut 0.,
+                        Some(notifier),
+                        true,
+                        true,
+                    )
+                    .await;
+                    account.lots.push(lot);
+                }
+            }
+            db.update_accounts_through_epoch(epoch, &accounts)?;
+        }
+    }
+
+    // Look for unexpected balance changes (such as transfers, transaction and rent rewards)
+    let identity_addresses = db
+        .get_identity_accounts()
+        .into_iter()
+        .map(|identity_account| identity_account.address)
+        .collect::<HashSet<_>>();
+    let known_signatures = db.known_signatures();
+    let mut tracked_transfers = vec![];
+    let mut identity_balances = vec![];
+    for (account, min_slot) in accounts.iter_mut().zip(min_slots) {
+        account.last_update_epoch = stop_epoch;
+        account.last_balance_scan_epoch = Some(stop_epoch);
+
+        let current_balance = account.token.balance(rpc_client, &account.address)?;
+
+        // Look for the transactions behind any surplus, however small, before they fall out of
+        // the scanned slots
+        let inflows = if current_balance > account.last_update_balance {
+            get_account_inflows(
+                &known_signatures,
+                rpc_client,
+                account,
+                min_slot,
+                current_balance - account.last_update_balance,
+            )?
+        } else {
+            vec![]
+        };
 
-        for (inflation_reward, address, account) in
-            itertools::izip!(inflation_rewards, addresses.iter(), accounts.iter_mut(),)
+        if current_balance < account.last_update_balance
+            && account.token.is_sol()
+            && identity_addresses.contains(&account.address)
         {
-            assert_eq!(*address, account.address);
-            if account.last_update_epoch >= epoch {
-                continue;
+            // Settled once transfers to other tracked accounts have been moved below
+            identity_balances.push((account.address, current_balance, min_slot));
+        } else if current_balance < account.last_update_balance {
+            let mut deficit = account.last_update_balance - current_balance;
+
+            // Stake split or merged away outside of `sys`
+            if account.token.is_sol() {
+                for stake_outflow in get_account_stake_outflows(
+                    &known_signatures,
+                    rpc_client,
+                    account,
+                    min_slot,
+                    deficit,
+                )? {
+                    deficit = deficit.saturating_sub(stake_outflow.amount);
+                    let merge = stake_outflow.kind == StakeOutflowKind::Merge;
+
+                    // The destination may already have claimed the transfer as an inflow
+                    if let Some(tracked_transfer) =
+                        tracked_transfers.iter_mut().find(|tracked_transfer| {
+                            tracked_transfer.signature == stake_outflow.signature
+                        })
+                    {
+                        tracked_transfer.merge |= merge;
+                        continue;
+                    }
+
+                    println!(
+                        "{}: {} of {} into {} ({})",
+                        account.address,
+                        if merge { "Merge" } else { "Split" },
+                        account.token.format_amount(stake_outflow.amount),
+                        stake_outflow.destination,
+                        stake_outflow.signature
+                    );
+                    tracked_transfers.push(TrackedTransfer {
+                        signature: stake_outflow.signature,
+                        slot: stake_outflow.slot,
+                        when: rpc_client_utils::get_block_date(rpc_client, stake_outflow.slot)
+                            .await?,
+                        from_address: account.address,
+                        to_address: stake_outflow.destination,
+                        token: account.token,
+                        amount: stake_outflow.amount,
+                        merge,
+                    });
+                }
+            }
+
+            if deficit > 0 {
+                println!(
+                    "\nWarning: {} ({}) balance is less than expected. Actual: {}{}, expected: {}{}\n",
+                    account.address,
+                    account.token,
+                    account.token.symbol(),
+                    account.token.ui_amount(current_balance),
+                    account.token.symbol(),
+                    account.token.ui_amount(account.last_update_balance)
+                );
             }
+        } else if !inflows.is_empty()
+            || current_balance > account.last_update_balance + account.token.amount(0.005)
+        {
+            let current_token_price = account.token.get_current_price(rpc_client).await?;
+            let mut surplus = current_balance - account.last_update_balance;
+
+            let msg = format!(
+                "{} ({}): {}",
+                account.address, account.token, account.description
+            );
+            notifier.send(&msg).await;
+            println!("{msg}");
 
-            if let Some(inflation_reward) = inflation_reward {
-                assert!(!account.token.is_token()); // Only SOL accounts can receive inflationary rewards
+            for inflow in inflows {
+                surplus -= inflow.amount;
 
-                account.last_update_balance += inflation_reward.amount;
+                let (when, decimal_price, price_source) =
+                    get_block_date_and_price(rpc_client, inflow.slot, account.token).await?;
 
-                let slot = inflation_reward.effective_slot;
-                let (when, price) =
-                    get_block_date_and_price(rpc_client, slot, account.token).await?;
+                if let Some(tip_distribution_account) = inflow
+                    .tip_distribution_account
+                    .filter(|_| account.token.is_sol())
+                {
+                    let claim_epoch = epoch_schedule.get_epoch(inflow.slot);
+                    let epoch = match get_tip_distribution_epoch(
+                        rpc_client,
+                        &tip_distribution_account,
+                        &account.address,
+                        claim_epoch,
+                    )? {
+                        Some(epoch) => epoch,
+                        None => {
+                            // Tips are claimed once the epoch they were earned in has ended
+                            let epoch = claim_epoch.saturating_sub(1);
+                            println!(
+                                "Warning: Unable to determine the epoch of the MEV tips claimed by \
+                                 {}, assuming epoch {epoch}",
+                                inflow.signature
+                            );
+                            epoch
+                        }
+                    };
+                    println!("  MEV tips for epoch {epoch} ({})", inflow.signature);
+                    let lot = Lot {
+                        lot_number: db.next_lot_number(),
+                        acquisition: LotAcquistion::new(
+                            when,
+                            decimal_price,
+                            LotAcquistionKind::MevReward {
+                                epoch,
+                                signature: inflow.signature,
+                            },
+                            price_source,
+                        ),
+                        amount: inflow.amount,
+                    };
+                    maybe_println_lot(
+                        account.token,
+                        &lot,
+                        Some(current_token_price),
+                        None,
+                        &mut 0.,
+                        &mut 0.,
+                        &mut 0.,
+                        &mut false,
+                        &mut 0.,
+                        Some(notifier),
+                        true,
+                        true,
+                    )
+                    .await;
+                    account.lots.push(lot);
+                    account.last_update_balance += inflow.amount;
+                    continue;
+                }
+
+                if let Some(sender) = inflow.senders.iter().copied().find(|sender| {
+                    *sender != account.address && db.get_account(*sender, account.token).is_some()
+                }) {
+                    println!(
+                        "  Transfer of {} from tracked account {sender} ({})",
+                        account.token.format_amount(inflow.amount),
+                        inflow.signature
+                    );
+                    // Unless the sender already claimed it as a split or merge
+                    if !tracked_transfers
+                        .iter()
+                        .any(|tracked_transfer| tracked_transfer.signature == inflow.signature)
+                    {
+                        tracked_transfers.push(TrackedTransfer {
+                            signature: inflow.signature,
+                            slot: inflow.slot,
+                            when,
+                            from_address: sender,
+                            to_address: account.address,
+                            token: account.token,
+                            amount: inflow.amount,
+                            merge: false,
+                        });
+                    }
+                    continue;
+                }
+
+                println!("  External deposit ({})", inflow.signature);
                 let lot = Lot {
                     lot_number: db.next_lot_number(),
                     acquisition: LotAcquistion::new(
                         when,
-                        price,
-                        LotAcquistionKind::EpochReward { epoch, slot },
+                        decimal_price,
+                        LotAcquistionKind::Transaction {
+                            slot: inflow.slot,
+                            signature: inflow.signature,
+                        },
+                        price_source,
                     ),
-                    amount: inflation_reward.amount,
+                    amount: inflow.amount,
                 };
-
-                let msg = format!("{}: {}", account.address, account.description);
-                notifier.send(&msg).await;
-                println!("{msg}");
-
                 maybe_println_lot(
                     account.token,
                     &lot,
-                    Some(current_sol_price),
+                    Some(current_token_price),
                     None,
                     &mut 0.,
                     &mut 0.,
@@ -3740,74 +5040,417 @@ async fn process_account_sync(
                 )
                 .await;
                 account.lots.push(lot);
+                account.last_update_balance += inflow.amount;
             }
-        }
-    }
-
-    // Look for unexpected balance changes (such as transaction and rent rewards)
-    for account in accounts.iter_mut() {
-        account.last_update_epoch = stop_epoch;
-
-        let current_balance = account.token.balance(rpc_client, &account.address)?;
-        if current_balance < account.last_update_balance {
-            println!(
-                "\nWarning: {} ({}) balance is less than expected. Actual: {}{}, expected: {}{}\n",
-                account.address,
-                account.token,
-                account.token.symbol(),
-                account.token.ui_amount(current_balance),
-                account.token.symbol(),
-                account.token.ui_amount(account.last_update_balance)
-            );
-        } else if current_balance > account.last_update_balance + account.token.amount(0.005) {
-            let slot = epoch_info.absolute_slot;
-            let current_token_price = account.token.get_current_price(rpc_client).await?;
-            let (when, decimal_price) =
-                get_block_date_and_price(rpc_client, slot, account.token).await?;
-            let amount = current_balance - account.last_update_balance;
 
-            let lot = Lot {
-                lot_number: db.next_lot_number(),
-                acquisition: LotAcquistion::new(
-                    when,
-                    decimal_price,
-                    LotAcquistionKind::NotAvailable,
-                ),
-                amount,
-            };
+            // What remains was credited without a transaction of its own, such as block rewards.
+            // Small amounts are left to accumulate until a later sync
+            if surplus > account.token.amount(0.005) {
+                println!("  Block reward or other income");
+                let slot = epoch_info.absolute_slot;
+                let (when, decimal_price, price_source) =
+                    get_block_date_and_price(rpc_client, slot, account.token).await?;
+                let lot = Lot {
+                    lot_number: db.next_lot_number(),
+                    acquisition: LotAcquistion::new(
+                        when,
+                        decimal_price,
+                        LotAcquistionKind::NotAvailable,
+                        price_source,
+                    ),
+                    amount: surplus,
+                };
+                maybe_println_lot(
+                    account.token,
+                    &lot,
+                    Some(current_token_price),
+                    None,
+                    &mut 0.,
+                    &mut 0.,
+                    &mut 0.,
+                    &mut false,
+                    &mut 0.,
+                    Some(notifier),
+                    true,
+                    true,
+                )
+                .await;
+                account.lots.push(lot);
+                account.last_update_balance += surplus;
+            }
+        }
 
-            let msg = format!(
-                "{} ({}): {}",
-                account.address, account.token, account.description
-            );
-            notifier.send(&msg).await;
-            println!("{msg}");
+        db.update_account(account.clone())?;
+    }
 
-            maybe_println_lot(
-                account.token,
-                &lot,
-                Some(current_token_price),
+    // Move the lots of transfers between tracked accounts that were made outside of `sys`
+    for TrackedTransfer {
+        signature,
+        slot,
+        when,
+        from_address,
+        to_address,
+        token,
+        amount,
+        merge,
+    } in tracked_transfers
+    {
+        if db.get_account(to_address, token).is_none() {
+            if merge {
+                // The balance the destination held before the merge has no lots to account for it
+                println!(
+                    "Warning: {from_address} ({token}) was merged into untracked account \
+                     {to_address} ({signature}), its lots were not moved",
+                );
+                continue;
+            }
+
+            // Adopt the stake account that was split off
+            let from_account = db
+                .get_account(from_address, token)
+                .ok_or_else(|| format!("{from_address} ({token}) does not exist"))?;
+            db.add_account(TrackedAccount {
+                address: to_address,
+                token,
+                description: format!("Split from {from_address}"),
+                last_update_epoch: from_account.last_update_epoch,
+                last_balance_scan_epoch: None,
+                last_update_balance: 0,
+                lots: vec![],
+                no_sync: from_account.no_sync,
+            })?;
+        }
+
+        let transferred = db
+            .record_transfer(
+                signature,
+                0,
+                if merge { None } else { Some(amount) },
+                from_address,
+                token,
+                to_address,
+                token,
+                lot_selection_method,
                 None,
-                &mut 0.,
-                &mut 0.,
-                &mut 0.,
-                &mut false,
-                &mut 0.,
-                Some(notifier),
-                true,
-                true,
             )
-            .await;
-            account.lots.push(lot);
-            account.last_update_balance = current_balance;
+            .and_then(|()| db.confirm_transfer(signature, when));
+
+        match transferred {
+            Ok(()) => {
+                if merge {
+                    println!("Removing {from_address} ({token}), merged into {to_address}");
+                    db.remove_account(from_address, token)?;
+                }
+            }
+            Err(err) => {
+                // The sender's lots don't cover the transfer, book it as an external deposit instead
+                println!(
+                    "Unable to move lots from {from_address} ({token}): {err}. Recording {signature} as a deposit",
+                );
+                let (when, decimal_price, price_source) =
+                    get_block_date_and_price(rpc_client, slot, token).await?;
+                let mut to_account = db
+                    .get_account(to_address, token)
+                    .ok_or_else(|| format!("{to_address} ({token}) does not exist"))?;
+                to_account.lots.push(Lot {
+                    lot_number: db.next_lot_number(),
+                    acquisition: LotAcquistion::new(
+                        when,
+                        decimal_price,
+                        LotAcquistionKind::Transaction { slot, signature },
+                        price_source,
+                    ),
+                    amount,
+                });
+                to_account.last_update_balance += amount;
+                db.update_account(to_account)?;
+            }
         }
+    }
 
-        db.update_account(account.clone())?;
+    // Block rewards are credited in full, so an identity's outflows that remain are mostly the
+    // fees of its vote transactions. Any other deficit is left to the balance check
+    for (address, current_balance, min_slot) in identity_balances {
+        let token = MaybeToken::SOL();
+        let mut deficit = match db.get_account(address, token) {
+            Some(account) if account.last_update_balance > current_balance => {
+                account.last_update_balance - current_balance
+            }
+            _ => continue,
+        };
+        for (when, fees) in
+            get_vote_transaction_fees(&known_signatures, rpc_client, &address, min_slot)?
+        {
+            let fees = fees.min(deficit);
+            if fees == 0 {
+                continue;
+            }
+            println!(
+                "{address}: vote transaction fees of {} on {when}",
+                token.format_amount(fees)
+            );
+            let (decimal_price, _price_source) =
+                retry_get_historical_price(rpc_client, when, token).await?;
+            db.record_disposal(
+                address,
+                token,
+                fees,
+                "vote transaction fees".into(),
+                when,
+                decimal_price,
+                LotSelectionMethod::default(),
+                None,
+            )?;
+            deficit -= fees;
+        }
+
+        if deficit > 0 {
+            let account = db.get_account(address, token).unwrap();
+            println!(
+                "\nWarning: {} ({}) balance is less than expected. Actual: {}{}, expected: {}{}\n",
+                account.address,
+                account.token,
+                account.token.symbol(),
+                account.token.ui_amount(current_balance),
+                account.token.symbol(),
+                account.token.ui_amount(account.last_update_balance)
+            );
+        }
     }
 
     Ok(())
 }
 
+// Visit the successful transactions of `address` since `min_slot` that are not yet accounted for,
+// newest first, for as long as `visit` returns true
+fn visit_new_signatures(
+    known_signatures: &HashSet<Signature>,
+    rpc_client: &RpcClient,
+    address: &Pubkey,
+    min_slot: Slot,
+    mut visit: impl FnMut(Signature) -> Result<bool, Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    const MAX_SIGNATURES: usize = 1_000;
+
+    let mut before = None;
+    let mut signatures_examined = 0;
+    loop {
+        let signatures = rpc_client.get_signatures_for_address_with_config(
+            address,
+            GetConfirmedSignaturesForAddress2Config {
+                before,
+                until: None,
+                limit: None,
+                commitment: Some(rpc_client.commitment()),
+            },
+        )?;
+        if signatures.is_empty() {
+            return Ok(());
+        }
+
+        for confirmed_signature in signatures {
+            if confirmed_signature.slot < min_slot {
+                return Ok(());
+            }
+            signatures_examined += 1;
+            if signatures_examined > MAX_SIGNATURES {
+                println!(
+                    "Warning: Stopped looking for the transactions of {address} after \
+                     {MAX_SIGNATURES} signatures, those before slot {} were not examined",
+                    confirmed_signature.slot
+                );
+                return Ok(());
+            }
+            let signature = confirmed_signature.signature.parse::<Signature>()?;
+            before = Some(signature);
+            if confirmed_signature.err.is_some() || known_signatures.contains(&signature) {
+                continue;
+            }
+            if !visit(signature)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// The fees paid by `identity` for its transactions since `min_slot` that are not yet accounted for,
+// by date. These are its vote transactions, which all pay the same fee, so only one is fetched
+fn get_vote_transaction_fees(
+    known_signatures: &HashSet<Signature>,
+    rpc_client: &RpcClient,
+    identity: &Pubkey,
+    min_slot: Slot,
+) -> Result<BTreeMap<NaiveDate, u64>, Box<dyn std::error::Error>> {
+    let mut transactions_by_date = BTreeMap::<NaiveDate, u64>::default();
+    let mut fee = None;
+    let mut before = None;
+    'pages: loop {
+        let signatures = rpc_client.get_signatures_for_address_with_config(
+            identity,
+            GetConfirmedSignaturesForAddress2Config {
+                before,
+                until: None,
+                limit: None,
+                commitment: Some(rpc_client.commitment()),
+            },
+        )?;
+        if signatures.is_empty() {
+            break;
+        }
+
+        for confirmed_signature in signatures {
+            if confirmed_signature.slot < min_slot {
+                break 'pages;
+            }
+            let signature = confirmed_signature.signature.parse::<Signature>()?;
+            before = Some(signature);
+            // Failed transactions pay their fee too
+            if known_signatures.contains(&signature) {
+                continue;
+            }
+            if fee.is_none() {
+                fee = Some(rpc_client_utils::get_transaction_fee(rpc_client, signature)?.1);
+            }
+            let block_time = match confirmed_signature.block_time {
+                Some(block_time) => block_time,
+                None => rpc_client.get_block_time(confirmed_signature.slot)?,
+            };
+            *transactions_by_date
+                .entry(rpc_client_utils::block_time_to_date(block_time))
+                .or_default() += 1;
+        }
+    }
+
+    let fee = fee.unwrap_or_default();
+    Ok(transactions_by_date
+        .into_iter()
+        .map(|(when, transactions)| (when, transactions * fee))
+        .collect())
+}
+
+// Attribute up to `surplus` of the balance of `account` to the most recent transactions since
+// `min_slot` that increased it, newest first. Transactions already accounted for are skipped
+fn get_account_inflows(
+    known_signatures: &HashSet<Signature>,
+    rpc_client: &RpcClient,
+    account: &TrackedAccount,
+    min_slot: Slot,
+    surplus: u64,
+) -> Result<Vec<TransactionInflow>, Box<dyn std::error::Error>> {
+    let (address, mint) = if account.token.is_token() {
+        (
+            account.token.ata(&account.address),
+            Some(account.token.mint()),
+        )
+    } else {
+        (account.address, None)
+    };
+
+    // A vote account sees a vote transaction every slot, none of which credit it
+    let vote_account = mint.is_none()
+        && rpc_client
+            .get_account_with_commitment(&address, rpc_client.commitment())?
+            .value
+            .is_some_and(|account| account.owner == solana_program::vote::program::id());
+
+    let mut inflows = vec![];
+    let mut remaining = surplus;
+
+    // Jito MEV tip claims are found through the tip distribution program's accounts, as those
+    // of a vote account are buried among its votes
+    if mint.is_none() {
+        for signature in get_tip_distribution_claims(rpc_client, &address, min_slot)? {
+            if remaining == 0 {
+                break;
+            }
+            if known_signatures.contains(&signature) {
+                continue;
+            }
+            if let Some(mut inflow) =
+                get_transaction_inflow(rpc_client, &signature, &account.address, None)?
+            {
+                inflow.amount = inflow.amount.min(remaining);
+                remaining -= inflow.amount;
+                inflows.push(inflow);
+            }
+        }
+    }
+
+    if remaining > 0 && !vote_account {
+        visit_new_signatures(
+            known_signatures,
+            rpc_client,
+            &address,
+            min_slot,
+            |signature| {
+                if inflows.iter().any(|inflow| inflow.signature == signature) {
+                    return Ok(true);
+                }
+                if let Some(mut inflow) =
+                    get_transaction_inflow(rpc_client, &signature, &account.address, mint)?
+                {
+                    inflow.amount = inflow.amount.min(remaining);
+                    remaining -= inflow.amount;
+                    inflows.push(inflow);
+                }
+                Ok(remaining > 0)
+            },
+        )?;
+    }
+    Ok(inflows)
+}
+
+// The splits and merges since `min_slot` that account for up to `deficit` of the balance of stake
+// account `account`, oldest first
+fn get_account_stake_outflows(
+    known_signatures: &HashSet<Signature>,
+    rpc_client: &RpcClient,
+    account: &TrackedAccount,
+    min_slot: Slot,
+    deficit: u64,
+) -> Result<Vec<StakeOutflow>, Box<dyn std::error::Error>> {
+    // Only stake accounts split or merge, and scanning a vote account's votes is wasted effort.
+    // A stake account that was merged away no longer exists
+    let stake_account = rpc_client
+        .get_account_with_commitment(&account.address, rpc_client.commitment())?
+        .value
+        .is_none_or(|account| account.owner == solana_sdk::stake::program::id());
+
+    let mut stake_outflows = vec![];
+    let mut remaining = deficit;
+    if remaining > 0 && stake_account {
+        visit_new_signatures(
+            known_signatures,
+            rpc_client,
+            &account.address,
+            min_slot,
+            |signature| {
+                for stake_outflow in
+                    get_transaction_stake_outflows(rpc_client, &signature, &account.address)?
+                {
+                    remaining = remaining.saturating_sub(stake_outflow.amount);
+                    stake_outflows.push(stake_outflow);
+                }
+                Ok(remaining > 0)
+            },
+        )?;
+    }
+    stake_outflows.reverse();
+    Ok(stake_outflows)
+}
+
+// Lots to move between tracked accounts for a transaction made outside of `sys`
+struct TrackedTransfer {
+    signature: Signature,
+    slot: Slot,
+    when: NaiveDate,
+    from_address: Pubkey,
+    to_address: Pubkey,
+    token: MaybeToken,
+    amount: u64,
+    merge: bool, // All of `from_address` was merged into `to_address`
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn process_account_wrap<T: Signers>(
     db: &mut Db,
@@ -3855,6 +5498,7 @@ async fn process_account_wrap<T: Signers>(
             token: wsol.into(),
             description: from_account.description,
             last_update_epoch: epoch,
+            last_balance_scan_epoch: None,
             last_update_balance: 0,
             lots: vec![],
             no_sync: None,
@@ -3914,6 +5558,7 @@ async fn process_account_wrap<T: Signers>(
     println!("Wrap confirmed: {signature}");
     let when = get_signature_date(rpc_client, signature).await?;
     db.confirm_transfer(signature, when)?;
+    record_transaction_fee(db, rpc_client, signature).await;
 
     Ok(())
 }
@@ -4015,10 +5660,227 @@ async fn process_account_unwrap<T: Signers>(
     println!("Unwrap confirmed: {signature}");
     let when = get_signature_date(rpc_client, signature).await?;
     db.confirm_transfer(signature, when)?;
+    record_transaction_fee(db, rpc_client, signature).await;
+
+    Ok(())
+}
+
+// Record the fee rewards of the blocks produced by validator identity accounts since they were
+// last synced, as one lot per block or per day for each of the base and priority fee rewards
+async fn process_account_sync_block_rewards(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    address: Option<Pubkey>,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let identity_accounts = db
+        .get_identity_accounts()
+        .into_iter()
+        .filter(|identity_account| {
+            address.is_none_or(|address| address == identity_account.address)
+        })
+        .collect::<Vec<_>>();
+    if identity_accounts.is_empty() {
+        return Ok(());
+    }
+
+    let token = MaybeToken::SOL();
+    let epoch_schedule = rpc_client.get_epoch_schedule()?;
+    let current_slot = rpc_client.get_slot_with_commitment(CommitmentConfig::finalized())?;
+
+    for mut identity_account in identity_accounts {
+        let Some(mut account) = db.get_account(identity_account.address, token) else {
+            println!(
+                "Warning: identity account {} is not tracked",
+                identity_account.address
+            );
+            continue;
+        };
+        if identity_account.last_slot >= current_slot {
+            continue;
+        }
+
+        let mut leader_slots = vec![];
+        for epoch in epoch_schedule.get_epoch(identity_account.last_slot + 1)
+            ..=epoch_schedule.get_epoch(current_slot)
+        {
+            match get_leader_slots(rpc_client, &epoch_schedule, epoch, &account.address)? {
+                Some(slots) => leader_slots.extend(
+                    slots
+                        .into_iter()
+                        .filter(|slot| *slot > identity_account.last_slot && *slot <= current_slot),
+                ),
+                None => println!(
+                    "Warning: leader schedule for epoch {epoch} is not available, \
+                     block rewards of {} in that epoch will not be attributed to their blocks",
+                    account.address
+                ),
+            }
+        }
+
+        let mut prices = HashMap::<NaiveDate, (Decimal, Option<PriceSourceKind>)>::default();
+        let mut num_blocks = 0;
+        let mut total_fee = 0;
+        let mut total_priority_fee = 0;
+        for slot in leader_slots {
+            let Some(block_fee_reward) = get_block_fee_reward(rpc_client, slot, &account.address)?
+            else {
+                continue;
+            };
+            num_blocks += 1;
+            total_fee += block_fee_reward.fee;
+            total_priority_fee += block_fee_reward.priority_fee;
+
+            let when = block_fee_reward.when;
+            let (decimal_price, price_source) = match prices.get(&when) {
+                Some(price) => *price,
+                None => {
+                    let price = retry_get_historical_price(rpc_client, when, token).await?;
+                    prices.insert(when, price);
+                    price
+                }
+            };
+
+            for (reward_type, amount) in [
+                (BlockRewardType::Fee, block_fee_reward.fee),
+                (BlockRewardType::PriorityFee, block_fee_reward.priority_fee),
+            ] {
+                if amount == 0 {
+                    continue;
+                }
+                account.last_update_balance += amount;
+
+                if identity_account.granularity == BlockRewardGranularity::Day {
+                    let day_lot = account.lots.iter_mut().find(|lot| {
+                        lot.acquisition.when == when
+                            && matches!(
+                                lot.acquisition.kind,
+                                LotAcquistionKind::BlockReward { reward_type: lot_reward_type, .. }
+                                    if lot_reward_type == reward_type
+                            )
+                    });
+                    if let Some(day_lot) = day_lot {
+                        day_lot.amount += amount;
+                        if let LotAcquistionKind::BlockReward { blocks, .. } =
+                            &mut day_lot.acquisition.kind
+                        {
+                            *blocks += 1;
+                        }
+                        continue;
+                    }
+                }
+
+                account.lots.push(Lot {
+                    lot_number: db.next_lot_number(),
+                    acquisition: LotAcquistion::new(
+                        when,
+                        decimal_price,
+                        LotAcquistionKind::BlockReward {
+                            slot,
+                            reward_type,
+                            blocks: 1,
+                        },
+                        price_source,
+                    ),
+                    amount,
+                });
+            }
+        }
+
+        if num_blocks > 0 {
+            let msg = format!(
+                "{}: {} blocks produced, fee rewards: {}, priority fee rewards: {}",
+                account.address,
+                num_blocks,
+                token.format_amount(total_fee),
+                token.format_amount(total_priority_fee),
+            );
+            notifier.send(&msg).await;
+            println!("{msg}");
+        }
+
+        identity_account.last_slot = current_slot;
+        db.update_identity_account_block_rewards(account, identity_account)?;
+    }
 
     Ok(())
 }
 
+fn process_account_block_rewards(db: &Db, year: Option<i32>) {
+    for identity_account in db.get_identity_accounts() {
+        println!(
+            "Identity {}: one lot per {}, synced up to slot {}",
+            identity_account.address,
+            <&str>::from(identity_account.granularity),
+            identity_account.last_slot
+        );
+    }
+
+    // Block reward lots may since have been transferred to other accounts or disposed of
+    let token = MaybeToken::SOL();
+    let lots = db
+        .get_accounts()
+        .into_iter()
+        .filter(|account| account.token == token)
+        .flat_map(|account| account.lots)
+        .chain(
+            db.disposed_lots()
+                .into_iter()
+                .filter(|disposed_lot| disposed_lot.token == token)
+                .map(|disposed_lot| disposed_lot.lot),
+        );
+
+    let mut monthly_rewards = BTreeMap::<(NaiveDate, &str), (u64, f64)>::default();
+    for lot in lots {
+        let LotAcquistionKind::BlockReward { reward_type, .. } = lot.acquisition.kind else {
+            continue;
+        };
+        let when = lot.acquisition.when;
+        if year.is_some_and(|year| when.year() != year) {
+            continue;
+        }
+        let reward_type = match reward_type {
+            BlockRewardType::Fee => "fee",
+            BlockRewardType::PriorityFee => "priority fee",
+        };
+        let (amount, income) = monthly_rewards
+            .entry((
+                NaiveDate::from_ymd_opt(when.year(), when.month(), 1).unwrap(),
+                reward_type,
+            ))
+            .or_default();
+        *amount += lot.amount;
+        *income += lot.income(token);
+    }
+
+    if monthly_rewards.is_empty() {
+        println!("No block rewards");
+        return;
+    }
+
+    let mut totals = BTreeMap::<&str, (u64, f64)>::default();
+    for ((month, reward_type), (amount, income)) in monthly_rewards {
+        println!(
+            "{} | {:>12} rewards: {:>20} | income: ${}",
+            month.format("%Y-%m"),
+            reward_type,
+            token.format_amount(amount),
+            income.separated_string_with_fixed_place(2)
+        );
+        let total = totals.entry(reward_type).or_default();
+        total.0 += amount;
+        total.1 += income;
+    }
+    for (reward_type, (amount, income)) in totals {
+        println!(
+            "Total {} rewards: {} | income: ${}",
+            reward_type,
+            token.format_amount(amount),
+            income.separated_string_with_fixed_place(2)
+        );
+    }
+}
+
 async fn process_account_sync_pending_transfers(
     db: &mut Db,
     rpc_client: &RpcClient,
@@ -4041,6 +5903,7 @@ async fn process_account_sync_pending_transfers(
                     println!("Pending transfer confirmed: {signature}");
                     let when = get_signature_date(rpc_client, signature).await?;
                     db.confirm_transfer(signature, when)?;
+                    record_transaction_fee(db, rpc_client, signature).await;
                 } else {
                     println!("Pending transfer failed with {result:?}: {signature}");
                     db.cancel_transfer(signature)?;
@@ -4216,6 +6079,7 @@ async fn process_account_sync_sweep(
         }
         let when = get_signature_date(rpc_client, signature).await?;
         db.confirm_transfer(signature, when)?;
+        record_transaction_fee(db, rpc_client, signature).await;
         db.remove_transitory_sweep_stake_address(transitory_sweep_stake_address)?;
     }
     Ok(())
@@ -4245,7 +6109,10 @@ fn lot_selection_arg<'a, 'b>() -> Arg<'a, 'b> {
         .validator(is_parsable::<LotSelectionMethod>)
         .default_value(POSSIBLE_LOT_SELECTION_METHOD_VALUES[0])
         .possible_values(POSSIBLE_LOT_SELECTION_METHOD_VALUES)
-        .help("Lot selection method")
+        .help(
+            "Lot selection method. The tax-optimal method requires a tax rate, \
+            see `sys account set-tax-rate`",
+        )
 }
 
 fn is_tax_rate(s: String) -> Result<(), String> {
@@ -4285,6 +6152,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .global(true)
                 .help("Database path"),
         )
+        .arg(
+            Arg::with_name("db_key_file")
+                .long("db-key-file")
+                .value_name("PATH")
+                .takes_value(true)
+                .global(true)
+                .help("Key file used to encrypt the exchange credentials database. \
+                       The SYS_DB_PASSPHRASE or SYS_DB_KEY_FILE environment variables \
+                       may be used instead"),
+        )
         .arg(
             Arg::with_name("json_rpc_url")
                 .short("u")
@@ -4314,6 +6191,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .validator(is_url)
                 .help("Helius JSON RPC URL to use only for the proprietary getPriorityFeeEstimate RPC method"),
         )
+        .arg(
+            Arg::with_name("price_sources")
+                .long("price-sources")
+                .value_name("SOURCE")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .global(true)
+                .possible_values(POSSIBLE_PRICE_SOURCE_VALUES)
+                .help("Price sources to consult, in priority order, falling back to the next \
+                       source when a price is not available [default: coingecko,pyth,jupiter]"),
+        )
+        .arg(
+            Arg::with_name("override_closed_years")
+                .long("override-closed-years")
+                .takes_value(false)
+                .global(true)
+                .help("Permit changes to lots acquired or disposed of in a closed tax year. \
+                       Each change is recorded in the database journal"),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -4361,6 +6258,127 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .help("Date to fetch the price for [default: current spot price]"),
                 )
         )
+        .subcommand(
+            SubCommand::with_name("price-cache")
+                .about("Historical price cache management")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::InferSubcommands)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List cached historical prices")
+                        .arg(
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .validator(is_valid_token_or_sol)
+                                .help("Only list prices for this token"),
+                        )
+                        .arg(
+                            Arg::with_name("source")
+                                .long("source")
+                                .value_name("SOURCE")
+                                .takes_value(true)
+                                .possible_values(POSSIBLE_PRICE_SOURCE_VALUES)
+                                .help("Only list prices from this price source"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("prefill")
+                        .about("Fetch and cache historical prices for a range of dates")
+                        .arg(
+                            Arg::with_name("from")
+                                .value_name("YY/MM/DD")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("First date to fetch"),
+                        )
+                        .arg(
+                            Arg::with_name("to")
+                                .value_name("YY/MM/DD")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("Last date to fetch"),
+                        )
+                        .arg(
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token type"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Manually override the cached historical price for a date")
+                        .arg(
+                            Arg::with_name("when")
+                                .value_name("YY/MM/DD")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("Date"),
+                        )
+                        .arg(
+                            Arg::with_name("price")
+                                .value_name("USD")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_parsable::<Decimal>)
+                                .help("Price"),
+                        )
+                        .arg(
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token type"),
+                        )
+                        .arg(
+                            Arg::with_name("source")
+                                .long("source")
+                                .value_name("SOURCE")
+                                .takes_value(true)
+                                .possible_values(POSSIBLE_PRICE_SOURCE_VALUES)
+                                .help("Price source [default: the highest priority price source]"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Remove a cached historical price, including an override")
+                        .arg(
+                            Arg::with_name("when")
+                                .value_name("YY/MM/DD")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("Date"),
+                        )
+                        .arg(
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token type"),
+                        )
+                        .arg(
+                            Arg::with_name("source")
+                                .long("source")
+                                .value_name("SOURCE")
+                                .takes_value(true)
+                                .possible_values(POSSIBLE_PRICE_SOURCE_VALUES)
+                                .help("Price source [default: the highest priority price source]"),
+                        )
+                )
+        )
         .subcommand(
             SubCommand::with_name("sync")
                 .about("Synchronize with all exchanges and accounts"))
@@ -4378,13 +6396,78 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .setting(AppSettings::SubcommandRequiredElseHelp)
                 .setting(AppSettings::InferSubcommands)
                 .subcommand(
-                    SubCommand::with_name("import")
-                        .about("Import another database")
+                    SubCommand::with_name("import")
+                        .about("Import another database")
+                        .arg(
+                            Arg::with_name("other_db_path")
+                                .value_name("PATH")
+                                .takes_value(true)
+                                .help("Path to the database to import"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("rekey")
+                        .about("Encrypt the exchange credentials database with a new passphrase or key file")
+                        .arg(
+                            Arg::with_name("new_key_file")
+                                .long("new-key-file")
+                                .value_name("PATH")
+                                .takes_value(true)
+                                .help("Key file to encrypt with [default: prompt for a new passphrase]"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Verify the consistency of the database")
+                        .arg(
+                            Arg::with_name("onchain")
+                                .long("onchain")
+                                .takes_value(false)
+                                .help("Also compare the balances of synced accounts with their on-chain balances"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("log")
+                        .about("Show the journal of database operations, most recent first")
+                        .arg(
+                            Arg::with_name("limit")
+                                .long("limit")
+                                .value_name("COUNT")
+                                .takes_value(true)
+                                .validator(is_parsable::<usize>)
+                                .help("Only show the COUNT most recent operations"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("undo")
+                        .about("Undo the most recent database operations")
+                        .arg(
+                            Arg::with_name("count")
+                                .value_name("COUNT")
+                                .takes_value(true)
+                                .validator(is_parsable::<usize>)
+                                .default_value("1")
+                                .help("Number of operations to undo, as numbered by `sys db log`"),
+                        )
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .takes_value(false)
+                                .help("Also undo operations that track an external transaction, \
+                                       exchange order or transfer"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("close-year")
+                        .about("Close a tax year, refusing further changes to the lots acquired \
+                                or disposed of in that year")
                         .arg(
-                            Arg::with_name("other_db_path")
-                                .value_name("PATH")
+                            Arg::with_name("year")
+                                .value_name("YEAR")
                                 .takes_value(true)
-                                .help("Path to the database to import"),
+                                .required(true)
+                                .validator(is_parsable::<i32>)
+                                .help("Tax year to close"),
                         )
                 )
         )
@@ -4657,6 +6740,81 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 .help("Date to calculate cost basis for")
                         )
                 )
+                .subcommand(
+                    SubCommand::with_name("unrealized")
+                        .about("Display unrealized short-term and long-term gain/loss of holdings")
+                        .arg(
+                            Arg::with_name("when")
+                                .value_name("YY/MM/DD")
+                                .takes_value(true)
+                                .required(false)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .default_value(&default_when)
+                                .help("Date to value holdings on")
+                        )
+                        .arg(
+                            Arg::with_name("price_source")
+                                .long("price-source")
+                                .value_name("SOURCE")
+                                .takes_value(true)
+                                .possible_values(POSSIBLE_PRICE_SOURCE_VALUES)
+                                .help("Only use this price source to value holdings")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("harvest")
+                        .about("Find lots with unrealized losses and harvest them by swapping into another token")
+                        .arg(
+                            Arg::with_name("min_loss")
+                                .long("min-loss")
+                                .value_name("USD")
+                                .takes_value(true)
+                                .validator(is_parsable::<f64>)
+                                .default_value("100")
+                                .help("Only consider lots with an unrealized loss of at least this amount"),
+                        )
+                        .arg(
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .validator(is_valid_token_or_sol)
+                                .help("Only consider lots of this token"),
+                        )
+                        .arg(
+                            Arg::with_name("into_token")
+                                .long("into")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .validator(is_valid_token_or_sol)
+                                .default_value("USDC")
+                                .help("Token to swap the harvested lots into"),
+                        )
+                        .arg(
+                            Arg::with_name("signer")
+                                .long("signer")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .validator(is_valid_signer)
+                                .help("Harvest the lots held by this account with a Jupiter swap"),
+                        )
+                        .arg(
+                            Arg::with_name("confirm")
+                                .long("confirm")
+                                .takes_value(false)
+                                .requires("signer")
+                                .help("Confirm the swaps"),
+                        )
+                        .arg(
+                            Arg::with_name("slippage_bps")
+                                .long("slippage")
+                                .value_name("BPS")
+                                .takes_value(true)
+                                .validator(is_parsable::<u64>)
+                                .default_value("100")
+                                .help("Maximum slippage bps"),
+                        )
+                )
                 .subcommand(
                     SubCommand::with_name("xls")
                         .about("Export an Excel spreadsheet file")
@@ -4677,7 +6835,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 )
                 .subcommand(
                     SubCommand::with_name("csv")
-                        .about("Export cap gains to a CSV file importable into TurboTax")
+                        .about("Export to a CSV file importable into tax software")
                         .arg(
                             Arg::with_name("outfile")
                                 .value_name("FILEPATH")
@@ -4691,6 +6849,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 .takes_value(true)
                                 .validator(is_parsable::<usize>)
                                 .help("Limit export to realized gains affecting the given year"),
+                        )
+                        .arg(
+                            Arg::with_name("format")
+                                .long("format")
+                                .value_name("FORMAT")
+                                .takes_value(true)
+                                .possible_values(POSSIBLE_CSV_FORMAT_VALUES)
+                                .default_value("turbotax")
+                                .help("Tax software to export for. The turbotax format only includes \
+                                       realized gains, the others include all acquisitions and disposals"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("form8949")
+                        .about("Export a tax year's disposals as Form 8949 entries, with Schedule D totals")
+                        .arg(
+                            Arg::with_name("outfile")
+                                .value_name("FILEPATH")
+                                .takes_value(true)
+                                .required(true)
+                                .help(".csv or .txf file to write"),
+                        )
+                        .arg(
+                            Arg::with_name("year")
+                                .long("year")
+                                .value_name("YYYY")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_parsable::<usize>)
+                                .help("Tax year"),
                         ),
                 )
                 .subcommand(
@@ -4773,6 +6961,133 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     SubCommand::with_name("tax-rate")
                         .about("Show entity tax rate for account listing")
                 )
+                .subcommand(
+                    SubCommand::with_name("set-fmv-discount")
+                        .about("Set the FMV discount applied to epoch rewards received by locked stake accounts. \
+                                With no arguments the discount is disabled")
+                        .arg(
+                            Arg::with_name("fixed")
+                                .long("fixed")
+                                .value_name("RATE")
+                                .takes_value(true)
+                                .validator(is_tax_rate)
+                                .conflicts_with("annual")
+                                .help("Discount all locked rewards by this rate, regardless of the time until unlock")
+                        )
+                        .arg(
+                            Arg::with_name("annual")
+                                .long("annual")
+                                .value_name("RATE")
+                                .takes_value(true)
+                                .validator(is_tax_rate)
+                                .help("Discount locked rewards by this rate per year until unlock")
+                        )
+                        .arg(
+                            Arg::with_name("max")
+                                .long("max")
+                                .value_name("RATE")
+                                .takes_value(true)
+                                .validator(is_tax_rate)
+                                .default_value("0.5")
+                                .help("Maximum discount when using --annual")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("discounted-basis")
+                        .about("Display the discounted and undiscounted basis of lots acquired while locked")
+                )
+                .subcommand(
+                    SubCommand::with_name("set-identity")
+                        .about("Track the fee rewards of the blocks produced by a validator identity \
+                                account individually, starting from the current slot")
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Validator identity account address"),
+                        )
+                        .arg(
+                            Arg::with_name("granularity")
+                                .long("granularity")
+                                .value_name("GRANULARITY")
+                                .takes_value(true)
+                                .possible_values(POSSIBLE_BLOCK_REWARD_GRANULARITY_VALUES)
+                                .default_value("block")
+                                .help("Create one lot per produced block or per day"),
+                        )
+                        .arg(
+                            Arg::with_name("disable")
+                                .long("disable")
+                                .takes_value(false)
+                                .conflicts_with("granularity")
+                                .help("Stop tracking block rewards for this account"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("block-rewards")
+                        .about("Display the fee and priority fee rewards of produced blocks by month")
+                        .arg(
+                            Arg::with_name("year")
+                                .long("year")
+                                .value_name("YEAR")
+                                .takes_value(true)
+                                .validator(is_parsable::<i32>)
+                                .help("Only include rewards received in this year"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("set-tax-jurisdiction")
+                        .about("Set the tax jurisdiction that realized gains are reported under")
+                        .arg(
+                            Arg::with_name("tax_jurisdiction")
+                                .value_name("JURISDICTION")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_parsable::<TaxJurisdiction>)
+                                .possible_values(POSSIBLE_TAX_JURISDICTION_VALUES)
+                                .help("Tax jurisdiction")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("tax-jurisdiction")
+                        .about("Show the tax jurisdiction that realized gains are reported under")
+                )
+                .subcommand(
+                    SubCommand::with_name("tax-report")
+                        .about("Display realized capital gains for a tax year, matched by the rules of the tax jurisdiction")
+                        .arg(
+                            Arg::with_name("year")
+                                .long("year")
+                                .value_name("YYYY")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_parsable::<i32>)
+                                .help("Tax year"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("estimated-tax")
+                        .about("Calculate quarterly estimated tax payments from the income and \
+                                capital gains of each IRS estimated tax period")
+                        .arg(
+                            Arg::with_name("year")
+                                .long("year")
+                                .value_name("YYYY")
+                                .takes_value(true)
+                                .validator(is_parsable::<i32>)
+                                .help("Tax year [default: current year]"),
+                        )
+                        .arg(
+                            Arg::with_name("quarter")
+                                .long("quarter")
+                                .value_name("QUARTER")
+                                .takes_value(true)
+                                .possible_values(&["1", "2", "3", "4"])
+                                .help("Last quarter to calculate [default: current quarter, or 4 for past years]"),
+                        )
+                )
                 .subcommand(
                     SubCommand::with_name("merge")
                         .about("Merge one stake account into another")
@@ -5008,6 +7323,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 .takes_value(false)
                                 .help("Rescan for account balance changes even in same epoch (advanced; uncommon)"),
                         )
+                        .arg(
+                            lot_selection_arg()
+                                .help("Lot selection method for stake split or merged outside of sys"),
+                        )
                 )
                 .subcommand(
                     SubCommand::with_name("wrap")
@@ -5764,7 +8083,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let app_matches = app.get_matches();
     let db_path = value_t_or_exit!(app_matches, "db_path", PathBuf);
+    let credentials_key = match value_t!(app_matches, "db_key_file", PathBuf) {
+        Ok(key_file) => Some(CredentialsKey::from_key_file(key_file)?),
+        Err(_) => CredentialsKey::from_env()?,
+    };
     let verbose = app_matches.is_present("verbose");
+    if let Ok(price_sources) = values_t!(app_matches, "price_sources", PriceSourceKind) {
+        price_source::set_price_sources(price_sources);
+    }
 
     let priority_fee = if let Ok(ui_priority_fee) = value_t!(app_matches, "priority_fee_exact", f64)
     {
@@ -5807,10 +8133,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    let mut db = db::new(&db_path).unwrap_or_else(|err| {
+    let mut db = db::new(&db_path, credentials_key.clone()).unwrap_or_else(|err| {
         eprintln!("Failed to open {}: {}", db_path.display(), err);
         exit(1)
     });
+    price_cache::open(&db_path)?;
+    db.set_override_closed_years(app_matches.is_present("override_closed_years"));
 
     match app_matches.subcommand() {
         ("price", Some(arg_matches)) => {
@@ -5819,20 +8147,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .ok();
             let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
 
-            let (price, verbose_msg) = if let Some(when) = when {
+            let ((price, price_source), verbose_msg) = if let Some(when) = when {
                 (
-                    token.get_historical_price(rpc_client, when).await?,
+                    token
+                        .get_historical_price_with_source(rpc_client, when)
+                        .await?,
                     format!("Historical {token} price on {when}"),
                 )
             } else {
                 (
-                    token.get_current_price(rpc_client).await?,
+                    token.get_current_price_with_source(rpc_client).await?,
                     format!("Current {token} price"),
                 )
             };
 
             if verbose {
-                println!("{verbose_msg}: ${price:.6}");
+                match price_source {
+                    Some(price_source) => {
+                        println!("{verbose_msg}: ${price:.6} (source: {price_source})")
+                    }
+                    None => println!("{verbose_msg}: ${price:.6}"),
+                }
 
                 if let Some(liquidity_token) = token.liquidity_token() {
                     let rate = token.get_current_liquidity_token_rate(rpc_client).await?;
@@ -5847,11 +8182,82 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("{price:.6}");
             }
         }
+        ("price-cache", Some(price_cache_matches)) => match price_cache_matches.subcommand() {
+            ("list", Some(arg_matches)) => {
+                let token = value_t!(arg_matches, "token", String)
+                    .ok()
+                    .map(|token| MaybeToken::from(Token::from_str(&token).ok()));
+                let source = value_t!(arg_matches, "source", PriceSourceKind).ok();
+
+                let entries = price_cache::entries()
+                    .into_iter()
+                    .filter(|entry| token.is_none_or(|token| entry.token == token))
+                    .filter(|entry| source.is_none_or(|source| entry.source == source))
+                    .collect::<Vec<_>>();
+                if entries.is_empty() {
+                    println!("No cached prices");
+                }
+                for entry in entries {
+                    println!(
+                        "{}  {:>8}  {:>9}  ${:.6}{}",
+                        entry.when,
+                        entry.token.to_string(),
+                        entry.source.to_string(),
+                        entry.price,
+                        if entry.overridden {
+                            " (overridden)"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+            }
+            ("prefill", Some(arg_matches)) => {
+                let from = naivedate_of(&value_t_or_exit!(arg_matches, "from", String)).unwrap();
+                let to = naivedate_of(&value_t_or_exit!(arg_matches, "to", String)).unwrap();
+                let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+
+                if from > to {
+                    return Err(format!("{from} is after {to}").into());
+                }
+                for when in from.iter_days().take_while(|when| *when <= to) {
+                    let (price, price_source) =
+                        retry_get_historical_price(rpc_client, when, token).await?;
+                    match price_source {
+                        Some(price_source) => println!("{when}: ${price:.6} ({price_source})"),
+                        None => println!("{when}: ${price:.6}"),
+                    }
+                }
+            }
+            ("set", Some(arg_matches)) => {
+                let when = naivedate_of(&value_t_or_exit!(arg_matches, "when", String)).unwrap();
+                let price = value_t_or_exit!(arg_matches, "price", Decimal);
+                let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                let source = value_t!(arg_matches, "source", PriceSourceKind)
+                    .unwrap_or_else(|_| price_source::price_sources()[0]);
+
+                price_cache::set_override(when, &token, source, price)?;
+                println!("{token} price on {when} set to ${price} ({source})");
+            }
+            ("remove", Some(arg_matches)) => {
+                let when = naivedate_of(&value_t_or_exit!(arg_matches, "when", String)).unwrap();
+                let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                let source = value_t!(arg_matches, "source", PriceSourceKind)
+                    .unwrap_or_else(|_| price_source::price_sources()[0]);
+
+                if price_cache::remove(when, &token, source)? {
+                    println!("Removed cached {token} price on {when} ({source})");
+                } else {
+                    println!("No cached {token} price on {when} ({source})");
+                }
+            }
+            _ => unreachable!(),
+        },
         ("sync", Some(arg_matches)) => {
             let max_epochs_to_process = value_t!(arg_matches, "max_epochs_to_process", u64).ok();
             process_sync_swaps(&mut db, rpc_client, &notifier).await?;
             for (exchange, exchange_credentials, exchange_account) in
-                db.get_default_accounts_from_configured_exchanges()
+                db.get_default_accounts_from_configured_exchanges()?
             {
                 println!("Synchronizing {exchange:?} {exchange_account}...");
                 let exchange_client = exchange_client_new(exchange, exchange_credentials)?;
@@ -5871,6 +8277,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 max_epochs_to_process,
                 false,
                 false,
+                LotSelectionMethod::default(),
                 &notifier,
             )
             .await?;
@@ -5895,7 +8302,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                 };
 
-                let other_db = db::new(&other_db_path).unwrap_or_else(|err| {
+                let other_db = db::new(&other_db_path, credentials_key).unwrap_or_else(|err| {
                     eprintln!("Failed to open {}: {}", other_db_path.display(), err);
                     exit(1)
                 });
@@ -5903,6 +8310,102 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("Importing {}", other_db_path.display());
                 db.import_db(other_db)?;
             }
+            ("rekey", Some(arg_matches)) => {
+                let new_key = match value_t!(arg_matches, "new_key_file", PathBuf) {
+                    Ok(new_key_file) => CredentialsKey::from_key_file(new_key_file)?,
+                    Err(_) => CredentialsKey::prompt_new()?,
+                };
+                let was_encrypted = db.credentials_encrypted();
+                db.rekey_credentials(new_key)?;
+                if was_encrypted {
+                    println!("Credentials database re-encrypted");
+                } else {
+                    println!("Credentials database encrypted");
+                }
+            }
+            ("check", Some(arg_matches)) => {
+                let onchain = arg_matches.is_present("onchain");
+
+                let mut problems = db.check();
+                if onchain {
+                    // Exchange accounts are tracked through the exchange deposit address, whose
+                    // on-chain balance isn't theirs
+                    for account in db
+                        .get_accounts()
+                        .into_iter()
+                        .filter(|account| account.no_sync != Some(true))
+                    {
+                        let balance = account.token.balance(rpc_client, &account.address)?;
+                        if balance != account.last_update_balance {
+                            problems.push(format!(
+                                "{} ({}): on-chain balance of {}{} does not match tracked balance of {}{}",
+                                account.address,
+                                account.token,
+                                account.token.symbol(),
+                                account.token.ui_amount(balance),
+                                account.token.symbol(),
+                                account.token.ui_amount(account.last_update_balance),
+                            ));
+                        }
+                    }
+                }
+
+                if problems.is_empty() {
+                    println!("No problems found");
+                } else {
+                    for problem in &problems {
+                        println!("{problem}");
+                    }
+                    eprintln!("{} problem(s) found", problems.len());
+                    exit(1);
+                }
+            }
+            ("log", Some(arg_matches)) => {
+                let limit = value_t!(arg_matches, "limit", usize).ok();
+
+                let entries = db.journal_entries()?;
+                if entries.is_empty() {
+                    println!("No journaled operations");
+                }
+                for (i, entry) in entries
+                    .into_iter()
+                    .rev()
+                    .take(limit.unwrap_or(usize::MAX))
+                    .enumerate()
+                {
+                    println!(
+                        "{:>3}. {}  {}",
+                        i + 1,
+                        entry
+                            .timestamp
+                            .with_timezone(&Local)
+                            .format("%Y-%m-%d %H:%M:%S"),
+                        entry.operation,
+                    );
+                    println!("     {}", entry.command);
+                }
+            }
+            ("undo", Some(arg_matches)) => {
+                let count = value_t_or_exit!(arg_matches, "count", usize);
+                let force = arg_matches.is_present("force");
+
+                for entry in db.undo(count, force)?.into_iter().rev() {
+                    println!("Undid: {}", entry.operation);
+                }
+            }
+            ("close-year", Some(arg_matches)) => {
+                let year = value_t_or_exit!(arg_matches, "year", i32);
+
+                db.close_year(year)?;
+                println!(
+                    "Closed tax years: {}",
+                    db.closed_years()
+                        .iter()
+                        .map(|year| year.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
             _ => unreachable!(),
         },
         ("influxdb", Some(db_matches)) => match db_matches.subcommand() {
@@ -5910,7 +8413,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 db.clear_metrics_config()?;
                 println!("Cleared InfluxDb configuration");
             }
-            ("show", Some(_arg_matches)) => match db.get_metrics_config() {
+            ("show", Some(_arg_matches)) => match db.get_metrics_config()? {
                 None => {
                     println!("No InfluxDb configuration");
                 }
@@ -6054,6 +8557,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     None,
                     false,
                     false,
+                    LotSelectionMethod::default(),
                     &notifier,
                 )
                 .await?;
@@ -6136,6 +8640,43 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                 process_account_cost_basis(&db, when).await?;
             }
+            ("unrealized", Some(arg_matches)) => {
+                let when = value_t!(arg_matches, "when", String)
+                    .map(|s| naivedate_of(&s).unwrap())
+                    .unwrap();
+                if let Ok(source) = value_t!(arg_matches, "price_source", PriceSourceKind) {
+                    price_source::set_price_sources(vec![source]);
+                }
+
+                process_account_unrealized(&db, rpc_client, when).await?;
+            }
+            ("harvest", Some(arg_matches)) => {
+                let min_loss = value_t_or_exit!(arg_matches, "min_loss", f64);
+                let token = value_t!(arg_matches, "token", String)
+                    .ok()
+                    .map(|token| MaybeToken::from(Token::from_str(&token).ok()));
+                let into_token = MaybeToken::from(value_t!(arg_matches, "into_token", Token).ok());
+                let signer = match signer_of(arg_matches, "signer", &mut wallet_manager)? {
+                    (Some(signer), Some(address)) => Some((signer, address)),
+                    _ => None,
+                };
+                let confirm = arg_matches.is_present("confirm");
+                let slippage_bps = value_t_or_exit!(arg_matches, "slippage_bps", u64);
+
+                process_account_harvest(
+                    &mut db,
+                    &rpc_clients,
+                    min_loss,
+                    token,
+                    into_token,
+                    signer,
+                    confirm,
+                    slippage_bps,
+                    priority_fee,
+                    &notifier,
+                )
+                .await?;
+            }
             ("xls", Some(arg_matches)) => {
                 let outfile = value_t_or_exit!(arg_matches, "outfile", String);
                 let filter_by_year = value_t!(arg_matches, "year", i32).ok();
@@ -6144,7 +8685,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             ("csv", Some(arg_matches)) => {
                 let outfile = value_t_or_exit!(arg_matches, "outfile", String);
                 let filter_by_year = value_t!(arg_matches, "year", i32).ok();
-                process_account_csv(&db, &outfile, filter_by_year).await?;
+                let format = value_t_or_exit!(arg_matches, "format", CsvFormat);
+                process_account_csv(&db, &outfile, format, filter_by_year).await?;
+            }
+            ("form8949", Some(arg_matches)) => {
+                let outfile = value_t_or_exit!(arg_matches, "outfile", String);
+                let year = value_t_or_exit!(arg_matches, "year", i32);
+                process_account_form_8949(&db, &outfile, year).await?;
             }
             ("remove", Some(arg_matches)) => {
                 let address = pubkey_of(arg_matches, "address").unwrap();
@@ -6240,6 +8787,82 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     println!("(unset)");
                 }
             }
+            ("set-fmv-discount", Some(arg_matches)) => {
+                let fmv_discount_model = if let Ok(discount) = value_t!(arg_matches, "fixed", f64) {
+                    Some(FmvDiscountModel::Fixed { discount })
+                } else if let Ok(annual_discount) = value_t!(arg_matches, "annual", f64) {
+                    Some(FmvDiscountModel::TimeToUnlock {
+                        annual_discount,
+                        max_discount: value_t_or_exit!(arg_matches, "max", f64),
+                    })
+                } else {
+                    None
+                };
+                match fmv_discount_model {
+                    Some(fmv_discount_model) => println!("FMV discount: {fmv_discount_model}"),
+                    None => println!("FMV discount disabled"),
+                }
+                db.set_fmv_discount_model(fmv_discount_model)?;
+            }
+            ("discounted-basis", Some(_arg_matches)) => {
+                process_account_discounted_basis(&db);
+            }
+            ("set-identity", Some(arg_matches)) => {
+                let address = pubkey_of(arg_matches, "address").unwrap();
+                if arg_matches.is_present("disable") {
+                    db.remove_identity_account(address)?;
+                    println!("Block rewards of {address} are no longer tracked");
+                } else {
+                    if db.get_account(address, MaybeToken::SOL()).is_none() {
+                        return Err(format!("SOL account does not exist for {address}").into());
+                    }
+                    let granularity =
+                        value_t_or_exit!(arg_matches, "granularity", BlockRewardGranularity);
+
+                    // Blocks produced since the last sync are still to be recorded
+                    let last_slot = match db
+                        .get_identity_accounts()
+                        .into_iter()
+                        .find(|identity_account| identity_account.address == address)
+                    {
+                        Some(identity_account) => identity_account.last_slot,
+                        None => {
+                            rpc_client.get_slot_with_commitment(CommitmentConfig::finalized())?
+                        }
+                    };
+                    db.set_identity_account(IdentityAccount {
+                        address,
+                        granularity,
+                        last_slot,
+                    })?;
+                    println!(
+                        "Block rewards of {address} are tracked per {} from slot {last_slot}",
+                        <&str>::from(granularity)
+                    );
+                }
+            }
+            ("block-rewards", Some(arg_matches)) => {
+                let year = value_t!(arg_matches, "year", i32).ok();
+                process_account_block_rewards(&db, year);
+            }
+            ("set-tax-jurisdiction", Some(arg_matches)) => {
+                let tax_jurisdiction =
+                    value_t_or_exit!(arg_matches, "tax_jurisdiction", TaxJurisdiction);
+                println!("Tax jurisdiction: {tax_jurisdiction}");
+                db.set_tax_jurisdiction(tax_jurisdiction)?;
+            }
+            ("tax-jurisdiction", Some(_arg_matches)) => {
+                println!("Tax jurisdiction: {}", db.get_tax_jurisdiction());
+            }
+            ("tax-report", Some(arg_matches)) => {
+                let year = value_t_or_exit!(arg_matches, "year", i32);
+                process_account_tax_report(&db, year)?;
+            }
+            ("estimated-tax", Some(arg_matches)) => {
+                let year = value_t!(arg_matches, "year", i32).unwrap_or_else(|_| today().year());
+                let quarter = value_t!(arg_matches, "quarter", usize).ok();
+                process_account_estimated_tax(&db, year, quarter)?;
+            }
             ("merge", Some(arg_matches)) => {
                 let from_address = pubkey_of(arg_matches, "from_address").unwrap();
                 let into_address = pubkey_of(arg_matches, "into_address").unwrap();
@@ -6375,6 +8998,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let force_rescan_balances = arg_matches.is_present("force_rescan_balances");
                 let max_epochs_to_process =
                     value_t!(arg_matches, "max_epochs_to_process", u64).ok();
+                let lot_selection_method =
+                    value_t_or_exit!(arg_matches, "lot_selection", LotSelectionMethod);
                 process_account_sync(
                     &mut db,
                     &rpc_clients,
@@ -6382,6 +9007,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     max_epochs_to_process,
                     reconcile_no_sync_account_balances,
                     force_rescan_balances,
+                    lot_selection_method,
                     &notifier,
                 )
                 .await?;
@@ -6539,7 +9165,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             let exchange_client = || {
                 let exchange_credentials = db
-                    .get_exchange_credentials(exchange, &exchange_account)
+                    .get_exchange_credentials(exchange, &exchange_account)?
                     .ok_or_else(|| format!("No API key set for {exchange:?}"))?;
                 exchange_client_new(exchange, exchange_credentials)
             };
@@ -7026,7 +9652,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 ("api", Some(api_matches)) => {
                     match api_matches.subcommand() {
                         ("show", Some(_arg_matches)) => {
-                            match db.get_exchange_credentials(exchange, &exchange_account) {
+                            match db.get_exchange_credentials(exchange, &exchange_account)? {
                                 Some(ExchangeCredentials {
                                     api_key,
                                     subaccount,
@@ -7075,6 +9701,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         _ => unreachable!(),
     };
 
-    metrics::send(db.get_metrics_config()).await;
+    // Metrics are configured in the credentials database, which is only unlocked when needed
+    if metrics::has_points().await {
+        metrics::send(db.get_metrics_config()?).await;
+    }
     Ok(())
 }
@@ -1,14 +1,266 @@
 use {
-    crate::{exchange::*, token::MaybeToken},
+    crate::{
+        exchange::*,
+        token::{MaybeToken, Token},
+    },
     async_trait::async_trait,
+    chrono::prelude::*,
     futures::{pin_mut, stream::StreamExt},
+    hmac::{Hmac, Mac},
     rust_decimal::prelude::*,
+    serde::{de::DeserializeOwned, Deserialize},
     solana_sdk::pubkey::Pubkey,
-    std::collections::HashMap,
+    std::{
+        collections::HashMap,
+        time::{SystemTime, UNIX_EPOCH},
+    },
 };
 
+const API_URL: &str = "https://api.coinbase.com";
+const ADVANCED_TRADE_PATH: &str = "/api/v3/brokerage";
+
 pub struct CoinbaseExchangeClient {
     client: coinbase_rs::Private,
+    http: reqwest::Client,
+    api_key: String,
+    secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Amount {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AdvancedTradeAccount {
+    currency: String,
+    available_balance: Amount,
+    hold: Amount,
+}
+
+#[derive(Debug, Deserialize)]
+struct AdvancedTradeAccounts {
+    accounts: Vec<AdvancedTradeAccount>,
+    has_next: bool,
+    cursor: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PriceLevel {
+    price: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PriceBook {
+    product_id: String,
+    bids: Vec<PriceLevel>,
+    asks: Vec<PriceLevel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BestBidAsk {
+    pricebooks: Vec<PriceBook>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Candle {
+    start: String,
+    low: String,
+    high: String,
+    volume: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Candles {
+    candles: Vec<Candle>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateOrderSuccess {
+    order_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateOrderFailure {
+    error: Option<String>,
+    message: Option<String>,
+    error_details: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateOrder {
+    success: bool,
+    success_response: Option<CreateOrderSuccess>,
+    error_response: Option<CreateOrderFailure>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CancelOrderResult {
+    success: bool,
+    failure_reason: Option<String>,
+    order_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CancelOrders {
+    results: Vec<CancelOrderResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LimitGtc {
+    base_size: String,
+    limit_price: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OrderConfiguration {
+    limit_limit_gtc: Option<LimitGtc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    order_id: String,
+    product_id: String,
+    side: String,
+    status: String,
+    order_configuration: OrderConfiguration,
+    filled_size: Option<String>,
+    total_fees: Option<String>,
+    last_fill_time: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoricalOrder {
+    order: Order,
+}
+
+#[derive(Debug, Deserialize)]
+struct V2Money {
+    amount: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct V2Network {
+    hash: Option<String>,
+    transaction_fee: Option<V2Money>,
+}
+
+#[derive(Debug, Deserialize)]
+struct V2To {
+    address: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct V2Transaction {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    status: String,
+    amount: V2Money,
+    network: Option<V2Network>,
+    to: Option<V2To>,
+}
+
+#[derive(Debug, Deserialize)]
+struct V2Response<T> {
+    data: T,
+}
+
+fn parse_f64(field: &str, value: &str) -> Result<f64, Box<dyn std::error::Error>> {
+    value
+        .parse::<f64>()
+        .map_err(|err| format!("Invalid `{field}` field, {value}: {err}").into())
+}
+
+fn token_of(currency: &str) -> Option<MaybeToken> {
+    if currency == "SOL" {
+        Some(MaybeToken::SOL())
+    } else {
+        currency.parse::<Token>().ok().map(|token| token.into())
+    }
+}
+
+impl CoinbaseExchangeClient {
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        query: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+
+        // Query parameters are not included in the signature
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(format!("{timestamp}{}{path}{body}", method.as_str()).as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        let url = if query.is_empty() {
+            format!("{API_URL}{path}")
+        } else {
+            format!("{API_URL}{path}?{query}")
+        };
+
+        let response = self
+            .http
+            .request(method, url)
+            .header("CB-ACCESS-KEY", &self.api_key)
+            .header("CB-ACCESS-SIGN", signature)
+            .header("CB-ACCESS-TIMESTAMP", timestamp)
+            .header("CB-VERSION", "2024-01-01")
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(format!("Coinbase request to {path} failed ({status}): {text}").into());
+        }
+        serde_json::from_str(&text)
+            .map_err(|err| format!("Invalid Coinbase response from {path}: {err}: {text}").into())
+    }
+
+    async fn accounts(
+        &self,
+    ) -> Result<Vec<(MaybeToken, coinbase_rs::Uuid)>, Box<dyn std::error::Error>> {
+        let mut result = vec![];
+
+        let accounts = self.client.accounts();
+        pin_mut!(accounts);
+
+        while let Some(account_result) = accounts.next().await {
+            for account in account_result.map_err(|err| format!("{err:?}"))? {
+                if let (Ok(id), Some(token)) = (
+                    coinbase_rs::Uuid::from_str(&account.id),
+                    token_of(&account.currency.code),
+                ) {
+                    if account.primary {
+                        result.push((token, id));
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    async fn account_id(
+        &self,
+        token: MaybeToken,
+    ) -> Result<coinbase_rs::Uuid, Box<dyn std::error::Error>> {
+        self.accounts()
+            .await?
+            .into_iter()
+            .find_map(|(account_token, id)| (account_token == token).then_some(id))
+            .ok_or_else(|| format!("No Coinbase account for {}", token.name()).into())
+    }
 }
 
 #[async_trait]
@@ -58,7 +310,36 @@ impl ExchangeClient for CoinbaseExchangeClient {
     async fn balances(
         &self,
     ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
-        Err("Balances not supported".into())
+        let mut balances = HashMap::new();
+
+        let mut cursor = String::new();
+        loop {
+            let response: AdvancedTradeAccounts = self
+                .request(
+                    reqwest::Method::GET,
+                    &format!("{ADVANCED_TRADE_PATH}/accounts"),
+                    &format!("limit=250&cursor={cursor}"),
+                    None,
+                )
+                .await?;
+
+            for account in response.accounts {
+                if account.currency == "SOL" || USD_COINS.contains(&account.currency.as_str()) {
+                    let available =
+                        parse_f64("available_balance", &account.available_balance.value)?;
+                    let total = available + parse_f64("hold", &account.hold.value)?;
+
+                    balances.insert(account.currency, ExchangeBalance { available, total });
+                }
+            }
+
+            if !response.has_next {
+                break;
+            }
+            cursor = response.cursor;
+        }
+
+        Ok(balances)
     }
 
     async fn recent_deposits(
@@ -69,57 +350,362 @@ impl ExchangeClient for CoinbaseExchangeClient {
     }
 
     async fn recent_withdrawals(&self) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
-        Ok(vec![])
+        let mut withdrawals = vec![];
+
+        for (token, id) in self.accounts().await? {
+            let transactions: V2Response<Vec<V2Transaction>> = self
+                .request(
+                    reqwest::Method::GET,
+                    &format!("/v2/accounts/{id}/transactions"),
+                    "",
+                    None,
+                )
+                .await?;
+
+            for transaction in transactions.data {
+                if transaction.kind != "send" {
+                    continue;
+                }
+
+                let tx_id = transaction.network.as_ref().and_then(|n| n.hash.clone());
+                let (completed, tx_id) = match transaction.status.as_str() {
+                    "completed" => (true, Some(tx_id.expect("transaction id"))),
+                    "canceled" | "failed" | "expired" => (true, None),
+                    _ => (false, None),
+                };
+
+                withdrawals.push(WithdrawalInfo {
+                    address: transaction
+                        .to
+                        .and_then(|to| to.address)
+                        .and_then(|address| address.parse::<Pubkey>().ok())
+                        .unwrap_or_default(),
+                    token,
+                    amount: parse_f64("amount", &transaction.amount.amount)?.abs(),
+                    tag: transaction.id,
+                    completed,
+                    tx_id,
+                });
+            }
+        }
+
+        Ok(withdrawals)
     }
 
     async fn request_withdraw(
         &self,
-        _address: Pubkey,
-        _token: MaybeToken,
-        _amount: f64,
+        address: Pubkey,
+        token: MaybeToken,
+        amount: f64,
         _password: Option<String>,
         _code: Option<String>,
     ) -> Result<(/* withdraw_id: */ String, /*withdraw_fee: */ f64), Box<dyn std::error::Error>>
     {
-        Err("Withdrawals not supported".into())
+        let id = self.account_id(token).await?;
+
+        let idem = coinbase_rs::Uuid::from_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+        );
+
+        let transaction: V2Response<V2Transaction> = self
+            .request(
+                reqwest::Method::POST,
+                &format!("/v2/accounts/{id}/transactions"),
+                "",
+                Some(serde_json::json!({
+                    "type": "send",
+                    "to": address.to_string(),
+                    "amount": amount.to_string(),
+                    "currency": token.name(),
+                    "network": "solana",
+                    "idem": idem.to_string(),
+                })),
+            )
+            .await?;
+
+        let withdraw_fee = match transaction
+            .data
+            .network
+            .and_then(|network| network.transaction_fee)
+        {
+            Some(fee) => parse_f64("transaction_fee", &fee.amount)?,
+            None => 0.,
+        };
+
+        Ok((transaction.data.id, withdraw_fee))
     }
 
     async fn print_market_info(
         &self,
-        _pair: &str,
-        _format: MarketInfoFormat,
+        pair: &str,
+        format: MarketInfoFormat,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        Err("Quotes not supported".into())
+        #[derive(Debug)]
+        struct Hlv {
+            time: DateTime<Utc>,
+            high: f64,
+            low: f64,
+            volume: f64,
+        }
+
+        let end = Utc::now().timestamp();
+        let start = end - 24 * 60 * 60;
+        let candles: Candles = self
+            .request(
+                reqwest::Method::GET,
+                &format!("{ADVANCED_TRADE_PATH}/products/{pair}/candles"),
+                &format!("start={start}&end={end}&granularity=ONE_HOUR"),
+                None,
+            )
+            .await?;
+
+        let hourly_prices = candles
+            .candles
+            .into_iter()
+            .take(24)
+            .filter_map(|candle| {
+                if let (Some(time), Some(high), Some(low), Some(volume)) = (
+                    candle
+                        .start
+                        .parse::<i64>()
+                        .ok()
+                        .and_then(|start| DateTime::from_timestamp(start, 0)),
+                    candle.high.parse::<f64>().ok(),
+                    candle.low.parse::<f64>().ok(),
+                    candle.volume.parse::<f64>().ok(),
+                ) {
+                    Some(Hlv {
+                        time,
+                        high,
+                        low,
+                        volume,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if hourly_prices.len() != 24 {
+            return Err(format!(
+                "Failed to fetch price data for last 24 hours (fetched {} hours)",
+                hourly_prices.len()
+            )
+            .into());
+        }
+
+        let weighted_24h_avg_price = {
+            let mut total_volume = 0.;
+            let mut avg_price_weighted_sum = 0.;
+            for hourly_price in &hourly_prices {
+                let avg_price = (hourly_price.low + hourly_price.high) / 2.;
+
+                total_volume += hourly_price.volume;
+                avg_price_weighted_sum += avg_price * hourly_price.volume;
+            }
+
+            avg_price_weighted_sum / total_volume
+        };
+
+        let bid_ask = self.bid_ask(pair).await?;
+
+        match format {
+            MarketInfoFormat::All => {
+                println!(
+                    "{} | Ask: ${:.2}, Bid: ${:.2}, 24hr Average: ${:.2}",
+                    pair, bid_ask.ask_price, bid_ask.bid_price, weighted_24h_avg_price
+                );
+            }
+            MarketInfoFormat::Ask => {
+                println!("{}", bid_ask.ask_price);
+            }
+            MarketInfoFormat::Hourly => {
+                println!("hour,low,high,average,volume");
+                for p in hourly_prices.iter().rev() {
+                    println!(
+                        "{},{},{},{},{}",
+                        DateTime::<Local>::from(p.time),
+                        p.low,
+                        p.high,
+                        (p.low + p.high) / 2.,
+                        p.volume
+                    );
+                }
+            }
+            MarketInfoFormat::Weighted24hAveragePrice => {
+                println!("{weighted_24h_avg_price:.4}");
+            }
+        }
+
+        Ok(())
     }
 
-    async fn bid_ask(&self, _pair: &str) -> Result<BidAsk, Box<dyn std::error::Error>> {
-        Err("Trading not supported".into())
+    async fn bid_ask(&self, pair: &str) -> Result<BidAsk, Box<dyn std::error::Error>> {
+        let response: BestBidAsk = self
+            .request(
+                reqwest::Method::GET,
+                &format!("{ADVANCED_TRADE_PATH}/best_bid_ask"),
+                &format!("product_ids={pair}"),
+                None,
+            )
+            .await?;
+
+        if let Some(price_book) = response.pricebooks.iter().find(|pb| pb.product_id == pair) {
+            if let (Some(ask_price), Some(bid_price)) = (
+                price_book
+                    .asks
+                    .first()
+                    .and_then(|level| level.price.parse::<f64>().ok()),
+                price_book
+                    .bids
+                    .first()
+                    .and_then(|level| level.price.parse::<f64>().ok()),
+            ) {
+                return Ok(BidAsk {
+                    bid_price,
+                    ask_price,
+                });
+            }
+        }
+        Err("Invalid API response".into())
     }
 
     async fn place_order(
         &self,
-        _pair: &str,
-        _side: OrderSide,
-        _price: f64,
-        _amount: f64,
+        pair: &str,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
     ) -> Result<OrderId, Box<dyn std::error::Error>> {
-        Err("Trading not supported".into())
+        let client_order_id = coinbase_rs::Uuid::from_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+        );
+
+        let response: CreateOrder = self
+            .request(
+                reqwest::Method::POST,
+                &format!("{ADVANCED_TRADE_PATH}/orders"),
+                "",
+                Some(serde_json::json!({
+                    "client_order_id": client_order_id.to_string(),
+                    "product_id": pair,
+                    "side": match side {
+                        OrderSide::Buy => "BUY",
+                        OrderSide::Sell => "SELL",
+                    },
+                    "order_configuration": {
+                        "limit_limit_gtc": {
+                            "base_size": amount.to_string(),
+                            "limit_price": price.to_string(),
+                            "post_only": true,
+                        }
+                    },
+                })),
+            )
+            .await?;
+
+        match response {
+            CreateOrder {
+                success: true,
+                success_response: Some(CreateOrderSuccess { order_id }),
+                ..
+            } => Ok(order_id),
+            CreateOrder {
+                error_response: Some(failure),
+                ..
+            } => Err(format!(
+                "Order rejected: {} {} {}",
+                failure.error.unwrap_or_default(),
+                failure.message.unwrap_or_default(),
+                failure.error_details.unwrap_or_default()
+            )
+            .into()),
+            _ => Err("Invalid API response".into()),
+        }
     }
 
     async fn cancel_order(
         &self,
         _pair: &str,
-        _order_id: &OrderId,
+        order_id: &OrderId,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        Err("Trading not supported".into())
+        let response: CancelOrders = self
+            .request(
+                reqwest::Method::POST,
+                &format!("{ADVANCED_TRADE_PATH}/orders/batch_cancel"),
+                "",
+                Some(serde_json::json!({ "order_ids": [order_id] })),
+            )
+            .await?;
+
+        match response.results.iter().find(|r| r.order_id == *order_id) {
+            Some(result) if result.success => Ok(()),
+            Some(result) => Err(format!(
+                "Failed to cancel order {order_id}: {}",
+                result.failure_reason.clone().unwrap_or_default()
+            )
+            .into()),
+            None => Err(format!("Unknown order id: {order_id}").into()),
+        }
     }
 
     async fn order_status(
         &self,
-        _pair: &str,
-        _order_id: &OrderId,
+        pair: &str,
+        order_id: &OrderId,
     ) -> Result<OrderStatus, Box<dyn std::error::Error>> {
-        Err("Trading not supported".into())
+        let HistoricalOrder { order } = self
+            .request(
+                reqwest::Method::GET,
+                &format!("{ADVANCED_TRADE_PATH}/orders/historical/{order_id}"),
+                "",
+                None,
+            )
+            .await?;
+
+        assert_eq!(order.order_id, *order_id);
+        assert_eq!(order.product_id, pair);
+
+        let limit = order
+            .order_configuration
+            .limit_limit_gtc
+            .ok_or_else(|| format!("Order {order_id} is not a GTC limit order"))?;
+
+        let fee = {
+            let fee =
+                parse_f64("total_fees", &order.total_fees.unwrap_or_default()).unwrap_or_default();
+            if fee > f64::EPSILON {
+                Some((fee, "USD".to_string()))
+            } else {
+                None
+            }
+        };
+
+        let last_update = order
+            .last_fill_time
+            .map(|last_fill_time| last_fill_time.with_timezone(&Local).date_naive())
+            .unwrap_or_else(|| Local::now().date_naive());
+
+        Ok(OrderStatus {
+            open: ["OPEN", "PENDING", "QUEUED", "CANCEL_QUEUED"].contains(&order.status.as_str()),
+            side: match order.side.as_str() {
+                "SELL" => OrderSide::Sell,
+                "BUY" => OrderSide::Buy,
+                side => panic!("Invalid order side: {side}"),
+            },
+            price: parse_f64("limit_price", &limit.limit_price)?,
+            amount: parse_f64("base_size", &limit.base_size)?,
+            filled_amount: parse_f64("filled_size", &order.filled_size.unwrap_or_default())
+                .unwrap_or_default(),
+            last_update,
+            fee,
+        })
     }
 
     async fn get_lending_info(
@@ -145,7 +731,7 @@ impl ExchangeClient for CoinbaseExchangeClient {
     }
 
     fn preferred_solusd_pair(&self) -> &'static str {
-        "SOLUSD"
+        "SOL-USD"
     }
 }
 
@@ -159,5 +745,8 @@ pub fn new(
     assert!(subaccount.is_none());
     Ok(CoinbaseExchangeClient {
         client: coinbase_rs::Private::new(coinbase_rs::MAIN_URL, &api_key, &secret),
+        http: reqwest::Client::builder().user_agent("sys").build()?,
+        api_key,
+        secret,
     })
 }
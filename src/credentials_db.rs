@@ -0,0 +1,313 @@
+use {
+    crate::db::{DbError, DbResult},
+    aes_gcm_siv::{
+        aead::{Aead, KeyInit},
+        Aes256GcmSiv, Nonce,
+    },
+    base64::{engine::general_purpose::STANDARD as BASE64, Engine},
+    hmac::Hmac,
+    pickledb::{PickleDb, PickleDbDumpPolicy},
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    sha2::Sha256,
+    std::{
+        cell::OnceCell,
+        collections::BTreeMap,
+        env, fs,
+        path::{Path, PathBuf},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+pub const PASSPHRASE_ENV_VAR: &str = "SYS_DB_PASSPHRASE";
+pub const KEY_FILE_ENV_VAR: &str = "SYS_DB_KEY_FILE";
+
+const KDF: &str = "pbkdf2-sha256";
+const KDF_ITERATIONS: u32 = 600_000;
+
+/// Secret material used to encrypt the credentials database, either a passphrase or the raw
+/// contents of a key file
+#[derive(Clone)]
+pub struct CredentialsKey(Vec<u8>);
+
+impl CredentialsKey {
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        Self(passphrase.as_bytes().to_vec())
+    }
+
+    pub fn from_key_file<P: AsRef<Path>>(key_file: P) -> DbResult<Self> {
+        let key_file = key_file.as_ref();
+        let bytes = fs::read(key_file)?;
+        if bytes.is_empty() {
+            return Err(DbError::Credentials(format!(
+                "Key file is empty: {}",
+                key_file.display()
+            )));
+        }
+        Ok(Self(bytes))
+    }
+
+    /// Key from the `SYS_DB_PASSPHRASE` or `SYS_DB_KEY_FILE` environment variables, if set
+    pub fn from_env() -> DbResult<Option<Self>> {
+        if let Ok(passphrase) = env::var(PASSPHRASE_ENV_VAR) {
+            return Ok(Some(Self::from_passphrase(&passphrase)));
+        }
+        if let Ok(key_file) = env::var(KEY_FILE_ENV_VAR) {
+            return Self::from_key_file(key_file).map(Some);
+        }
+        Ok(None)
+    }
+
+    pub fn prompt(prompt: &str) -> DbResult<Self> {
+        let passphrase = rpassword::prompt_password(prompt)?;
+        if passphrase.is_empty() {
+            return Err(DbError::Credentials("Empty passphrase".into()));
+        }
+        Ok(Self::from_passphrase(&passphrase))
+    }
+
+    pub fn prompt_new() -> DbResult<Self> {
+        let passphrase = rpassword::prompt_password("New credentials passphrase: ")?;
+        if passphrase.is_empty() {
+            return Err(DbError::Credentials("Empty passphrase".into()));
+        }
+        if passphrase != rpassword::prompt_password("Confirm new credentials passphrase: ")? {
+            return Err(DbError::Credentials("Passphrases do not match".into()));
+        }
+        Ok(Self::from_passphrase(&passphrase))
+    }
+
+    fn derive(&self, salt: &[u8], iterations: u32) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(&self.0, salt, iterations, &mut key);
+        key
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedCredentials {
+    kdf: String,
+    iterations: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+struct Unlocked {
+    key: Option<CredentialsKey>,
+    entries: BTreeMap<String, serde_json::Value>,
+}
+
+/// Key/value store for exchange API keys and other secrets, encrypted at rest once a
+/// `CredentialsKey` is available.
+///
+/// An encrypted database is only decrypted on first access, so commands that don't need
+/// credentials never ask for the passphrase.
+///
+/// Plaintext databases written by earlier versions (a PickleDb JSON file) are read as-is and
+/// re-written encrypted the first time they are opened with a key.
+pub struct CredentialsDb {
+    filename: PathBuf,
+    // Key to decrypt `encrypted` with, the passphrase is prompted for otherwise
+    key: Option<CredentialsKey>,
+    encrypted: Option<EncryptedCredentials>,
+    unlocked: OnceCell<Unlocked>,
+}
+
+impl CredentialsDb {
+    pub fn load(filename: PathBuf, key: Option<CredentialsKey>) -> DbResult<Self> {
+        if !filename.exists() {
+            return Ok(Self {
+                filename,
+                key: None,
+                encrypted: None,
+                unlocked: OnceCell::from(Unlocked {
+                    key,
+                    entries: BTreeMap::default(),
+                }),
+            });
+        }
+
+        let contents = fs::read_to_string(&filename)?;
+        match serde_json::from_str::<EncryptedCredentials>(&contents) {
+            Ok(encrypted) => Ok(Self {
+                filename,
+                key,
+                encrypted: Some(encrypted),
+                unlocked: OnceCell::new(),
+            }),
+            Err(_) => {
+                let legacy_db = PickleDb::load_json(&filename, PickleDbDumpPolicy::NeverDump)?;
+                let entries = legacy_db
+                    .get_all()
+                    .into_iter()
+                    .filter_map(|k| legacy_db.get::<serde_json::Value>(&k).map(|v| (k, v)))
+                    .collect();
+
+                let encrypt = key.is_some();
+                let not_empty = !entries.is_empty();
+                let credentials_db = Self {
+                    filename,
+                    key: None,
+                    encrypted: None,
+                    unlocked: OnceCell::from(Unlocked { key, entries }),
+                };
+                if encrypt {
+                    credentials_db.dump()?;
+                    eprintln!("Encrypted {}", credentials_db.filename.display());
+                } else if not_empty {
+                    eprintln!(
+                        "Warning: {} is not encrypted. Set {PASSPHRASE_ENV_VAR} or {KEY_FILE_ENV_VAR}, \
+                         or run `sys db rekey`",
+                        credentials_db.filename.display()
+                    );
+                }
+                Ok(credentials_db)
+            }
+        }
+    }
+
+    fn unlocked(&self) -> DbResult<&Unlocked> {
+        if let Some(unlocked) = self.unlocked.get() {
+            return Ok(unlocked);
+        }
+
+        let encrypted = self
+            .encrypted
+            .as_ref()
+            .expect("unencrypted databases are unlocked when loaded");
+        let key = match &self.key {
+            Some(key) => key.clone(),
+            None => {
+                CredentialsKey::prompt(&format!("Passphrase for {}: ", self.filename.display()))?
+            }
+        };
+        let entries = Self::decrypt(encrypted, &key)?;
+        Ok(self.unlocked.get_or_init(|| Unlocked {
+            key: Some(key),
+            entries,
+        }))
+    }
+
+    fn unlocked_mut(&mut self) -> DbResult<&mut Unlocked> {
+        self.unlocked()?;
+        Ok(self.unlocked.get_mut().expect("unlocked"))
+    }
+
+    fn decrypt(
+        encrypted: &EncryptedCredentials,
+        key: &CredentialsKey,
+    ) -> DbResult<BTreeMap<String, serde_json::Value>> {
+        if encrypted.kdf != KDF {
+            return Err(DbError::Credentials(format!(
+                "Unsupported key derivation function: {}",
+                encrypted.kdf
+            )));
+        }
+        let decode = |field: &str| {
+            BASE64
+                .decode(field)
+                .map_err(|err| DbError::Credentials(format!("Invalid encoding: {err}")))
+        };
+        let salt = decode(&encrypted.salt)?;
+        let nonce = decode(&encrypted.nonce)?;
+        let ciphertext = decode(&encrypted.ciphertext)?;
+        if nonce.len() != 12 {
+            return Err(DbError::Credentials("Invalid nonce".into()));
+        }
+
+        let cipher = Aes256GcmSiv::new_from_slice(&key.derive(&salt, encrypted.iterations))
+            .expect("valid key length");
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| DbError::Credentials("Incorrect passphrase or key file".into()))?;
+
+        serde_json::from_slice(&plaintext)
+            .map_err(|err| DbError::Credentials(format!("JSON parse failed: {err}")))
+    }
+
+    pub fn dump(&self) -> DbResult<()> {
+        let Unlocked { key, entries } = self.unlocked()?;
+        let plaintext = serde_json::to_vec(entries)
+            .map_err(|err| DbError::Credentials(format!("JSON serialize failed: {err}")))?;
+
+        let contents = match key {
+            None => {
+                // No key available, preserve the legacy plaintext format
+                let mut legacy_db =
+                    PickleDb::new_json(&self.filename, PickleDbDumpPolicy::DumpUponRequest);
+                for (k, v) in entries {
+                    legacy_db.set(k, v)?;
+                }
+                return Ok(legacy_db.dump()?);
+            }
+            Some(key) => {
+                let salt = rand::random::<[u8; 16]>();
+                let nonce = rand::random::<[u8; 12]>();
+
+                let cipher = Aes256GcmSiv::new_from_slice(&key.derive(&salt, KDF_ITERATIONS))
+                    .expect("valid key length");
+                let ciphertext = cipher
+                    .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
+                    .map_err(|_| DbError::Credentials("Encryption failed".into()))?;
+
+                serde_json::to_string_pretty(&EncryptedCredentials {
+                    kdf: KDF.into(),
+                    iterations: KDF_ITERATIONS,
+                    salt: BASE64.encode(salt),
+                    nonce: BASE64.encode(nonce),
+                    ciphertext: BASE64.encode(ciphertext),
+                })
+                .map_err(|err| DbError::Credentials(format!("JSON serialize failed: {err}")))?
+            }
+        };
+
+        let temp_filename = format!(
+            "{}.temp.{}",
+            self.filename.display(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        );
+        fs::write(&temp_filename, contents)?;
+        fs::rename(temp_filename, &self.filename)?;
+        Ok(())
+    }
+
+    /// Re-encrypt the database with `new_key`, encrypting it for the first time if it was
+    /// previously stored in plaintext
+    pub fn rekey(&mut self, new_key: CredentialsKey) -> DbResult<()> {
+        self.unlocked_mut()?.key = Some(new_key);
+        self.dump()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        match self.unlocked.get() {
+            Some(unlocked) => unlocked.key.is_some(),
+            None => self.encrypted.is_some(),
+        }
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> DbResult<Option<T>> {
+        Ok(self
+            .unlocked()?
+            .entries
+            .get(key)
+            .and_then(|value| serde_json::from_value(value.clone()).ok()))
+    }
+
+    pub fn set<T: Serialize>(&mut self, key: &str, value: &T) -> DbResult<()> {
+        let value = serde_json::to_value(value)
+            .map_err(|err| DbError::Credentials(format!("JSON serialize failed: {err}")))?;
+        self.unlocked_mut()?.entries.insert(key.into(), value);
+        Ok(())
+    }
+
+    pub fn rem(&mut self, key: &str) -> DbResult<bool> {
+        Ok(self.unlocked_mut()?.entries.remove(key).is_some())
+    }
+
+    pub fn get_all(&self) -> DbResult<Vec<String>> {
+        Ok(self.unlocked()?.entries.keys().cloned().collect())
+    }
+}
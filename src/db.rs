@@ -1,5 +1,14 @@
 use {
-    crate::{exchange::*, field_as_string, metrics::MetricsConfig, token::*},
+    crate::{
+        credentials_db::{CredentialsDb, CredentialsKey},
+        exchange::*,
+        field_as_string,
+        journal::{Journal, JournalEntry, JournalOperation},
+        metrics::MetricsConfig,
+        price_source::PriceSourceKind,
+        tax_jurisdiction::TaxJurisdiction,
+        token::*,
+    },
     chrono::{prelude::*, NaiveDate},
     pickledb::{PickleDb, PickleDbDumpPolicy},
     rust_decimal::prelude::*,
@@ -11,7 +20,7 @@ use {
         signature::Signature,
     },
     std::{
-        collections::HashSet,
+        collections::{BTreeMap, BTreeSet, HashMap, HashSet},
         fmt, fs, io,
         path::{Path, PathBuf},
         time::{SystemTime, UNIX_EPOCH},
@@ -57,11 +66,32 @@ pub enum DbError {
 
     #[error("Import failed: {0}")]
     ImportFailed(String),
+
+    #[error("Tax rate not set, run `sys account set-tax-rate` first")]
+    TaxRateNotSet,
+
+    #[error("Tax-optimal lot selection assumes US tax rules, not {0}")]
+    TaxOptimalNotSupported(TaxJurisdiction),
+
+    #[error("Credentials: {0}")]
+    Credentials(String),
+
+    #[error("Database version {0} is newer than the supported version {1}, upgrade sys")]
+    UnsupportedVersion(u32, u32),
+
+    #[error("Database migration from version {0} failed: {1}")]
+    MigrationFailed(u32, String),
+
+    #[error("Undo failed: {0}")]
+    UndoFailed(String),
+
+    #[error("Tax year {0} is closed, use --override-closed-years to modify it anyway")]
+    ClosedYear(i32),
 }
 
 pub type DbResult<T> = std::result::Result<T, DbError>;
 
-pub fn new<P: AsRef<Path>>(db_path: P) -> DbResult<Db> {
+pub fn new<P: AsRef<Path>>(db_path: P, credentials_key: Option<CredentialsKey>) -> DbResult<Db> {
     let db_path = db_path.as_ref();
     if !db_path.exists() {
         fs::create_dir_all(db_path)?;
@@ -71,34 +101,49 @@ pub fn new<P: AsRef<Path>>(db_path: P) -> DbResult<Db> {
     let credentials_db_filename = db_path.join("🤐.db");
     let data_filename = db_path.join("data.json");
 
-    let credentials_db = if credentials_db_filename.exists() {
-        PickleDb::load_json(credentials_db_filename, PickleDbDumpPolicy::DumpUponRequest)?
-    } else {
-        PickleDb::new_json(credentials_db_filename, PickleDbDumpPolicy::DumpUponRequest)
-    };
+    let credentials_db = CredentialsDb::load(credentials_db_filename, credentials_key)?;
 
     let data = if data_filename.exists() {
         DbData::load(&data_filename)?
     } else if legacy_db_filename.exists() {
         let db = PickleDb::load_json(&legacy_db_filename, PickleDbDumpPolicy::NeverDump)?;
-        DbData::import_legacy_db(&db)
+        DbData::migrate(DbData::import_legacy_db(&db))?.0
+    } else {
+        DbData {
+            version: DB_DATA_VERSION,
+            ..DbData::default()
+        }
+    };
+
+    // A database that hasn't been written to `data_filename` yet is treated as empty, so that
+    // the first save always writes it
+    let persisted_data = if data_filename.exists() {
+        data.clone()
     } else {
         DbData::default()
     };
 
     Ok(Db {
+        persisted_data,
         data,
         data_filename,
         credentials_db,
+        journal: Journal::open(db_path)?,
+        operation: None,
         auto_save: true,
+        override_closed_years: false,
     })
 }
 
 pub struct Db {
-    credentials_db: PickleDb,
+    credentials_db: CredentialsDb,
     data: DbData,
     data_filename: PathBuf,
+    journal: Journal,
+    persisted_data: DbData, // `data` as of the last write to `data_filename`
+    operation: Option<JournalOperation>, // Journal operation for the next write
     auto_save: bool,
+    override_closed_years: bool,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -155,6 +200,8 @@ pub struct PendingSwap {
 
     pub to_token: MaybeToken,
     pub to_token_price: Decimal,
+    #[serde(default)]
+    pub to_token_price_source: Option<PriceSourceKind>,
 
     pub lot_selection_method: LotSelectionMethod,
     pub lot_numbers: Option<HashSet<usize>>,
@@ -193,6 +240,8 @@ pub enum LotAcquistionKind {
         exchange: Exchange,
         pair: String,
         order_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fee: Option<(f64, String)>, // already included in the lot price
     },
     NotAvailable, // Generic acquisition subject to income tax
     Fiat,         // Generic acquisition with post-tax fiat
@@ -202,6 +251,16 @@ pub enum LotAcquistionKind {
         token: MaybeToken,
         amount: Option<u64>,
     },
+    BlockReward {
+        slot: Slot, // first block of the lot
+        reward_type: BlockRewardType,
+        blocks: usize,
+    },
+    MevReward {
+        epoch: Epoch, // epoch the tips were earned in
+        #[serde(with = "field_as_string")]
+        signature: Signature, // tip distribution claim
+    },
 }
 
 impl fmt::Display for LotAcquistionKind {
@@ -210,12 +269,37 @@ impl fmt::Display for LotAcquistionKind {
             LotAcquistionKind::EpochReward { epoch, slot } => {
                 write!(f, "epoch {epoch} reward (slot {slot})")
             }
+            LotAcquistionKind::MevReward { epoch, signature } => {
+                write!(f, "epoch {epoch} MEV tips, {signature}")
+            }
+            LotAcquistionKind::BlockReward {
+                slot,
+                reward_type,
+                blocks,
+            } => {
+                if *blocks > 1 {
+                    write!(f, "{reward_type} of {blocks} blocks (from slot {slot})")
+                } else {
+                    write!(f, "{reward_type} of block {slot}")
+                }
+            }
             LotAcquistionKind::Transaction { signature, .. } => write!(f, "{signature}"),
             LotAcquistionKind::Exchange {
                 exchange,
                 pair,
                 order_id,
-            } => write!(f, "{exchange:?} {pair}, order {order_id}"),
+                fee,
+            } => write!(
+                f,
+                "{:?} {}, order {}{}",
+                exchange,
+                pair,
+                order_id,
+                match fee {
+                    Some((amount, coin)) if *amount > 0. => format!(" (fee: {amount} {coin})"),
+                    _ => "".into(),
+                }
+            ),
             LotAcquistionKind::Fiat => {
                 write!(f, "post tax")
             }
@@ -252,24 +336,155 @@ impl fmt::Display for LotAcquistionKind {
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct LotAcquistion {
     pub when: NaiveDate,
-    price: Option<f64>,             // USD per SOL/token
-    decimal_price: Option<Decimal>, // Prefer over `price` if Some(_)
+    decimal_price: Decimal, // USD per SOL/token
     pub kind: LotAcquistionKind,
+
+    // Where `decimal_price` was obtained from, `None` if not fetched from a price source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price_source: Option<PriceSourceKind>,
+
+    // Set if `decimal_price` was discounted because the tokens were acquired locked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fmv_discount: Option<LotFmvDiscount>,
+}
+
+/// Fair market value discount applied to a lot acquired into a stake account with an active
+/// lockup
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct LotFmvDiscount {
+    pub undiscounted_price: Decimal,
+    pub discount: f64, // Fraction of `undiscounted_price`, 0 to 1
+    pub unlock_date: NaiveDate,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum FmvDiscountModel {
+    // The same discount regardless of how long the tokens remain locked
+    Fixed {
+        discount: f64,
+    },
+    // Discount `annual_discount` for each year until the tokens unlock, up to `max_discount`
+    TimeToUnlock {
+        annual_discount: f64,
+        max_discount: f64,
+    },
+}
+
+impl FmvDiscountModel {
+    pub fn discount(&self, when: NaiveDate, unlock_date: NaiveDate) -> f64 {
+        if unlock_date <= when {
+            return 0.;
+        }
+        match self {
+            FmvDiscountModel::Fixed { discount } => *discount,
+            FmvDiscountModel::TimeToUnlock {
+                annual_discount,
+                max_discount,
+            } => {
+                let years_locked = (unlock_date - when).num_days() as f64 / 365.;
+                (annual_discount * years_locked).min(*max_discount)
+            }
+        }
+    }
+}
+
+impl fmt::Display for FmvDiscountModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FmvDiscountModel::Fixed { discount } => write!(f, "fixed {:.2}%", discount * 100.),
+            FmvDiscountModel::TimeToUnlock {
+                annual_discount,
+                max_discount,
+            } => write!(
+                f,
+                "{:.2}% per year until unlocked, up to {:.2}%",
+                annual_discount * 100.,
+                max_discount * 100.
+            ),
+        }
+    }
+}
+
+/// The part of a block's fee reward that a lot was acquired from
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum BlockRewardType {
+    Fee,         // the leader's share of the base transaction fees
+    PriorityFee, // the priority fees paid to the leader
+}
+
+impl fmt::Display for BlockRewardType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockRewardType::Fee => write!(f, "fee reward"),
+            BlockRewardType::PriorityFee => write!(f, "priority fee reward"),
+        }
+    }
+}
+
+#[derive(
+    Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, EnumString, IntoStaticStr, Default,
+)]
+pub enum BlockRewardGranularity {
+    #[strum(serialize = "block")]
+    #[default]
+    Block,
+    #[strum(serialize = "day")]
+    Day,
+}
+
+pub const POSSIBLE_BLOCK_REWARD_GRANULARITY_VALUES: &[&str] = &["block", "day"];
+
+/// A validator identity account whose block rewards are tracked block by block
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct IdentityAccount {
+    #[serde(with = "field_as_string")]
+    pub address: Pubkey,
+    pub granularity: BlockRewardGranularity, // one lot per block or one lot per day
+    pub last_slot: Slot,                     // block rewards are tracked up to this slot
 }
 
 impl LotAcquistion {
-    pub fn new(when: NaiveDate, decimal_price: Decimal, kind: LotAcquistionKind) -> Self {
+    pub fn new(
+        when: NaiveDate,
+        decimal_price: Decimal,
+        kind: LotAcquistionKind,
+        price_source: Option<PriceSourceKind>,
+    ) -> Self {
         Self {
             when,
-            price: None,
-            decimal_price: Some(decimal_price),
+            decimal_price,
             kind,
+            price_source,
+            fmv_discount: None,
         }
     }
 
     pub fn price(&self) -> Decimal {
         self.decimal_price
-            .unwrap_or_else(|| Decimal::from_f64(self.price.unwrap_or_default()).unwrap())
+    }
+
+    /// The price before any FMV discount
+    pub fn undiscounted_price(&self) -> Decimal {
+        self.fmv_discount
+            .as_ref()
+            .map(|fmv_discount| fmv_discount.undiscounted_price)
+            .unwrap_or(self.decimal_price)
+    }
+
+    /// Discount the price of tokens that remain locked until `unlock_date`
+    pub fn apply_fmv_discount(&mut self, model: &FmvDiscountModel, unlock_date: NaiveDate) {
+        let discount = model.discount(self.when, unlock_date);
+        if discount <= 0. {
+            return;
+        }
+        let undiscounted_price = self.undiscounted_price();
+        self.decimal_price =
+            undiscounted_price * (Decimal::ONE - Decimal::from_f64(discount).unwrap());
+        self.fmv_discount = Some(LotFmvDiscount {
+            undiscounted_price,
+            discount,
+            unlock_date,
+        });
     }
 }
 
@@ -286,6 +501,10 @@ pub enum LotSelectionMethod {
     LowestBasis,
     #[strum(serialize = "highest-basis")]
     HighestBasis,
+    // Minimize the estimated tax of a disposal. Behaves like `HighestBasis` when the disposal
+    // price or tax rate is unknown
+    #[strum(serialize = "tax-optimal")]
+    TaxOptimal,
 }
 
 impl LotSelectionMethod {
@@ -294,13 +513,48 @@ impl LotSelectionMethod {
             LotSelectionMethod::FirstInFirstOut => a.acquisition.when.cmp(&b.acquisition.when),
             LotSelectionMethod::LastInFirstOut => b.acquisition.when.cmp(&a.acquisition.when),
             LotSelectionMethod::LowestBasis => a.acquisition.price().cmp(&b.acquisition.price()),
-            LotSelectionMethod::HighestBasis => b.acquisition.price().cmp(&a.acquisition.price()),
+            LotSelectionMethod::HighestBasis | LotSelectionMethod::TaxOptimal => {
+                b.acquisition.price().cmp(&a.acquisition.price())
+            }
         }
     }
 }
 
-pub const POSSIBLE_LOT_SELECTION_METHOD_VALUES: &[&str] =
-    &["fifo", "lifo", "lowest-basis", "highest-basis"];
+pub const POSSIBLE_LOT_SELECTION_METHOD_VALUES: &[&str] = &[
+    "fifo",
+    "lifo",
+    "lowest-basis",
+    "highest-basis",
+    "tax-optimal",
+];
+
+pub fn is_long_term_holding(acquisition: NaiveDate, disposal: NaiveDate) -> bool {
+    disposal - acquisition >= chrono::Duration::try_days(365).unwrap()
+}
+
+/// The price and date of a prospective disposal, which `LotSelectionMethod::TaxOptimal` needs
+/// to estimate the tax of disposing each lot
+#[derive(Debug, Clone, Copy)]
+pub struct LotDisposalEstimate {
+    pub price: Decimal,
+    pub when: NaiveDate,
+}
+
+impl LotDisposalEstimate {
+    // Lots are preferred by category: long-term losses, short-term losses, long-term gains and
+    // finally short-term gains. Within a category the lowest estimated tax per token wins
+    fn tax_optimal_key(&self, lot: &Lot, tax_rate: &TaxRate) -> (u8, Decimal) {
+        let gain = self.price - lot.acquisition.price();
+        let long_term = is_long_term_holding(lot.acquisition.when, self.when);
+        let (category, rate) = match (gain.is_sign_negative() && !gain.is_zero(), long_term) {
+            (true, true) => (0, tax_rate.long_term_gain),
+            (true, false) => (1, tax_rate.short_term_gain),
+            (false, true) => (2, tax_rate.long_term_gain),
+            (false, false) => (3, tax_rate.short_term_gain),
+        };
+        (category, gain * Decimal::from_f64(rate).unwrap_or_default())
+    }
+}
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Lot {
@@ -320,9 +574,10 @@ impl Lot {
     pub fn income(&self, token: MaybeToken) -> f64 {
         match self.acquisition.kind {
             // These lots were acquired pre-tax
-            LotAcquistionKind::EpochReward { .. } | LotAcquistionKind::NotAvailable => {
-                self.basis(token)
-            }
+            LotAcquistionKind::EpochReward { .. }
+            | LotAcquistionKind::BlockReward { .. }
+            | LotAcquistionKind::MevReward { .. }
+            | LotAcquistionKind::NotAvailable => self.basis(token),
             // Assume these kinds of lots are acquired post-tax
             LotAcquistionKind::Exchange { .. }
             | LotAcquistionKind::Fiat
@@ -339,6 +594,20 @@ impl Lot {
     }
 }
 
+fn sort_lots_for_disposal(
+    lots: &mut Vec<Lot>,
+    lot_selection_method: LotSelectionMethod,
+    disposal: Option<LotDisposalEstimate>,
+    tax_rate: Option<&TaxRate>,
+) {
+    match (lot_selection_method, disposal, tax_rate) {
+        (LotSelectionMethod::TaxOptimal, Some(disposal), Some(tax_rate)) => {
+            lots.sort_by_cached_key(|lot| disposal.tax_optimal_key(lot, tax_rate))
+        }
+        _ => sort_lots_by_selection_method(lots, lot_selection_method),
+    }
+}
+
 pub fn sort_lots_by_selection_method(
     lots: &mut Vec<Lot>,
     lot_selection_method: LotSelectionMethod,
@@ -373,6 +642,10 @@ pub enum LotDisposalKind {
         exchange: Exchange,
         tag: String,
     },
+    TransactionFee {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+    },
 }
 
 impl LotDisposalKind {
@@ -382,6 +655,7 @@ impl LotDisposalKind {
             LotDisposalKind::Other { .. }
             | LotDisposalKind::Swap { .. }
             | LotDisposalKind::WithdrawalFee { .. }
+            | LotDisposalKind::TransactionFee { .. }
             | LotDisposalKind::Fiat => None,
         }
     }
@@ -410,6 +684,9 @@ impl fmt::Display for LotDisposalKind {
             LotDisposalKind::WithdrawalFee { exchange, tag } => {
                 write!(f, "{exchange} withdrawal fee [{tag}])")
             }
+            LotDisposalKind::TransactionFee { signature } => {
+                write!(f, "transaction fee, {signature}")
+            }
             LotDisposalKind::Swap {
                 token,
                 amount,
@@ -438,17 +715,50 @@ impl fmt::Display for LotDisposalKind {
 pub struct DisposedLot {
     pub lot: Lot,
     pub when: NaiveDate,
-    price: Option<f64>,             // USD per SOL/token
-    decimal_price: Option<Decimal>, // Prefer over `price` if Some(_)
+    decimal_price: Decimal, // USD per SOL/token
     pub kind: LotDisposalKind,
     #[serde(default = "MaybeToken::SOL")]
     pub token: MaybeToken,
 }
 
 impl DisposedLot {
+    /// Proceeds per SOL/token, net of any exchange trading fee that can be valued in USD
     pub fn price(&self) -> Decimal {
+        match self
+            .kind
+            .fee()
+            .and_then(|fee| exchange_fee_in_usd(fee, self.token, self.decimal_price))
+        {
+            Some(fee) if self.lot.amount > 0 => {
+                self.decimal_price
+                    - fee / Decimal::from_f64(self.token.ui_amount(self.lot.amount)).unwrap()
+            }
+            _ => self.decimal_price,
+        }
+    }
+
+    /// Sale price per SOL/token, before any exchange trading fee
+    pub fn gross_price(&self) -> Decimal {
         self.decimal_price
-            .unwrap_or_else(|| Decimal::from_f64(self.price.unwrap_or_default()).unwrap())
+    }
+}
+
+const USD_FEE_COINS: &[&str] = &["USD", "USDC", "USDT", "ZUSD"];
+
+/// USD value of an exchange trading fee of `token` traded at `price`, or `None` if the fee was
+/// charged in neither `token` nor USD (or a USD stablecoin) and cannot be valued
+pub fn exchange_fee_in_usd(
+    (amount, coin): &(f64, String),
+    token: MaybeToken,
+    price: Decimal,
+) -> Option<Decimal> {
+    let amount = Decimal::from_f64(*amount)?;
+    if coin == token.name() || (token.is_sol_or_wsol() && coin == "SOL") {
+        Some(amount * price)
+    } else if USD_FEE_COINS.contains(&coin.as_str()) {
+        Some(amount)
+    } else {
+        None
     }
 }
 
@@ -460,22 +770,29 @@ pub struct TrackedAccount {
     pub token: MaybeToken, // if token then `address` is the token owner
     pub description: String,
     pub last_update_epoch: Epoch,
+    // The epoch through which unexpected balance changes were looked for, which lags
+    // `last_update_epoch` when a sync is interrupted after processing epoch rewards
+    #[serde(default)]
+    pub last_balance_scan_epoch: Option<Epoch>,
     pub last_update_balance: u64, // lamports/tokens
     pub lots: Vec<Lot>,
     pub no_sync: Option<bool>,
 }
 
-fn split_lots(
-    db: &mut Db,
+/// Select `amount` from `lots`, returning the selected and remaining lots. When a lot must be
+/// split, both halves keep the original lot number
+pub fn select_lots(
     mut lots: Vec<Lot>,
     amount: u64,
     lot_selection_method: LotSelectionMethod,
     lot_numbers: Option<HashSet<usize>>,
+    disposal: Option<LotDisposalEstimate>,
+    tax_rate: Option<&TaxRate>,
 ) -> (Vec<Lot>, Vec<Lot>) {
     let mut extracted_lots = vec![];
     let mut remaining_lots = vec![];
 
-    sort_lots_by_selection_method(&mut lots, lot_selection_method);
+    sort_lots_for_disposal(&mut lots, lot_selection_method, disposal, tax_rate);
 
     let mut amount_remaining = amount;
     for mut lot in lots {
@@ -492,7 +809,7 @@ fn split_lots(
                 extracted_lots.push(lot);
             } else {
                 let split_lot = Lot {
-                    lot_number: db.next_lot_number(),
+                    lot_number: lot.lot_number,
                     acquisition: lot.acquisition.clone(),
                     amount: amount_remaining,
                 };
@@ -515,7 +832,43 @@ fn split_lots(
     (extracted_lots, remaining_lots)
 }
 
+fn split_lots(
+    db: &mut Db,
+    lots: Vec<Lot>,
+    amount: u64,
+    lot_selection_method: LotSelectionMethod,
+    lot_numbers: Option<HashSet<usize>>,
+    disposal: Option<LotDisposalEstimate>,
+) -> (Vec<Lot>, Vec<Lot>) {
+    let tax_rate = db.get_tax_rate().cloned();
+    let (mut extracted_lots, remaining_lots) = select_lots(
+        lots,
+        amount,
+        lot_selection_method,
+        lot_numbers,
+        disposal,
+        tax_rate.as_ref(),
+    );
+
+    // The extracted half of a split lot gets a new lot number
+    for extracted_lot in extracted_lots.iter_mut() {
+        if remaining_lots
+            .iter()
+            .any(|lot| lot.lot_number == extracted_lot.lot_number)
+        {
+            extracted_lot.lot_number = db.next_lot_number();
+        }
+    }
+
+    (extracted_lots, remaining_lots)
+}
+
 impl TrackedAccount {
+    pub fn balance_scan_epoch(&self) -> Epoch {
+        self.last_balance_scan_epoch
+            .unwrap_or(self.last_update_epoch)
+    }
+
     pub fn assert_lot_balance(&self) {
         let lot_balance: u64 = self.lots.iter().map(|lot| lot.amount).sum();
         assert_eq!(
@@ -539,9 +892,29 @@ impl TrackedAccount {
         amount: u64,
         lot_selection_method: LotSelectionMethod,
         lot_numbers: Option<HashSet<usize>>,
+    ) -> DbResult<Vec<Lot>> {
+        self.extract_lots_for_disposal(db, amount, lot_selection_method, lot_numbers, None)
+    }
+
+    pub fn extract_lots_for_disposal(
+        &mut self,
+        db: &mut Db,
+        amount: u64,
+        lot_selection_method: LotSelectionMethod,
+        lot_numbers: Option<HashSet<usize>>,
+        disposal: Option<LotDisposalEstimate>,
     ) -> DbResult<Vec<Lot>> {
         self.assert_lot_balance();
 
+        if lot_selection_method == LotSelectionMethod::TaxOptimal && disposal.is_some() {
+            if db.get_tax_jurisdiction() != TaxJurisdiction::UnitedStates {
+                return Err(DbError::TaxOptimalNotSupported(db.get_tax_jurisdiction()));
+            }
+            if db.get_tax_rate().is_none() {
+                return Err(DbError::TaxRateNotSet);
+            }
+        }
+
         let mut lots = std::mem::take(&mut self.lots);
         lots.sort_by_key(|lot| lot.acquisition.when);
 
@@ -550,8 +923,14 @@ impl TrackedAccount {
             return Err(DbError::AccountHasInsufficientBalance(self.address));
         }
 
-        let (extracted_lots, remaining_lots) =
-            split_lots(db, lots, amount, lot_selection_method, lot_numbers);
+        let (extracted_lots, remaining_lots) = split_lots(
+            db,
+            lots,
+            amount,
+            lot_selection_method,
+            lot_numbers,
+            disposal,
+        );
 
         self.lots = remaining_lots;
         self.last_update_balance -= amount;
@@ -608,6 +987,40 @@ pub struct TaxRate {
     pub long_term_gain: f64,
 }
 
+impl TaxRate {
+    /// Estimated capital gains tax of disposing `lots`, negative when the disposal is a net loss
+    pub fn estimated_tax(
+        &self,
+        token: MaybeToken,
+        lots: &[Lot],
+        disposal: LotDisposalEstimate,
+    ) -> f64 {
+        lots.iter()
+            .map(|lot| {
+                let rate = if is_long_term_holding(lot.acquisition.when, disposal.when) {
+                    self.long_term_gain
+                } else {
+                    self.short_term_gain
+                };
+                lot.cap_gain(token, disposal.price) * rate
+            })
+            .sum()
+    }
+
+    /// Estimated tax owed on net short-term and long-term capital gains. A net loss in one
+    /// term offsets gains in the other, and an overall net loss owes nothing
+    pub fn estimated_cap_gain_tax(&self, short_term_gain: f64, long_term_gain: f64) -> f64 {
+        match (short_term_gain < 0., long_term_gain < 0.) {
+            (false, false) => {
+                short_term_gain * self.short_term_gain + long_term_gain * self.long_term_gain
+            }
+            (true, false) => (short_term_gain + long_term_gain).max(0.) * self.long_term_gain,
+            (false, true) => (short_term_gain + long_term_gain).max(0.) * self.short_term_gain,
+            (true, true) => 0.,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct ValidatorCreditScore {
     #[serde(with = "field_as_string")]
@@ -615,8 +1028,101 @@ pub struct ValidatorCreditScore {
     pub credits: u64,
 }
 
+/// Current `data.json` schema version. Bump this and append a step to `DB_DATA_MIGRATIONS`
+/// whenever the serialized form of `DbData` changes incompatibly
+pub const DB_DATA_VERSION: u32 = 1;
+
+type DbDataMigration = fn(&mut serde_json::Value) -> Result<(), String>;
+
+// `DB_DATA_MIGRATIONS[n]` upgrades a version `n` database to version `n + 1`
+const DB_DATA_MIGRATIONS: &[DbDataMigration] = &[migrate_v0_to_v1];
+
+// Version 0 lot acquisitions and disposals carry either an f64 `price` or an optional
+// `decimal_price`. Version 1 always has a `decimal_price`
+fn migrate_v0_price(object: &mut serde_json::Value) -> Result<(), String> {
+    let object = object
+        .as_object_mut()
+        .ok_or_else(|| format!("JSON object expected: {object}"))?;
+
+    let decimal_price = match object.remove("decimal_price") {
+        Some(decimal_price) if !decimal_price.is_null() => decimal_price,
+        _ => {
+            let price = object
+                .get("price")
+                .and_then(|price| price.as_f64())
+                .ok_or_else(|| {
+                    format!("No price: {}", serde_json::Value::Object(object.clone()))
+                })?;
+            let decimal_price =
+                Decimal::from_f64(price).ok_or_else(|| format!("Invalid price: {price}"))?;
+            serde_json::to_value(decimal_price).map_err(|err| err.to_string())?
+        }
+    };
+    object.remove("price");
+    object.insert("decimal_price".into(), decimal_price);
+    Ok(())
+}
+
+fn migrate_v0_lots(lots: Option<&mut serde_json::Value>) -> Result<(), String> {
+    if let Some(lots) = lots.and_then(|lots| lots.as_array_mut()) {
+        for lot in lots {
+            migrate_v0_price(
+                lot.get_mut("acquisition")
+                    .ok_or_else(|| format!("Lot has no acquisition: {lot}"))?,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn migrate_v0_to_v1(value: &mut serde_json::Value) -> Result<(), String> {
+    fn items<'a>(
+        value: &'a mut serde_json::Value,
+        name: &str,
+    ) -> impl Iterator<Item = &'a mut serde_json::Value> {
+        value
+            .get_mut(name)
+            .and_then(|items| items.as_array_mut())
+            .into_iter()
+            .flatten()
+    }
+
+    for name in [
+        "accounts",
+        "open_orders",
+        "pending_transfers",
+        "pending_withdrawals",
+    ] {
+        for item in items(value, name) {
+            migrate_v0_lots(item.get_mut("lots"))?;
+        }
+    }
+
+    for pending_deposit in items(value, "pending_deposits") {
+        migrate_v0_lots(
+            pending_deposit
+                .get_mut("transfer")
+                .and_then(|transfer| transfer.get_mut("lots")),
+        )?;
+    }
+
+    for disposed_lot in items(value, "disposed_lots") {
+        migrate_v0_price(disposed_lot)?;
+        migrate_v0_price(
+            disposed_lot
+                .get_mut("lot")
+                .and_then(|lot| lot.get_mut("acquisition"))
+                .ok_or_else(|| "Disposed lot has no acquisition".to_string())?,
+        )?;
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
 pub struct DbData {
+    #[serde(default)]
+    version: u32,
     next_lot_number: usize,
     accounts: Vec<TrackedAccount>,
     open_orders: Vec<OpenOrder>,
@@ -628,67 +1134,183 @@ pub struct DbData {
     sweep_stake_account: Option<SweepStakeAccount>,
     transitory_sweep_stake_accounts: Vec<TransitorySweepStake>,
     tax_rate: Option<TaxRate>,
+    #[serde(default)]
+    tax_jurisdiction: TaxJurisdiction,
+    #[serde(default)]
+    fmv_discount_model: Option<FmvDiscountModel>,
+    #[serde(default)]
+    closed_years: BTreeSet<i32>,
+    #[serde(default)]
+    identity_accounts: Vec<IdentityAccount>,
+    // Signatures of confirmed transfers, whose lots keep the acquisition they had in the sender
+    #[serde(default)]
+    confirmed_transfer_signatures: BTreeSet<String>,
+}
+
+fn with_token(token: MaybeToken, lots: &[Lot]) -> impl Iterator<Item = (MaybeToken, &Lot)> {
+    lots.iter().map(move |lot| (token, lot))
 }
 
 impl DbData {
-    fn import_legacy_db(db: &PickleDb) -> Self {
-        Self {
-            next_lot_number: db.get::<usize>("next_lot_number").unwrap_or(0),
-            accounts: db
-                .liter("accounts")
-                .filter_map(|item_iter| item_iter.get_item())
-                .collect(),
-            open_orders: db.get("orders").unwrap_or_default(),
-            disposed_lots: db.get("disposed-lots").unwrap_or_default(),
-            pending_deposits: if db.lexists("deposits") {
-                {
-                    db.liter("deposits")
-                        .filter_map(|item_iter| item_iter.get_item())
-                        .collect()
-                }
-            } else {
-                Default::default()
-            },
-            pending_withdrawals: if db.lexists("withdrawals") {
-                {
-                    db.liter("withdrawals")
-                        .filter_map(|item_iter| item_iter.get_item())
-                        .collect()
-                }
+    fn import_legacy_db(db: &PickleDb) -> serde_json::Value {
+        let list = |name: &str| -> Vec<serde_json::Value> {
+            if db.lexists(name) {
+                db.liter(name)
+                    .filter_map(|item_iter| item_iter.get_item())
+                    .collect()
             } else {
                 Default::default()
-            },
-            pending_transfers: if db.lexists("transfers") {
-                {
-                    db.liter("transfers")
-                        .filter_map(|item_iter| item_iter.get_item())
-                        .collect()
-                }
-            } else {
-                Default::default()
-            },
-            pending_swaps: if db.lexists("swaps") {
-                {
-                    db.liter("swaps")
-                        .filter_map(|item_iter| item_iter.get_item())
-                        .collect()
-                }
-            } else {
-                Default::default()
-            },
-            sweep_stake_account: db.get("sweep-stake-account"),
-            transitory_sweep_stake_accounts: db
-                .get("transitory-sweep-stake-accounts")
-                .unwrap_or_default(),
-            tax_rate: None,
+            }
+        };
+        let get_or_empty_list = |key: &str| {
+            db.get::<serde_json::Value>(key)
+                .unwrap_or_else(|| serde_json::json!([]))
+        };
+
+        serde_json::json!({
+            "next_lot_number": db.get::<usize>("next_lot_number").unwrap_or(0),
+            "accounts": list("accounts"),
+            "open_orders": get_or_empty_list("orders"),
+            "disposed_lots": get_or_empty_list("disposed-lots"),
+            "pending_deposits": list("deposits"),
+            "pending_withdrawals": list("withdrawals"),
+            "pending_transfers": list("transfers"),
+            "pending_swaps": list("swaps"),
+            "sweep_stake_account": db.get::<serde_json::Value>("sweep-stake-account"),
+            "transitory_sweep_stake_accounts":
+                get_or_empty_list("transitory-sweep-stake-accounts"),
+            "tax_rate": null,
+        })
+    }
+
+    // Upgrade `value` to `DB_DATA_VERSION`, returning the original version if any migrations
+    // were applied
+    fn migrate(mut value: serde_json::Value) -> DbResult<(Self, Option<u32>)> {
+        if !value.is_object() {
+            return Err(io::Error::other("JSON object expected").into());
+        }
+        let version = value
+            .get("version")
+            .and_then(|version| version.as_u64())
+            .unwrap_or_default() as u32;
+
+        if version > DB_DATA_VERSION {
+            return Err(DbError::UnsupportedVersion(version, DB_DATA_VERSION));
         }
+        assert_eq!(DB_DATA_MIGRATIONS.len(), DB_DATA_VERSION as usize);
+
+        for (from_version, migration) in
+            DB_DATA_MIGRATIONS.iter().enumerate().skip(version as usize)
+        {
+            let from_version = from_version as u32;
+            migration(&mut value).map_err(|err| DbError::MigrationFailed(from_version, err))?;
+            value["version"] = (from_version + 1).into();
+        }
+
+        let data = serde_json::from_value(value)
+            .map_err(|err| io::Error::other(format!("JSON parse failed: {err:?}")))?;
+        Ok((data, (version < DB_DATA_VERSION).then_some(version)))
     }
 
-    fn load(filename: &Path) -> io::Result<Self> {
+    fn load(filename: &Path) -> DbResult<Self> {
         let bytes = fs::read(filename)?;
 
-        serde_json::from_str(std::str::from_utf8(&bytes).expect("invalid utf8"))
-            .map_err(|err| io::Error::other(format!("JSON parse failed: {err:?}")))
+        let value = serde_json::from_str(std::str::from_utf8(&bytes).expect("invalid utf8"))
+            .map_err(|err| io::Error::other(format!("JSON parse failed: {err:?}")))?;
+
+        let (data, migrated_from_version) = Self::migrate(value)?;
+        if let Some(version) = migrated_from_version {
+            // Keep the original around in case something went wrong with the migration
+            let backup_filename = format!("{}.v{version}", filename.display());
+            fs::copy(filename, &backup_filename)?;
+            data.save(filename)?;
+            println!(
+                "Upgraded {} from version {version} to {DB_DATA_VERSION} (backup: {backup_filename})",
+                filename.display()
+            );
+        }
+        Ok(data)
+    }
+
+    // Every lot not yet disposed of, whether held in an account or in flight
+    fn held_lots(&self) -> impl Iterator<Item = (MaybeToken, &Lot)> {
+        self.accounts
+            .iter()
+            .flat_map(|account| with_token(account.token, &account.lots))
+            .chain(self.in_flight_lots())
+    }
+
+    // Lots in an open order or a pending transfer, deposit or withdrawal
+    fn in_flight_lots(&self) -> impl Iterator<Item = (MaybeToken, &Lot)> {
+        self.open_orders
+            .iter()
+            .flat_map(|order| with_token(order.token, &order.lots))
+            .chain(
+                self.pending_transfers
+                    .iter()
+                    .flat_map(|transfer| with_token(transfer.from_token, &transfer.lots)),
+            )
+            .chain(self.pending_deposits.iter().flat_map(|deposit| {
+                with_token(deposit.transfer.from_token, &deposit.transfer.lots)
+            }))
+            .chain(
+                self.pending_withdrawals
+                    .iter()
+                    .flat_map(|withdrawal| with_token(withdrawal.token, &withdrawal.lots)),
+            )
+    }
+
+    // The acquisitions and disposals of a closed `year`. Acquisitions are totalled by kind, as
+    // lots of the same acquisition may be split, merged, moved between accounts or disposed of
+    // in a later year
+    fn closed_year_lots(&self, year: i32) -> (BTreeMap<String, u64>, Vec<String>) {
+        let mut acquisitions = BTreeMap::<String, u64>::default();
+        for lot in self
+            .held_lots()
+            .map(|(_token, lot)| lot)
+            .chain(
+                self.disposed_lots
+                    .iter()
+                    .map(|disposed_lot| &disposed_lot.lot),
+            )
+            .filter(|lot| lot.acquisition.when.year() == year)
+        {
+            *acquisitions
+                .entry(serde_json::to_string(&lot.acquisition).unwrap())
+                .or_default() += lot.amount;
+        }
+
+        let mut disposals = self
+            .disposed_lots
+            .iter()
+            .filter(|disposed_lot| disposed_lot.when.year() == year)
+            .map(|disposed_lot| serde_json::to_string(disposed_lot).unwrap())
+            .collect::<Vec<_>>();
+        disposals.sort();
+
+        (acquisitions, disposals)
+    }
+
+    // The first closed year that was reopened, or whose lots differ from `previous`
+    fn modified_closed_year(&self, previous: &Self) -> Option<i32> {
+        if let Some(year) = previous
+            .closed_years
+            .iter()
+            .find(|year| !self.closed_years.contains(year))
+        {
+            return Some(*year);
+        }
+
+        // Totalling the lots of closed years is only worth it when some lot changed
+        if self.disposed_lots == previous.disposed_lots && self.held_lots().eq(previous.held_lots())
+        {
+            return None;
+        }
+        previous
+            .closed_years
+            .iter()
+            .find(|year| self.closed_year_lots(**year) != previous.closed_year_lots(**year))
+            .copied()
     }
 
     fn save(&self, filename: &Path) -> io::Result<()> {
@@ -719,12 +1341,10 @@ impl Db {
     ) -> DbResult<()> {
         self.clear_exchange_credentials(exchange, exchange_account)?;
 
-        self.credentials_db
-            .set(
-                &format!("{exchange:?}{exchange_account}"),
-                &exchange_credentials,
-            )
-            .unwrap();
+        self.credentials_db.set(
+            &format!("{exchange:?}{exchange_account}"),
+            &exchange_credentials,
+        )?;
 
         Ok(self.credentials_db.dump()?)
     }
@@ -733,7 +1353,7 @@ impl Db {
         &self,
         exchange: Exchange,
         exchange_account: &str,
-    ) -> Option<ExchangeCredentials> {
+    ) -> DbResult<Option<ExchangeCredentials>> {
         self.credentials_db
             .get(&format!("{exchange:?}{exchange_account}"))
     }
@@ -744,12 +1364,11 @@ impl Db {
         exchange_account: &str,
     ) -> DbResult<()> {
         if self
-            .get_exchange_credentials(exchange, exchange_account)
+            .get_exchange_credentials(exchange, exchange_account)?
             .is_some()
         {
             self.credentials_db
-                .rem(&format!("{exchange:?}{exchange_account}"))
-                .ok();
+                .rem(&format!("{exchange:?}{exchange_account}"))?;
             self.credentials_db.dump()?;
         }
         Ok(())
@@ -757,53 +1376,139 @@ impl Db {
 
     pub fn get_default_accounts_from_configured_exchanges(
         &self,
-    ) -> Vec<(Exchange, ExchangeCredentials, String)> {
-        self.credentials_db
-            .get_all()
-            .into_iter()
-            .filter_map(|key| {
-                if let Ok(exchange) = key.parse() {
-                    self.get_exchange_credentials(exchange, "")
-                        .map(|exchange_credentials| (exchange, exchange_credentials, "".into()))
-                } else {
-                    None
+    ) -> DbResult<Vec<(Exchange, ExchangeCredentials, String)>> {
+        let mut accounts = vec![];
+        for key in self.credentials_db.get_all()? {
+            if let Ok(exchange) = key.parse() {
+                if let Some(exchange_credentials) = self.get_exchange_credentials(exchange, "")? {
+                    accounts.push((exchange, exchange_credentials, "".into()));
                 }
-            })
-            .collect()
+            }
+        }
+        Ok(accounts)
     }
 
     pub fn set_metrics_config(&mut self, metrics_config: MetricsConfig) -> DbResult<()> {
         self.clear_metrics_config()?;
 
-        self.credentials_db
-            .set("influxdb", &metrics_config)
-            .unwrap();
+        self.credentials_db.set("influxdb", &metrics_config)?;
 
         Ok(self.credentials_db.dump()?)
     }
 
-    pub fn get_metrics_config(&self) -> Option<MetricsConfig> {
+    pub fn get_metrics_config(&self) -> DbResult<Option<MetricsConfig>> {
         self.credentials_db.get("influxdb")
     }
 
     pub fn clear_metrics_config(&mut self) -> DbResult<()> {
-        if self.get_metrics_config().is_some() {
-            self.credentials_db.rem("influxdb").ok();
+        if self.get_metrics_config()?.is_some() {
+            self.credentials_db.rem("influxdb")?;
             self.credentials_db.dump()?;
         }
         Ok(())
     }
 
+    pub fn rekey_credentials(&mut self, new_key: CredentialsKey) -> DbResult<()> {
+        self.credentials_db.rekey(new_key)
+    }
+
+    pub fn credentials_encrypted(&self) -> bool {
+        self.credentials_db.is_encrypted()
+    }
+
     fn auto_save(&mut self, auto_save: bool) -> DbResult<()> {
         self.auto_save = auto_save;
         self.save()
     }
 
+    fn begin_operation(&mut self, operation: JournalOperation) {
+        self.operation = Some(operation);
+    }
+
+    // For mutations that are also used to implement other operations, which then take precedence
+    fn begin_nested_operation(&mut self, operation: JournalOperation) {
+        if self.operation.is_none() {
+            self.operation = Some(operation);
+        }
+    }
+
     fn save(&mut self) -> DbResult<()> {
         if self.auto_save {
-            self.data.save(&self.data_filename)?;
+            let mut operation = self.operation.take().unwrap_or(JournalOperation::Other);
+            if self.data != self.persisted_data {
+                // Lots of closed years are only modified when overridden, whatever the operation
+                if let Some(year) = self.data.modified_closed_year(&self.persisted_data) {
+                    if !self.override_closed_years {
+                        self.data = self.persisted_data.clone();
+                        return Err(DbError::ClosedYear(year));
+                    }
+                    eprintln!("Warning: modifying closed tax year {year}");
+                    operation = JournalOperation::ClosedYearOverride {
+                        year,
+                        operation: Box::new(operation),
+                    };
+                }
+
+                // Journal first, so that a saved change can always be undone
+                let snapshot =
+                    serde_json::to_string(&self.persisted_data).map_err(io::Error::other)?;
+                self.journal.append(operation, &snapshot)?;
+
+                self.data.save(&self.data_filename)?;
+                self.persisted_data = self.data.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// All journaled operations, oldest first
+    pub fn journal_entries(&self) -> DbResult<Vec<JournalEntry>> {
+        Ok(self.journal.entries()?)
+    }
+
+    /// Revert the last `count` journaled operations.
+    ///
+    /// Operations that track a transaction, exchange order or transfer are not undone without
+    /// `force`, as the database would no longer reflect what actually happened.  The undo itself
+    /// is journaled and may also be undone
+    pub fn undo(&mut self, count: usize, force: bool) -> DbResult<Vec<JournalEntry>> {
+        let entries = self.journal.entries()?;
+        if count == 0 || count > entries.len() {
+            return Err(DbError::UndoFailed(format!(
+                "{count} operation(s) requested but only {} journaled",
+                entries.len()
+            )));
+        }
+        let undone_entries = entries[entries.len() - count..].to_vec();
+
+        if !force {
+            if let Some(entry) = undone_entries
+                .iter()
+                .find(|entry| entry.operation.is_external())
+            {
+                return Err(DbError::UndoFailed(format!(
+                    "\"{}\" tracks an external transaction or order, use --force to undo anyway",
+                    entry.operation
+                )));
+            }
         }
-        Ok(())
+
+        let snapshot = self
+            .journal
+            .snapshot(undone_entries[0].id)?
+            .ok_or_else(|| {
+                DbError::UndoFailed(format!(
+                    "No snapshot available for \"{}\", it is too old to undo",
+                    undone_entries[0].operation
+                ))
+            })?;
+        let value = serde_json::from_str(&snapshot)
+            .map_err(|err| DbError::UndoFailed(format!("Invalid snapshot: {err}")))?;
+
+        self.data = DbData::migrate(value)?.0;
+        self.begin_operation(JournalOperation::Undo { count });
+        self.save()?;
+        Ok(undone_entries)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -819,6 +1524,10 @@ impl Db {
         lot_selection_method: LotSelectionMethod,
         lot_numbers: Option<HashSet<usize>>,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::RecordDeposit {
+            signature,
+            exchange,
+        });
         let mut from_account = self
             .get_account(from_address, token)
             .ok_or(DbError::AccountDoesNotExist(from_address, token))?;
@@ -837,7 +1546,7 @@ impl Db {
             },
         };
         self.data.pending_deposits.push(deposit);
-        self.update_account(from_account) // `update_account` calls `save`...
+        self.update_account_nested(from_account) // `update_account_nested` calls `save`...
     }
 
     fn complete_deposit(
@@ -860,24 +1569,38 @@ impl Db {
     }
 
     pub fn cancel_deposit(&mut self, signature: Signature) -> DbResult<()> {
+        self.begin_operation(JournalOperation::CancelDeposit { signature });
         self.complete_deposit(signature, None)
     }
 
     pub fn confirm_deposit(&mut self, signature: Signature, when: NaiveDate) -> DbResult<()> {
+        self.begin_operation(JournalOperation::ConfirmDeposit { signature });
         self.complete_deposit(signature, Some(when))
     }
 
-    // Careful!
-    pub fn drop_deposit(&mut self, signature: Signature) -> DbResult<()> {
-        let _ = self
+    // Careful! The lots of the dropped deposit are disposed of like untracked fiat lots, so the
+    // acquisitions of closed years remain accounted for
+    pub fn drop_deposit(&mut self, signature: Signature, when: NaiveDate) -> DbResult<()> {
+        self.begin_operation(JournalOperation::DropDeposit { signature });
+        let pending_deposit = self
             .data
             .pending_deposits
             .iter()
             .find(|pd| pd.transfer.signature == signature)
-            .ok_or(DbError::PendingDepositDoesNotExist(signature))?;
+            .ok_or(DbError::PendingDepositDoesNotExist(signature))?
+            .clone();
         self.data
             .pending_deposits
             .retain(|pd| pd.transfer.signature != signature);
+        self.record_lots_disposal(
+            pending_deposit.transfer.from_token,
+            pending_deposit.transfer.lots,
+            LotDisposalKind::Other {
+                description: "fiat".into(),
+            },
+            when,
+            Decimal::from_f64(1.).unwrap(),
+        );
         self.save()
     }
 
@@ -906,9 +1629,15 @@ impl Db {
         from_token_price: Decimal,
         to_token: MaybeToken,
         to_token_price: Decimal,
+        to_token_price_source: Option<PriceSourceKind>,
         lot_selection_method: LotSelectionMethod,
         lot_numbers: Option<HashSet<usize>>,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::RecordSwap {
+            signature,
+            from_token,
+            to_token,
+        });
         let _ = self
             .get_account(address, from_token)
             .ok_or(DbError::AccountDoesNotExist(address, from_token))?;
@@ -921,6 +1650,7 @@ impl Db {
             from_token_price,
             to_token,
             to_token_price,
+            to_token_price_source,
             lot_selection_method,
             lot_numbers,
         });
@@ -939,6 +1669,7 @@ impl Db {
             from_token_price,
             to_token,
             to_token_price,
+            to_token_price_source,
             lot_selection_method,
             lot_numbers,
             ..
@@ -963,8 +1694,16 @@ impl Db {
 
         self.auto_save(false)?;
         if let Some((when, from_amount, to_amount)) = success {
-            let lots =
-                from_account.extract_lots(self, from_amount, lot_selection_method, lot_numbers)?;
+            let lots = from_account.extract_lots_for_disposal(
+                self,
+                from_amount,
+                lot_selection_method,
+                lot_numbers,
+                Some(LotDisposalEstimate {
+                    price: from_token_price,
+                    when,
+                }),
+            )?;
 
             let to_amount_over_from_amount = to_amount as f64 / from_amount as f64;
             for lot in lots {
@@ -974,8 +1713,7 @@ impl Db {
                 self.data.disposed_lots.push(DisposedLot {
                     lot,
                     when,
-                    price: None,
-                    decimal_price: Some(from_token_price),
+                    decimal_price: from_token_price,
                     kind: LotDisposalKind::Swap {
                         signature,
                         token: to_token,
@@ -988,25 +1726,27 @@ impl Db {
             to_account.merge_or_add_lot(Lot {
                 lot_number: self.next_lot_number(),
                 acquisition: LotAcquistion {
-                    price: None,
-                    decimal_price: Some(to_token_price),
+                    decimal_price: to_token_price,
                     when,
                     kind: LotAcquistionKind::Swap {
                         signature,
                         token: from_token,
                         amount: Some(from_amount),
                     },
+                    price_source: to_token_price_source,
+                    fmv_discount: None,
                 },
                 amount: to_amount,
             });
             to_account.last_update_balance += to_amount;
-            self.update_account(from_account)?;
-            self.update_account(to_account)?;
+            self.update_account_nested(from_account)?;
+            self.update_account_nested(to_account)?;
         }
         self.auto_save(true)
     }
 
     pub fn cancel_swap(&mut self, signature: Signature) -> DbResult<()> {
+        self.begin_operation(JournalOperation::CancelSwap { signature });
         self.complete_swap(signature, None)
     }
 
@@ -1017,6 +1757,7 @@ impl Db {
         from_amount: u64,
         to_amount: u64,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::ConfirmSwap { signature });
         self.complete_swap(signature, Some((when, from_amount, to_amount)))
     }
 
@@ -1037,6 +1778,10 @@ impl Db {
         lot_selection_method: LotSelectionMethod,
         lot_numbers: Option<HashSet<usize>>,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::RecordWithdrawal {
+            exchange,
+            tag: tag.clone(),
+        });
         if self.data.pending_withdrawals.iter().any(|pw| pw.tag == tag) {
             panic!("Withdrawal tag already present in database: {tag}");
         }
@@ -1057,10 +1802,11 @@ impl Db {
             vec![Lot {
                 lot_number: self.next_lot_number(),
                 acquisition: LotAcquistion {
-                    price: Some(1.),
-                    decimal_price: None,
+                    decimal_price: Decimal::ONE,
                     when,
                     kind: LotAcquistionKind::Fiat,
+                    price_source: None,
+                    fmv_discount: None,
                 },
                 amount,
             }]
@@ -1080,7 +1826,7 @@ impl Db {
         };
 
         self.data.pending_withdrawals.push(withdrawal);
-        self.update_account(from_account) // `update_account` calls `save`.../
+        self.update_account_nested(from_account) // `update_account_nested` calls `save`.../
     }
 
     // The caller must call `save()`...
@@ -1098,6 +1844,7 @@ impl Db {
             ..
         }: PendingWithdrawal,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::CancelWithdrawal { tag: tag.clone() });
         self.remove_pending_withdrawal(&tag);
 
         let mut from_account = self
@@ -1106,7 +1853,7 @@ impl Db {
         if !token.fiat_fungible() {
             from_account.merge_lots(lots);
         }
-        self.update_account(from_account) // `update_account` calls `save`...
+        self.update_account_nested(from_account) // `update_account_nested` calls `save`...
     }
 
     pub fn confirm_withdrawal(
@@ -1122,6 +1869,7 @@ impl Db {
         }: PendingWithdrawal,
         when: NaiveDate,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::ConfirmWithdrawal { tag: tag.clone() });
         self.remove_pending_withdrawal(&tag);
 
         if fee > 0 {
@@ -1147,7 +1895,7 @@ impl Db {
             .ok_or(DbError::AccountDoesNotExist(to_address, token))?;
 
         to_account.merge_lots(lots);
-        self.update_account(to_account) // `update_account` calls `save`...
+        self.update_account_nested(to_account) // `update_account_nested` calls `save`...
     }
 
     pub fn pending_withdrawals(&self, exchange: Option<Exchange>) -> Vec<PendingWithdrawal> {
@@ -1177,6 +1925,10 @@ impl Db {
         lots: Vec<Lot>,
         ui_amount: Option<f64>,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::OpenOrder {
+            exchange,
+            order_id: order_id.clone(),
+        });
         match side {
             OrderSide::Buy => {
                 assert!(lots.is_empty());
@@ -1200,11 +1952,15 @@ impl Db {
             token: deposit_account.token,
             ui_amount,
         });
-        self.update_account(deposit_account) // `update_account` calls `save`...
+        self.update_account_nested(deposit_account) // `update_account_nested` calls `save`...
     }
 
     #[allow(dead_code)]
     pub fn update_order_price(&mut self, order_id: &str, price: f64) -> DbResult<()> {
+        self.begin_operation(JournalOperation::UpdateOrderPrice {
+            order_id: order_id.into(),
+            price,
+        });
         self.data.open_orders = self
             .data
             .open_orders
@@ -1229,6 +1985,9 @@ impl Db {
         when: NaiveDate,
         fee: Option<(f64, String)>,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::CloseOrder {
+            order_id: order_id.into(),
+        });
         self.auto_save(false)?;
 
         let OpenOrder {
@@ -1249,6 +2008,15 @@ impl Db {
             .clone();
         self.data.open_orders.retain(|o| o.order_id != order_id);
 
+        if let Some(fee @ (fee_amount, fee_coin)) = &fee {
+            if exchange_fee_in_usd(fee, token, Decimal::ONE).is_none() {
+                eprintln!(
+                    "Warning: {exchange:?} order {order_id} fee of {fee_amount} {fee_coin} is not \
+                     in USD or {token} and was ignored"
+                );
+            }
+        }
+
         match side {
             OrderSide::Buy => {
                 assert!(lots.is_empty());
@@ -1258,21 +2026,36 @@ impl Db {
                         .get_account(deposit_address, token)
                         .ok_or(DbError::AccountDoesNotExist(deposit_address, token))?;
 
+                    // Buy-side fees are added to the basis
+                    let decimal_price = Decimal::from_f64(price).unwrap();
+                    let decimal_price = match fee
+                        .as_ref()
+                        .and_then(|fee| exchange_fee_in_usd(fee, token, decimal_price))
+                    {
+                        Some(fee) => {
+                            decimal_price
+                                + fee / Decimal::from_f64(token.ui_amount(filled_amount)).unwrap()
+                        }
+                        None => decimal_price,
+                    };
+
                     deposit_account.merge_lots(vec![Lot {
                         lot_number: self.next_lot_number(),
                         acquisition: LotAcquistion {
                             when,
-                            price: Some(price),
-                            decimal_price: None,
+                            decimal_price,
                             kind: LotAcquistionKind::Exchange {
                                 exchange,
                                 pair,
                                 order_id,
+                                fee,
                             },
+                            price_source: None,
+                            fmv_discount: None,
                         },
                         amount: filled_amount,
                     }]);
-                    self.update_account(deposit_account)?;
+                    self.update_account_nested(deposit_account)?;
                 }
             }
             OrderSide::Sell => {
@@ -1286,6 +2069,7 @@ impl Db {
                     filled_amount,
                     LotSelectionMethod::default(),
                     None,
+                    None,
                 );
 
                 if !filled_lots.is_empty() {
@@ -1300,8 +2084,7 @@ impl Db {
                         self.data.disposed_lots.push(DisposedLot {
                             lot,
                             when,
-                            price: Some(price),
-                            decimal_price: None,
+                            decimal_price: Decimal::from_f64(price).unwrap(),
                             kind: LotDisposalKind::Usd {
                                 exchange,
                                 pair: pair.clone(),
@@ -1319,7 +2102,7 @@ impl Db {
                         .ok_or(DbError::AccountDoesNotExist(deposit_address, token))?;
 
                     deposit_account.merge_lots(cancelled_lots);
-                    self.update_account(deposit_account)?;
+                    self.update_account_nested(deposit_account)?;
                 }
             }
         }
@@ -1335,11 +2118,16 @@ impl Db {
         lot_selection_method: LotSelectionMethod,
         lot_numbers: Option<HashSet<usize>>,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::RecordDrop {
+            address: from_address,
+            token,
+            amount,
+        });
         let mut from_account = self
             .get_account(from_address, token)
             .ok_or(DbError::AccountDoesNotExist(from_address, token))?;
         let _ = from_account.extract_lots(self, amount, lot_selection_method, lot_numbers)?;
-        self.update_account(from_account)?; // `update_account` calls `save`...
+        self.update_account_nested(from_account)?; // `update_account_nested` calls `save`...
         Ok(())
     }
 
@@ -1355,10 +2143,25 @@ impl Db {
         lot_selection_method: LotSelectionMethod,
         lot_numbers: Option<HashSet<usize>>,
     ) -> DbResult<Vec<DisposedLot>> {
+        self.begin_operation(JournalOperation::RecordDisposal {
+            address: from_address,
+            token,
+            amount,
+            description: description.clone(),
+        });
         let mut from_account = self
             .get_account(from_address, token)
             .ok_or(DbError::AccountDoesNotExist(from_address, token))?;
-        let lots = from_account.extract_lots(self, amount, lot_selection_method, lot_numbers)?;
+        let lots = from_account.extract_lots_for_disposal(
+            self,
+            amount,
+            lot_selection_method,
+            lot_numbers,
+            Some(LotDisposalEstimate {
+                price: decimal_price,
+                when,
+            }),
+        )?;
         let disposed_lots = self.record_lots_disposal(
             token,
             lots,
@@ -1366,7 +2169,47 @@ impl Db {
             when,
             decimal_price,
         );
-        self.update_account(from_account)?; // `update_account` calls `save`...
+        self.update_account_nested(from_account)?; // `update_account_nested` calls `save`...
+        Ok(disposed_lots)
+    }
+
+    /// Dispose of the SOL that `fee_payer` spent on the fee of transaction `signature`. Nothing is
+    /// recorded if the fee payer is not tracked or the fee was already recorded
+    pub fn record_transaction_fee(
+        &mut self,
+        signature: Signature,
+        fee_payer: Pubkey,
+        fee: u64,
+        when: NaiveDate,
+        decimal_price: Decimal,
+    ) -> DbResult<Vec<DisposedLot>> {
+        let token = MaybeToken::SOL();
+        let Some(mut fee_payer_account) = self.get_account(fee_payer, token) else {
+            return Ok(vec![]);
+        };
+        if fee == 0
+            || self.data.disposed_lots.iter().any(|disposed_lot| {
+                disposed_lot.kind == LotDisposalKind::TransactionFee { signature }
+            })
+        {
+            return Ok(vec![]);
+        }
+
+        self.begin_operation(JournalOperation::RecordTransactionFee { signature });
+        let lots = fee_payer_account.extract_lots(
+            self,
+            fee.min(fee_payer_account.last_update_balance),
+            LotSelectionMethod::default(),
+            None,
+        )?;
+        let disposed_lots = self.record_lots_disposal(
+            token,
+            lots,
+            LotDisposalKind::TransactionFee { signature },
+            when,
+            decimal_price,
+        );
+        self.update_account_nested(fee_payer_account)?; // `update_account_nested` calls `save`...
         Ok(disposed_lots)
     }
 
@@ -1384,8 +2227,7 @@ impl Db {
             let disposed_lot = DisposedLot {
                 lot,
                 when,
-                price: None,
-                decimal_price: Some(decimal_price),
+                decimal_price,
                 kind: kind.clone(),
                 token,
             };
@@ -1427,11 +2269,37 @@ impl Db {
     }
 
     pub fn add_account(&mut self, account: TrackedAccount) -> DbResult<()> {
+        self.begin_operation(JournalOperation::AddAccount {
+            address: account.address,
+            token: account.token,
+        });
+        self.add_account_nested(account)
+    }
+
+    // `add_account` as part of another operation
+    fn add_account_nested(&mut self, account: TrackedAccount) -> DbResult<()> {
+        self.begin_nested_operation(JournalOperation::AddAccount {
+            address: account.address,
+            token: account.token,
+        });
         self.add_account_no_save(account)?;
         self.save()
     }
 
     pub fn update_account(&mut self, account: TrackedAccount) -> DbResult<()> {
+        self.begin_operation(JournalOperation::UpdateAccount {
+            address: account.address,
+            token: account.token,
+        });
+        self.update_account_nested(account)
+    }
+
+    // `update_account` as part of another operation
+    fn update_account_nested(&mut self, account: TrackedAccount) -> DbResult<()> {
+        self.begin_nested_operation(JournalOperation::UpdateAccount {
+            address: account.address,
+            token: account.token,
+        });
         account.assert_lot_balance();
 
         let position = self
@@ -1441,6 +2309,25 @@ impl Db {
         self.save()
     }
 
+    /// Update the accounts that were synced through `epoch` at once, so an interrupted sync
+    /// resumes from the following epoch
+    pub fn update_accounts_through_epoch(
+        &mut self,
+        epoch: Epoch,
+        accounts: &[TrackedAccount],
+    ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::SyncEpochRewards { epoch });
+        for account in accounts {
+            account.assert_lot_balance();
+
+            let position = self
+                .get_account_position(account.address, account.token)
+                .ok_or(DbError::AccountDoesNotExist(account.address, account.token))?;
+            self.data.accounts[position] = account.clone();
+        }
+        self.save()
+    }
+
     fn remove_account_no_save(&mut self, address: Pubkey, token: MaybeToken) -> DbResult<()> {
         let position = self
             .get_account_position(address, token)
@@ -1450,6 +2337,7 @@ impl Db {
     }
 
     pub fn remove_account(&mut self, address: Pubkey, token: MaybeToken) -> DbResult<()> {
+        self.begin_operation(JournalOperation::RemoveAccount { address, token });
         self.remove_account_no_save(address, token)?;
         self.save()
     }
@@ -1503,6 +2391,9 @@ impl Db {
         &mut self,
         sweep_stake_account: SweepStakeAccount,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::SetSweepStakeAccount {
+            address: sweep_stake_account.address,
+        });
         let _ = self
             .get_account_position(sweep_stake_account.address, MaybeToken::SOL())
             .ok_or_else(|| {
@@ -1526,6 +2417,7 @@ impl Db {
         address: Pubkey,
         current_epoch: Epoch,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::AddTransitorySweepStake { address });
         let mut transitory_sweep_stake_addresses = self.get_transitory_sweep_stake_addresses();
 
         if transitory_sweep_stake_addresses.contains(&address) {
@@ -1541,12 +2433,14 @@ impl Db {
             description: "Transitory stake account".to_string(),
             last_update_balance: 0,
             last_update_epoch: current_epoch,
+            last_balance_scan_epoch: None,
             lots: vec![],
             no_sync: None,
         })
     }
 
     pub fn remove_transitory_sweep_stake_address(&mut self, address: Pubkey) -> DbResult<()> {
+        self.begin_operation(JournalOperation::RemoveTransitorySweepStake { address });
         let token = MaybeToken::SOL();
         let _ = self.remove_account_no_save(address, token);
 
@@ -1579,10 +2473,97 @@ impl Db {
     }
 
     pub fn set_tax_rate(&mut self, tax_rate: TaxRate) -> DbResult<()> {
+        self.begin_operation(JournalOperation::SetTaxRate);
         self.data.tax_rate = Some(tax_rate);
         self.save()
     }
 
+    pub fn get_fmv_discount_model(&self) -> Option<&FmvDiscountModel> {
+        self.data.fmv_discount_model.as_ref()
+    }
+
+    pub fn set_fmv_discount_model(
+        &mut self,
+        fmv_discount_model: Option<FmvDiscountModel>,
+    ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::SetFmvDiscountModel);
+        self.data.fmv_discount_model = fmv_discount_model;
+        self.save()
+    }
+
+    pub fn get_identity_accounts(&self) -> Vec<IdentityAccount> {
+        self.data.identity_accounts.clone()
+    }
+
+    pub fn set_identity_account(&mut self, identity_account: IdentityAccount) -> DbResult<()> {
+        self.begin_operation(JournalOperation::SetIdentityAccount {
+            address: identity_account.address,
+        });
+        self.data
+            .identity_accounts
+            .retain(|existing| existing.address != identity_account.address);
+        self.data.identity_accounts.push(identity_account);
+        self.save()
+    }
+
+    /// Save the block rewards recorded in the identity's `account` together with the slot they
+    /// were synced through, so that no block is recorded twice
+    pub fn update_identity_account_block_rewards(
+        &mut self,
+        account: TrackedAccount,
+        identity_account: IdentityAccount,
+    ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::SyncBlockRewards {
+            address: identity_account.address,
+            last_slot: identity_account.last_slot,
+        });
+        account.assert_lot_balance();
+
+        let position = self
+            .get_account_position(account.address, account.token)
+            .ok_or(DbError::AccountDoesNotExist(account.address, account.token))?;
+        self.data.accounts[position] = account;
+        self.data
+            .identity_accounts
+            .retain(|existing| existing.address != identity_account.address);
+        self.data.identity_accounts.push(identity_account);
+        self.save()
+    }
+
+    pub fn remove_identity_account(&mut self, address: Pubkey) -> DbResult<()> {
+        self.begin_operation(JournalOperation::RemoveIdentityAccount { address });
+        self.data
+            .identity_accounts
+            .retain(|identity_account| identity_account.address != address);
+        self.save()
+    }
+
+    pub fn closed_years(&self) -> Vec<i32> {
+        self.data.closed_years.iter().copied().collect()
+    }
+
+    /// Freeze the acquisitions and disposals dated in `year`, usually once its tax return is filed
+    pub fn close_year(&mut self, year: i32) -> DbResult<()> {
+        self.begin_operation(JournalOperation::CloseYear { year });
+        self.data.closed_years.insert(year);
+        self.save()
+    }
+
+    /// Permit modifications to closed years. Each such modification is journaled as an override
+    pub fn set_override_closed_years(&mut self, override_closed_years: bool) {
+        self.override_closed_years = override_closed_years;
+    }
+
+    pub fn get_tax_jurisdiction(&self) -> TaxJurisdiction {
+        self.data.tax_jurisdiction
+    }
+
+    pub fn set_tax_jurisdiction(&mut self, tax_jurisdiction: TaxJurisdiction) -> DbResult<()> {
+        self.begin_operation(JournalOperation::SetTaxJurisdiction { tax_jurisdiction });
+        self.data.tax_jurisdiction = tax_jurisdiction;
+        self.save()
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn record_transfer(
         &mut self,
@@ -1596,6 +2577,11 @@ impl Db {
         lot_selection_method: LotSelectionMethod,
         lot_numbers: Option<HashSet<usize>>,
     ) -> DbResult<()> {
+        self.begin_operation(JournalOperation::RecordTransfer {
+            signature,
+            from_address,
+            to_address,
+        });
         assert_eq!(from_token.mint(), to_token.mint());
 
         let mut pending_transfers = self.pending_transfers();
@@ -1623,7 +2609,7 @@ impl Db {
         });
 
         self.data.pending_transfers = pending_transfers;
-        self.update_account(from_account) // `update_account` calls `save`...
+        self.update_account_nested(from_account) // `update_account_nested` calls `save`...
     }
 
     fn complete_transfer_or_deposit(
@@ -1673,8 +2659,8 @@ impl Db {
             from_account.merge_lots(lots);
         }
 
-        self.update_account(to_account)?;
-        self.update_account(from_account)?;
+        self.update_account_nested(to_account)?;
+        self.update_account_nested(from_account)?;
         self.auto_save(true)
     }
 
@@ -1693,15 +2679,22 @@ impl Db {
 
         pending_transfers.retain(|pt| pt.signature != signature);
         self.data.pending_transfers = pending_transfers;
+        if success.is_some() {
+            self.data
+                .confirmed_transfer_signatures
+                .insert(signature.to_string());
+        }
 
         self.complete_transfer_or_deposit(transfer, success, true) // `complete_transfer_or_deposit` calls `save`...
     }
 
     pub fn cancel_transfer(&mut self, signature: Signature) -> DbResult<()> {
+        self.begin_operation(JournalOperation::CancelTransfer { signature });
         self.complete_transfer(signature, None)
     }
 
     pub fn confirm_transfer(&mut self, signature: Signature, when: NaiveDate) -> DbResult<()> {
+        self.begin_operation(JournalOperation::ConfirmTransfer { signature });
         self.complete_transfer(signature, Some(when))
     }
 
@@ -1709,6 +2702,74 @@ impl Db {
         self.data.pending_transfers.clone()
     }
 
+    /// The transactions the database already accounts for, such as pending or confirmed
+    /// transfers, swaps and transaction fees
+    pub fn known_signatures(&self) -> HashSet<Signature> {
+        let lot_signature = |lot: &Lot| match &lot.acquisition.kind {
+            LotAcquistionKind::Transaction { signature, .. }
+            | LotAcquistionKind::Swap { signature, .. }
+            | LotAcquistionKind::MevReward { signature, .. } => Some(*signature),
+            _ => None,
+        };
+
+        let mut known_signatures = HashSet::default();
+        known_signatures.extend(
+            self.data
+                .pending_transfers
+                .iter()
+                .map(|pending_transfer| pending_transfer.signature),
+        );
+        known_signatures.extend(
+            self.data
+                .confirmed_transfer_signatures
+                .iter()
+                .filter_map(|signature| signature.parse::<Signature>().ok()),
+        );
+        known_signatures.extend(
+            self.data
+                .pending_deposits
+                .iter()
+                .map(|pending_deposit| pending_deposit.transfer.signature),
+        );
+        known_signatures.extend(
+            self.data
+                .pending_swaps
+                .iter()
+                .map(|pending_swap| pending_swap.signature),
+        );
+        known_signatures.extend(
+            self.data
+                .held_lots()
+                .filter_map(|(_token, lot)| lot_signature(lot)),
+        );
+        for disposed_lot in &self.data.disposed_lots {
+            known_signatures.extend(lot_signature(&disposed_lot.lot));
+            if let LotDisposalKind::Swap { signature, .. }
+            | LotDisposalKind::TransactionFee { signature } = &disposed_lot.kind
+            {
+                known_signatures.insert(*signature);
+            }
+        }
+        known_signatures
+    }
+
+    /// Every lot not yet disposed of, including those in open orders and pending transfers,
+    /// deposits and withdrawals
+    pub fn held_lots(&self) -> Vec<(MaybeToken, Lot)> {
+        self.data
+            .held_lots()
+            .map(|(token, lot)| (token, lot.clone()))
+            .collect()
+    }
+
+    /// Lots in open orders and pending transfers, deposits and withdrawals
+    pub fn in_flight_lots(&self) -> Vec<(MaybeToken, Lot)> {
+        self.data
+            .in_flight_lots()
+            .map(|(token, lot)| (token, lot.clone()))
+            .collect()
+    }
+
     pub fn disposed_lots(&self) -> Vec<DisposedLot> {
         let mut disposed_lots = self.data.disposed_lots.clone();
         disposed_lots.sort_by_key(|lot| lot.when);
@@ -1716,6 +2777,10 @@ impl Db {
     }
 
     pub fn swap_lots(&mut self, lot_number1: usize, lot_number2: usize) -> DbResult<()> {
+        self.begin_operation(JournalOperation::SwapLots {
+            lot_number1,
+            lot_number2,
+        });
         self.auto_save(false)?;
 
         let mut disposed_lot = self
@@ -1806,7 +2871,7 @@ impl Db {
             disposed_lots.push(disposed_lot);
 
             self.data.disposed_lots = disposed_lots;
-            self.update_account(account2)?;
+            self.update_account_nested(account2)?;
         } else {
             if tracked_accounts.len() != 2 {
                 return Err(DbError::LotSwapFailed("Unknown lot".into()));
@@ -1849,14 +2914,15 @@ impl Db {
 
             account1.merge_or_add_lot(lot2);
             account2.merge_or_add_lot(lot1);
-            self.update_account(account1)?;
-            self.update_account(account2)?;
+            self.update_account_nested(account1)?;
+            self.update_account_nested(account2)?;
         }
 
         self.auto_save(true)
     }
 
     pub fn delete_lot(&mut self, lot_number: usize) -> DbResult<()> {
+        self.begin_operation(JournalOperation::DeleteLot { lot_number });
         let mut account = self
             .get_accounts()
             .into_iter()
@@ -1878,10 +2944,14 @@ impl Db {
         account.remove_lot(lot_number);
         account.last_update_balance -= lot.amount;
 
-        self.update_account(account)
+        self.update_account_nested(account)
     }
 
     pub fn move_lot(&mut self, lot_number: usize, to_address: Pubkey) -> DbResult<()> {
+        self.begin_operation(JournalOperation::MoveLot {
+            lot_number,
+            to_address,
+        });
         self.auto_save(false)?;
 
         let mut from_account = self
@@ -1931,13 +3001,14 @@ impl Db {
         from_account.last_update_balance -= lot.amount;
         to_account.merge_or_add_lot(lot);
 
-        self.update_account(to_account)?;
-        self.update_account(from_account)?;
+        self.update_account_nested(to_account)?;
+        self.update_account_nested(from_account)?;
 
         self.auto_save(true)
     }
 
     pub fn import_db(&mut self, other_db: Self) -> DbResult<()> {
+        self.begin_operation(JournalOperation::Import);
         if other_db.pending_deposits(None).len()
             + other_db.pending_swaps().len()
             + other_db.pending_withdrawals(None).len()
@@ -1956,7 +3027,7 @@ impl Db {
             for lot in other_account.lots.iter_mut() {
                 lot.lot_number = self.next_lot_number();
             }
-            self.add_account(other_account)?;
+            self.add_account_nested(other_account)?;
         }
 
         let mut disposed_lots = self.disposed_lots();
@@ -1970,4 +3041,347 @@ impl Db {
         self.auto_save(true)?;
         Ok(())
     }
+
+    /// Verify the internal consistency of the database, returning a description of each problem
+    /// found
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let account_exists = |address: Pubkey, token: MaybeToken| {
+            self.data
+                .accounts
+                .iter()
+                .any(|account| account.address == address && account.token == token)
+        };
+
+        let mut all_lots: Vec<(&Lot, String)> = vec![];
+        for account in &self.data.accounts {
+            let lot_balance: u64 = account.lots.iter().map(|lot| lot.amount).sum();
+            if lot_balance != account.last_update_balance {
+                problems.push(format!(
+                    "{} ({}): lot balance of {}{} does not match account balance of {}{}",
+                    account.address,
+                    account.token,
+                    account.token.symbol(),
+                    account.token.ui_amount(lot_balance),
+                    account.token.symbol(),
+                    account.token.ui_amount(account.last_update_balance),
+                ));
+            }
+            all_lots.extend(
+                account
+                    .lots
+                    .iter()
+                    .map(|lot| (lot, format!("{} ({})", account.address, account.token))),
+            );
+        }
+
+        for PendingDeposit {
+            exchange, transfer, ..
+        } in &self.data.pending_deposits
+        {
+            if !account_exists(transfer.from_address, transfer.from_token) {
+                problems.push(format!(
+                    "Pending {exchange} deposit {}: source account {} ({}) does not exist",
+                    transfer.signature, transfer.from_address, transfer.from_token
+                ));
+            }
+            all_lots.extend(
+                transfer
+                    .lots
+                    .iter()
+                    .map(|lot| (lot, format!("pending deposit {}", transfer.signature))),
+            );
+        }
+
+        for transfer in &self.data.pending_transfers {
+            for (address, token) in [
+                (transfer.from_address, transfer.from_token),
+                (transfer.to_address, transfer.to_token),
+            ] {
+                if !account_exists(address, token) {
+                    problems.push(format!(
+                        "Pending transfer {}: account {address} ({token}) does not exist",
+                        transfer.signature
+                    ));
+                }
+            }
+            all_lots.extend(
+                transfer
+                    .lots
+                    .iter()
+                    .map(|lot| (lot, format!("pending transfer {}", transfer.signature))),
+            );
+        }
+
+        for swap in &self.data.pending_swaps {
+            for token in [swap.from_token, swap.to_token] {
+                if !account_exists(swap.address, token) {
+                    problems.push(format!(
+                        "Pending swap {}: account {} ({token}) does not exist",
+                        swap.signature, swap.address
+                    ));
+                }
+            }
+        }
+
+        for withdrawal in &self.data.pending_withdrawals {
+            for address in [withdrawal.from_address, withdrawal.to_address] {
+                if !account_exists(address, withdrawal.token) {
+                    problems.push(format!(
+                        "Pending {} withdrawal {}: account {address} ({}) does not exist",
+                        withdrawal.exchange, withdrawal.tag, withdrawal.token
+                    ));
+                }
+            }
+            all_lots.extend(
+                withdrawal
+                    .lots
+                    .iter()
+                    .map(|lot| (lot, format!("pending withdrawal {}", withdrawal.tag))),
+            );
+        }
+
+        for order in &self.data.open_orders {
+            if !account_exists(order.deposit_address, order.token) {
+                problems.push(format!(
+                    "Open {} order {}: deposit account {} ({}) is not tracked",
+                    order.exchange, order.order_id, order.deposit_address, order.token
+                ));
+            }
+            all_lots.extend(
+                order
+                    .lots
+                    .iter()
+                    .map(|lot| (lot, format!("open order {}", order.order_id))),
+            );
+        }
+
+        for disposed_lot in &self.data.disposed_lots {
+            if disposed_lot.when < disposed_lot.lot.acquisition.when {
+                problems.push(format!(
+                    "Disposed lot {}: disposed on {} before it was acquired on {}",
+                    disposed_lot.lot.lot_number,
+                    disposed_lot.when,
+                    disposed_lot.lot.acquisition.when
+                ));
+            }
+            all_lots.push((&disposed_lot.lot, "disposed lots".into()));
+        }
+
+        let mut lot_locations = HashMap::<usize, &str>::default();
+        for (lot, location) in &all_lots {
+            if lot.lot_number >= self.data.next_lot_number {
+                problems.push(format!(
+                    "Lot {} in {location}: lot number is not less than the next lot number ({})",
+                    lot.lot_number, self.data.next_lot_number
+                ));
+            }
+            if let Some(other_location) = lot_locations.insert(lot.lot_number, location) {
+                problems.push(format!(
+                    "Lot {}: duplicate lot number in {other_location} and {location}",
+                    lot.lot_number
+                ));
+            }
+        }
+
+        if let Some(sweep_stake_account) = &self.data.sweep_stake_account {
+            if !account_exists(sweep_stake_account.address, MaybeToken::SOL()) {
+                problems.push(format!(
+                    "Sweep stake account {} is not tracked",
+                    sweep_stake_account.address
+                ));
+            }
+        }
+        for TransitorySweepStake { address } in &self.data.transitory_sweep_stake_accounts {
+            if !account_exists(*address, MaybeToken::SOL()) {
+                problems.push(format!(
+                    "Transitory sweep stake account {address} is not tracked"
+                ));
+            }
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_v0_to_v1() {
+        let v0 = serde_json::json!({
+            "next_lot_number": 3,
+            "accounts": [{
+                "address": "11111111111111111111111111111111",
+                "description": "test",
+                "last_update_epoch": 100,
+                "last_update_balance": 3000000000u64,
+                "lots": [
+                    {
+                        "lot_number": 0,
+                        "acquisition": {
+                            "when": "2021-01-01",
+                            "price": 1.5,
+                            "kind": {"EpochReward": {"epoch": 100, "slot": 200}},
+                        },
+                        "amount": 1000000000u64,
+                    },
+                    {
+                        "lot_number": 1,
+                        "acquisition": {
+                            "when": "2021-01-02",
+                            "price": null,
+                            "decimal_price": "2.25",
+                            "kind": {"EpochReward": {"epoch": 101, "slot": 300}},
+                        },
+                        "amount": 2000000000u64,
+                    },
+                ],
+                "no_sync": null,
+            }],
+            "open_orders": [],
+            "disposed_lots": [{
+                "lot": {
+                    "lot_number": 2,
+                    "acquisition": {
+                        "when": "2021-01-03",
+                        "price": 3.0,
+                        "kind": {"EpochReward": {"epoch": 102, "slot": 400}},
+                    },
+                    "amount": 1000000000u64,
+                },
+                "when": "2022-01-03",
+                "price": 4.0,
+                "kind": {"Other": {"description": "sold"}},
+            }],
+            "pending_deposits": [],
+            "pending_withdrawals": [],
+            "pending_transfers": [],
+            "pending_swaps": [],
+            "sweep_stake_account": null,
+            "transitory_sweep_stake_accounts": [],
+            "tax_rate": null,
+        });
+
+        let (data, migrated_from_version) = DbData::migrate(v0).unwrap();
+        assert_eq!(migrated_from_version, Some(0));
+        assert_eq!(data.version, DB_DATA_VERSION);
+
+        let lots = &data.accounts[0].lots;
+        assert_eq!(lots[0].acquisition.price(), Decimal::new(15, 1));
+        assert_eq!(lots[1].acquisition.price(), Decimal::new(225, 2));
+
+        let disposed_lot = &data.disposed_lots[0];
+        assert_eq!(disposed_lot.price(), Decimal::from(4));
+        assert_eq!(disposed_lot.lot.acquisition.price(), Decimal::from(3));
+        assert_eq!(
+            disposed_lot.kind,
+            LotDisposalKind::Other {
+                description: "sold".into()
+            }
+        );
+
+        // A version 1 database is left alone
+        let v1 = serde_json::to_value(&data).unwrap();
+        assert_eq!(DbData::migrate(v1).unwrap(), (data, None));
+    }
+
+    #[test]
+    fn test_migrate_v0_to_v1_missing_price() {
+        let v0 = serde_json::json!({
+            "next_lot_number": 1,
+            "accounts": [{
+                "address": "11111111111111111111111111111111",
+                "description": "test",
+                "last_update_epoch": 100,
+                "last_update_balance": 1000000000u64,
+                "lots": [{
+                    "lot_number": 0,
+                    "acquisition": {
+                        "when": "2021-01-01",
+                        "kind": {"EpochReward": {"epoch": 100, "slot": 200}},
+                    },
+                    "amount": 1000000000u64,
+                }],
+            }],
+        });
+
+        assert!(matches!(
+            DbData::migrate(v0),
+            Err(DbError::MigrationFailed(0, _))
+        ));
+    }
+
+    #[test]
+    fn test_modified_closed_year() {
+        let when = NaiveDate::from_ymd_opt(2022, 6, 1).unwrap();
+        let lot = |lot_number, amount| Lot {
+            lot_number,
+            acquisition: LotAcquistion::new(
+                when,
+                Decimal::from(10),
+                LotAcquistionKind::EpochReward {
+                    epoch: 300,
+                    slot: 1000,
+                },
+                None,
+            ),
+            amount,
+        };
+        let account = |address, lots: Vec<Lot>| TrackedAccount {
+            address,
+            token: MaybeToken::SOL(),
+            description: String::default(),
+            last_update_epoch: 0,
+            last_balance_scan_epoch: None,
+            last_update_balance: lots.iter().map(|lot| lot.amount).sum(),
+            lots,
+            no_sync: None,
+        };
+        let (address1, address2) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        let data = DbData {
+            accounts: vec![account(address1, vec![lot(0, 100)])],
+            closed_years: BTreeSet::from([2022]),
+            ..DbData::default()
+        };
+        assert_eq!(data.modified_closed_year(&data), None);
+
+        // Moving part of a lot to another account leaves the year unchanged
+        let mut moved = data.clone();
+        moved.accounts = vec![
+            account(address1, vec![lot(0, 60)]),
+            account(address2, vec![lot(0, 40)]),
+        ];
+        assert_eq!(moved.modified_closed_year(&data), None);
+
+        // As does disposing of it in a later year
+        let mut disposed = data.clone();
+        disposed.accounts[0].lots.clear();
+        disposed.disposed_lots.push(DisposedLot {
+            lot: lot(0, 100),
+            when: NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(),
+            decimal_price: Decimal::from(20),
+            kind: LotDisposalKind::Fiat,
+            token: MaybeToken::SOL(),
+        });
+        assert_eq!(disposed.modified_closed_year(&data), None);
+
+        // But not adding to it, disposing of it within the closed year or reopening the year
+        let mut added = data.clone();
+        added.accounts[0].lots.push(lot(1, 1));
+        assert_eq!(added.modified_closed_year(&data), Some(2022));
+
+        let mut disposed_in_closed_year = disposed.clone();
+        disposed_in_closed_year.disposed_lots[0].when = when;
+        assert_eq!(
+            disposed_in_closed_year.modified_closed_year(&data),
+            Some(2022)
+        );
+
+        let mut reopened = data.clone();
+        reopened.closed_years.clear();
+        assert_eq!(reopened.modified_closed_year(&data), Some(2022));
+    }
 }
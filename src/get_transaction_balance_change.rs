@@ -1,9 +1,22 @@
 use {
     chrono::prelude::*,
-    solana_client::rpc_client::RpcClient,
-    solana_sdk::{clock::Slot, pubkey::Pubkey, signature::Signature},
-    solana_transaction_status::UiTransactionEncoding,
-    std::str::FromStr,
+    solana_client::{
+        rpc_client::{GetConfirmedSignaturesForAddress2Config, RpcClient},
+        rpc_config::RpcTransactionConfig,
+    },
+    solana_sdk::{
+        account_utils::StateMut,
+        clock::{Epoch, Slot},
+        message::VersionedMessage,
+        pubkey,
+        pubkey::Pubkey,
+        signature::Signature,
+        stake::{instruction::StakeInstruction, state::StakeStateV2},
+    },
+    solana_transaction_status::{
+        UiLoadedAddresses, UiTransactionEncoding, UiTransactionTokenBalance,
+    },
+    std::{collections::HashMap, str::FromStr},
 };
 
 #[derive(Debug, Clone)]
@@ -93,3 +106,412 @@ pub fn get_transaction_balance_change(
         when,
     })
 }
+
+/// Jito tip distribution program, which pays out the MEV tips of an epoch to validators and stakers
+pub const JITO_TIP_DISTRIBUTION_PROGRAM_ID: Pubkey =
+    pubkey!("4R3gSG8BpU4t19KYj8CfnbtRpnT8gtk4dvTHxVRwc2r7");
+
+// The static account keys of `message` followed by the addresses loaded from lookup tables
+fn transaction_account_keys(
+    message: &VersionedMessage,
+    loaded_addresses: Option<UiLoadedAddresses>,
+) -> Result<Vec<Pubkey>, Box<dyn std::error::Error>> {
+    let mut account_keys = message.static_account_keys().to_vec();
+    if let Some(loaded_addresses) = loaded_addresses {
+        for loaded_address in loaded_addresses
+            .writable
+            .iter()
+            .chain(loaded_addresses.readonly.iter())
+        {
+            account_keys.push(Pubkey::from_str(loaded_address)?);
+        }
+    }
+    Ok(account_keys)
+}
+
+/// A successful transaction that increased the balance of an address
+#[derive(Debug, Clone)]
+pub struct TransactionInflow {
+    pub signature: Signature,
+    pub slot: Slot,
+    pub amount: u64,
+    // Addresses whose balance decreased in the transaction. For tokens, the token account owners
+    pub senders: Vec<Pubkey>,
+    // The Jito tip distribution account that MEV tips were claimed from, if any
+    pub tip_distribution_account: Option<Pubkey>,
+}
+
+/// The inflow into `address` from transaction `signature`, if any. When `mint` is provided
+/// `address` is the owner of the token account that received the inflow
+pub fn get_transaction_inflow(
+    rpc_client: &RpcClient,
+    signature: &Signature,
+    address: &Pubkey,
+    mint: Option<Pubkey>,
+) -> Result<Option<TransactionInflow>, Box<dyn std::error::Error>> {
+    let confirmed_transaction = rpc_client.get_transaction_with_config(
+        signature,
+        RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::Base64),
+            commitment: Some(rpc_client.commitment()),
+            max_supported_transaction_version: Some(0),
+        },
+    )?;
+
+    let slot = confirmed_transaction.slot;
+    let meta = confirmed_transaction
+        .transaction
+        .meta
+        .ok_or("Transaction metadata not available")?;
+    if meta.err.is_some() {
+        return Ok(None);
+    }
+
+    let mut tip_distribution_account = None;
+    let (amount, senders) = match mint {
+        None => {
+            let transaction = confirmed_transaction
+                .transaction
+                .transaction
+                .decode()
+                .ok_or("Unable to decode transaction")?;
+
+            let account_keys = transaction_account_keys(
+                &transaction.message,
+                Option::<UiLoadedAddresses>::from(meta.loaded_addresses),
+            )?;
+
+            // The fee paid by the first account is not a transfer
+            let diffs = meta
+                .pre_balances
+                .iter()
+                .zip(meta.post_balances.iter())
+                .enumerate()
+                .map(|(i, (pre_balance, post_balance))| {
+                    *post_balance as i64 - *pre_balance as i64
+                        + if i == 0 { meta.fee as i64 } else { 0 }
+                })
+                .collect::<Vec<_>>();
+
+            let Some(index) = account_keys.iter().position(|key| key == address) else {
+                return Ok(None);
+            };
+            tip_distribution_account = tip_distribution_claim(&transaction.message, &account_keys);
+            let senders = account_keys
+                .iter()
+                .zip(diffs.iter())
+                .filter(|(_key, diff)| **diff < 0)
+                .map(|(key, _diff)| *key)
+                .collect();
+            (diffs.get(index).copied().unwrap_or_default(), senders)
+        }
+        Some(mint) => {
+            let mint = mint.to_string();
+
+            // Token balance change of each owner
+            let mut diffs = HashMap::<String, i64>::default();
+            for (token_balances, sign) in [
+                (Option::<Vec<_>>::from(meta.pre_token_balances), -1),
+                (Option::<Vec<_>>::from(meta.post_token_balances), 1),
+            ] {
+                for token_balance in token_balances
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|token_balance: &UiTransactionTokenBalance| token_balance.mint == mint)
+                {
+                    if let Some(owner) = Option::<String>::from(token_balance.owner) {
+                        let amount = i64::from_str(&token_balance.ui_token_amount.amount)?;
+                        *diffs.entry(owner).or_default() += sign * amount;
+                    }
+                }
+            }
+
+            let senders = diffs
+                .iter()
+                .filter(|(_owner, diff)| **diff < 0)
+                .map(|(owner, _diff)| Pubkey::from_str(owner))
+                .collect::<Result<Vec<_>, _>>()?;
+            (
+                diffs.get(&address.to_string()).copied().unwrap_or_default(),
+                senders,
+            )
+        }
+    };
+
+    if amount <= 0 {
+        return Ok(None);
+    }
+    Ok(Some(TransactionInflow {
+        signature: *signature,
+        slot,
+        amount: amount as u64,
+        senders,
+        tip_distribution_account,
+    }))
+}
+
+// The tip distribution account claimed from, the second account of a claim instruction
+fn tip_distribution_claim(message: &VersionedMessage, account_keys: &[Pubkey]) -> Option<Pubkey> {
+    let instruction = message.instructions().iter().find(|instruction| {
+        account_keys.get(instruction.program_id_index as usize)
+            == Some(&JITO_TIP_DISTRIBUTION_PROGRAM_ID)
+    })?;
+    account_keys
+        .get(*instruction.accounts.get(1)? as usize)
+        .copied()
+}
+
+// Tip distribution accounts can be claimed from for this many epochs before they are closed
+const MAX_TIP_DISTRIBUTION_CLAIM_EPOCHS: Epoch = 10;
+
+// `TipDistributionAccount::epoch_created_at`, which follows an 8 byte discriminator, the
+// validator vote account, the merkle root upload authority and an optional merkle root
+fn tip_distribution_account_epoch(data: &[u8]) -> Option<Epoch> {
+    const MERKLE_ROOT_LEN: usize = 32 + 4 * 8;
+    let offset = match data.get(72)? {
+        0 => 73,
+        1 => 73 + MERKLE_ROOT_LEN,
+        _ => return None,
+    };
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// The epoch in which the MEV tips that `claimant` claimed in `claim_epoch` from
+/// `tip_distribution_account` were earned. Read from the tip distribution account or, once it
+/// has been closed, found by deriving the accounts of the validators `claimant` may have earned
+/// the tips from
+pub fn get_tip_distribution_epoch(
+    rpc_client: &RpcClient,
+    tip_distribution_account: &Pubkey,
+    claimant: &Pubkey,
+    claim_epoch: Epoch,
+) -> Result<Option<Epoch>, Box<dyn std::error::Error>> {
+    if let Some(account) = rpc_client
+        .get_account_with_commitment(tip_distribution_account, rpc_client.commitment())?
+        .value
+    {
+        if account.owner == JITO_TIP_DISTRIBUTION_PROGRAM_ID {
+            return Ok(tip_distribution_account_epoch(&account.data));
+        }
+    }
+
+    let vote_accounts = tip_distribution_vote_accounts(rpc_client, claimant)?;
+    for epoch in (claim_epoch.saturating_sub(MAX_TIP_DISTRIBUTION_CLAIM_EPOCHS)..claim_epoch).rev()
+    {
+        for vote_account in &vote_accounts {
+            if tip_distribution_account_address(vote_account, epoch) == *tip_distribution_account {
+                return Ok(Some(epoch));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// A validator claims its commission into its vote account, stakers into stake accounts delegated
+// to the validator
+fn tip_distribution_vote_accounts(
+    rpc_client: &RpcClient,
+    claimant: &Pubkey,
+) -> Result<Vec<Pubkey>, Box<dyn std::error::Error>> {
+    let mut vote_accounts = vec![*claimant];
+    if let Some(account) = rpc_client
+        .get_account_with_commitment(claimant, rpc_client.commitment())?
+        .value
+    {
+        if let Ok(StakeStateV2::Stake(_, stake, _)) = account.state() {
+            vote_accounts.push(stake.delegation.voter_pubkey);
+        }
+    }
+    Ok(vote_accounts)
+}
+
+fn tip_distribution_account_address(vote_account: &Pubkey, epoch: Epoch) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            b"TIP_DISTRIBUTION_ACCOUNT",
+            vote_account.as_ref(),
+            &epoch.to_le_bytes(),
+        ],
+        &JITO_TIP_DISTRIBUTION_PROGRAM_ID,
+    )
+    .0
+}
+
+fn claim_status_address(claimant: &Pubkey, tip_distribution_account: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            b"CLAIM_STATUS",
+            claimant.as_ref(),
+            tip_distribution_account.as_ref(),
+        ],
+        &JITO_TIP_DISTRIBUTION_PROGRAM_ID,
+    )
+    .0
+}
+
+/// The successful transactions since `min_slot` in which `claimant` claimed MEV tips, newest
+/// first. Claims are found through the claim status account of each tip distribution account
+/// that is still open, rather than by scanning the transactions of `claimant`
+pub fn get_tip_distribution_claims(
+    rpc_client: &RpcClient,
+    claimant: &Pubkey,
+    min_slot: Slot,
+) -> Result<Vec<Signature>, Box<dyn std::error::Error>> {
+    let epoch_schedule = rpc_client.get_epoch_schedule()?;
+    let current_epoch = rpc_client.get_epoch_info()?.epoch;
+    let first_epoch = epoch_schedule
+        .get_epoch(min_slot)
+        .saturating_sub(MAX_TIP_DISTRIBUTION_CLAIM_EPOCHS);
+
+    let tip_distribution_accounts = tip_distribution_vote_accounts(rpc_client, claimant)?
+        .iter()
+        .flat_map(|vote_account| {
+            (first_epoch..current_epoch)
+                .map(|epoch| tip_distribution_account_address(vote_account, epoch))
+        })
+        .collect::<Vec<_>>();
+
+    let mut claim_status_accounts = vec![];
+    for chunk in tip_distribution_accounts.chunks(100) {
+        for (tip_distribution_account, account) in
+            chunk.iter().zip(rpc_client.get_multiple_accounts(chunk)?)
+        {
+            if account.is_some() {
+                claim_status_accounts
+                    .push(claim_status_address(claimant, tip_distribution_account));
+            }
+        }
+    }
+
+    let mut claims = vec![];
+    for chunk in claim_status_accounts.chunks(100) {
+        for (claim_status_account, account) in
+            chunk.iter().zip(rpc_client.get_multiple_accounts(chunk)?)
+        {
+            if account.is_none() {
+                continue;
+            }
+            for confirmed_signature in rpc_client.get_signatures_for_address_with_config(
+                claim_status_account,
+                GetConfirmedSignaturesForAddress2Config {
+                    before: None,
+                    until: None,
+                    limit: None,
+                    commitment: Some(rpc_client.commitment()),
+                },
+            )? {
+                if confirmed_signature.err.is_none() && confirmed_signature.slot >= min_slot {
+                    claims.push((
+                        confirmed_signature.signature.parse::<Signature>()?,
+                        confirmed_signature.slot,
+                    ));
+                }
+            }
+        }
+    }
+    claims.sort_by_key(|(_signature, slot)| std::cmp::Reverse(*slot));
+    Ok(claims
+        .into_iter()
+        .map(|(signature, _slot)| signature)
+        .collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StakeOutflowKind {
+    Split,
+    Merge,
+}
+
+/// Stake moved out of a stake account by a split or merge
+#[derive(Debug, Clone)]
+pub struct StakeOutflow {
+    pub signature: Signature,
+    pub slot: Slot,
+    pub kind: StakeOutflowKind,
+    pub destination: Pubkey,
+    // For merges, the entire balance of the stake account before it was merged
+    pub amount: u64,
+}
+
+/// The splits and merges in transaction `signature` that moved stake out of `address`
+pub fn get_transaction_stake_outflows(
+    rpc_client: &RpcClient,
+    signature: &Signature,
+    address: &Pubkey,
+) -> Result<Vec<StakeOutflow>, Box<dyn std::error::Error>> {
+    let confirmed_transaction = rpc_client.get_transaction_with_config(
+        signature,
+        RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::Base64),
+            commitment: Some(rpc_client.commitment()),
+            max_supported_transaction_version: Some(0),
+        },
+    )?;
+
+    let slot = confirmed_transaction.slot;
+    let meta = confirmed_transaction
+        .transaction
+        .meta
+        .ok_or("Transaction metadata not available")?;
+    if meta.err.is_some() {
+        return Ok(vec![]);
+    }
+    let transaction = confirmed_transaction
+        .transaction
+        .transaction
+        .decode()
+        .ok_or("Unable to decode transaction")?;
+    let account_keys = transaction_account_keys(
+        &transaction.message,
+        Option::<UiLoadedAddresses>::from(meta.loaded_addresses),
+    )?;
+
+    let mut stake_outflows = vec![];
+    for instruction in transaction.message.instructions() {
+        if account_keys.get(instruction.program_id_index as usize)
+            != Some(&solana_sdk::stake::program::id())
+        {
+            continue;
+        }
+        let instruction_account = |i: usize| {
+            instruction
+                .accounts
+                .get(i)
+                .and_then(|index| account_keys.get(*index as usize))
+                .copied()
+        };
+
+        let stake_outflow = match bincode::deserialize::<StakeInstruction>(&instruction.data) {
+            // Accounts: [source stake, destination stake, stake authority]
+            Ok(StakeInstruction::Split(lamports)) if instruction_account(0) == Some(*address) => {
+                instruction_account(1)
+                    .map(|destination| (StakeOutflowKind::Split, destination, lamports))
+            }
+            // Accounts: [destination stake, source stake, clock, stake history, stake authority]
+            Ok(StakeInstruction::Merge) if instruction_account(1) == Some(*address) => {
+                let pre_balance = account_keys
+                    .iter()
+                    .position(|key| key == address)
+                    .and_then(|index| meta.pre_balances.get(index))
+                    .copied()
+                    .unwrap_or_default();
+                instruction_account(0)
+                    .map(|destination| (StakeOutflowKind::Merge, destination, pre_balance))
+            }
+            _ => None,
+        };
+
+        if let Some((kind, destination, amount)) = stake_outflow {
+            stake_outflows.push(StakeOutflow {
+                signature: *signature,
+                slot,
+                kind,
+                destination,
+                amount,
+            });
+        }
+    }
+    Ok(stake_outflows)
+}
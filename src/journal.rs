@@ -0,0 +1,473 @@
+use {
+    crate::{
+        exchange::Exchange, field_as_string, tax_jurisdiction::TaxJurisdiction, token::MaybeToken,
+    },
+    chrono::prelude::*,
+    serde::{Deserialize, Serialize},
+    solana_sdk::{
+        clock::{Epoch, Slot},
+        pubkey::Pubkey,
+        signature::Signature,
+    },
+    std::{
+        fmt, fs,
+        io::{self, BufRead, Write},
+        path::{Path, PathBuf},
+    },
+};
+
+// Number of most recent journal entries that retain a snapshot of the database, and can
+// therefore be undone
+pub const MAX_UNDO_SNAPSHOTS: usize = 32;
+
+/// A single `Db` mutation
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum JournalOperation {
+    RecordDeposit {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+        exchange: Exchange,
+    },
+    CancelDeposit {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+    },
+    ConfirmDeposit {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+    },
+    DropDeposit {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+    },
+    RecordSwap {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+        from_token: MaybeToken,
+        to_token: MaybeToken,
+    },
+    CancelSwap {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+    },
+    ConfirmSwap {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+    },
+    RecordWithdrawal {
+        exchange: Exchange,
+        tag: String,
+    },
+    CancelWithdrawal {
+        tag: String,
+    },
+    ConfirmWithdrawal {
+        tag: String,
+    },
+    OpenOrder {
+        exchange: Exchange,
+        order_id: String,
+    },
+    UpdateOrderPrice {
+        order_id: String,
+        price: f64,
+    },
+    CloseOrder {
+        order_id: String,
+    },
+    RecordDrop {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+        token: MaybeToken,
+        amount: u64,
+    },
+    RecordDisposal {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+        token: MaybeToken,
+        amount: u64,
+        description: String,
+    },
+    RecordTransactionFee {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+    },
+    AddAccount {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+        token: MaybeToken,
+    },
+    UpdateAccount {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+        token: MaybeToken,
+    },
+    SyncEpochRewards {
+        epoch: Epoch,
+    },
+    RemoveAccount {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+        token: MaybeToken,
+    },
+    SetSweepStakeAccount {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+    },
+    AddTransitorySweepStake {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+    },
+    RemoveTransitorySweepStake {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+    },
+    SetTaxRate,
+    SetTaxJurisdiction {
+        tax_jurisdiction: TaxJurisdiction,
+    },
+    SetFmvDiscountModel,
+    RecordTransfer {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+        #[serde(with = "field_as_string")]
+        from_address: Pubkey,
+        #[serde(with = "field_as_string")]
+        to_address: Pubkey,
+    },
+    CancelTransfer {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+    },
+    ConfirmTransfer {
+        #[serde(with = "field_as_string")]
+        signature: Signature,
+    },
+    SwapLots {
+        lot_number1: usize,
+        lot_number2: usize,
+    },
+    DeleteLot {
+        lot_number: usize,
+    },
+    MoveLot {
+        lot_number: usize,
+        #[serde(with = "field_as_string")]
+        to_address: Pubkey,
+    },
+    Import,
+    Undo {
+        count: usize,
+    },
+    CloseYear {
+        year: i32,
+    },
+    SetIdentityAccount {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+    },
+    RemoveIdentityAccount {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+    },
+    SyncBlockRewards {
+        #[serde(with = "field_as_string")]
+        address: Pubkey,
+        last_slot: Slot,
+    },
+    ClosedYearOverride {
+        year: i32,
+        operation: Box<JournalOperation>,
+    },
+    Other,
+}
+
+impl JournalOperation {
+    /// Operations that track something happening outside of the database, such as a
+    /// transaction that was submitted or an exchange order that was placed. Undoing these
+    /// leaves the database out of sync with the outside world
+    pub fn is_external(&self) -> bool {
+        if let JournalOperation::ClosedYearOverride { operation, .. } = self {
+            return operation.is_external();
+        }
+        matches!(
+            self,
+            JournalOperation::RecordDeposit { .. }
+                | JournalOperation::CancelDeposit { .. }
+                | JournalOperation::ConfirmDeposit { .. }
+                | JournalOperation::DropDeposit { .. }
+                | JournalOperation::RecordSwap { .. }
+                | JournalOperation::CancelSwap { .. }
+                | JournalOperation::ConfirmSwap { .. }
+                | JournalOperation::RecordWithdrawal { .. }
+                | JournalOperation::CancelWithdrawal { .. }
+                | JournalOperation::ConfirmWithdrawal { .. }
+                | JournalOperation::OpenOrder { .. }
+                | JournalOperation::UpdateOrderPrice { .. }
+                | JournalOperation::CloseOrder { .. }
+                | JournalOperation::RecordTransfer { .. }
+                | JournalOperation::CancelTransfer { .. }
+                | JournalOperation::ConfirmTransfer { .. }
+        )
+    }
+}
+
+impl fmt::Display for JournalOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JournalOperation::RecordDeposit {
+                signature,
+                exchange,
+            } => write!(f, "record {exchange} deposit {signature}"),
+            JournalOperation::CancelDeposit { signature } => {
+                write!(f, "cancel deposit {signature}")
+            }
+            JournalOperation::ConfirmDeposit { signature } => {
+                write!(f, "confirm deposit {signature}")
+            }
+            JournalOperation::DropDeposit { signature } => write!(f, "drop deposit {signature}"),
+            JournalOperation::RecordSwap {
+                signature,
+                from_token,
+                to_token,
+            } => write!(f, "record {from_token} to {to_token} swap {signature}"),
+            JournalOperation::CancelSwap { signature } => write!(f, "cancel swap {signature}"),
+            JournalOperation::ConfirmSwap { signature } => write!(f, "confirm swap {signature}"),
+            JournalOperation::RecordWithdrawal { exchange, tag } => {
+                write!(f, "record {exchange} withdrawal {tag}")
+            }
+            JournalOperation::CancelWithdrawal { tag } => write!(f, "cancel withdrawal {tag}"),
+            JournalOperation::ConfirmWithdrawal { tag } => write!(f, "confirm withdrawal {tag}"),
+            JournalOperation::OpenOrder { exchange, order_id } => {
+                write!(f, "open {exchange} order {order_id}")
+            }
+            JournalOperation::UpdateOrderPrice { order_id, price } => {
+                write!(f, "update order {order_id} price to ${price}")
+            }
+            JournalOperation::CloseOrder { order_id } => write!(f, "close order {order_id}"),
+            JournalOperation::RecordDrop {
+                address,
+                token,
+                amount,
+            } => write!(
+                f,
+                "drop {}{} from {address}",
+                token.symbol(),
+                token.ui_amount(*amount)
+            ),
+            JournalOperation::RecordDisposal {
+                address,
+                token,
+                amount,
+                description,
+            } => write!(
+                f,
+                "dispose {}{} from {address}: {description}",
+                token.symbol(),
+                token.ui_amount(*amount)
+            ),
+            JournalOperation::RecordTransactionFee { signature } => {
+                write!(f, "record transaction fee {signature}")
+            }
+            JournalOperation::AddAccount { address, token } => {
+                write!(f, "add account {address} ({token})")
+            }
+            JournalOperation::UpdateAccount { address, token } => {
+                write!(f, "update account {address} ({token})")
+            }
+            JournalOperation::SyncEpochRewards { epoch } => {
+                write!(f, "sync epoch rewards through epoch {epoch}")
+            }
+            JournalOperation::RemoveAccount { address, token } => {
+                write!(f, "remove account {address} ({token})")
+            }
+            JournalOperation::SetSweepStakeAccount { address } => {
+                write!(f, "set sweep stake account {address}")
+            }
+            JournalOperation::AddTransitorySweepStake { address } => {
+                write!(f, "add transitory sweep stake {address}")
+            }
+            JournalOperation::RemoveTransitorySweepStake { address } => {
+                write!(f, "remove transitory sweep stake {address}")
+            }
+            JournalOperation::SetTaxRate => write!(f, "set tax rate"),
+            JournalOperation::SetTaxJurisdiction { tax_jurisdiction } => {
+                write!(f, "set tax jurisdiction to {tax_jurisdiction}")
+            }
+            JournalOperation::SetFmvDiscountModel => write!(f, "set FMV discount model"),
+            JournalOperation::RecordTransfer {
+                signature,
+                from_address,
+                to_address,
+            } => write!(
+                f,
+                "record transfer from {from_address} to {to_address} {signature}"
+            ),
+            JournalOperation::CancelTransfer { signature } => {
+                write!(f, "cancel transfer {signature}")
+            }
+            JournalOperation::ConfirmTransfer { signature } => {
+                write!(f, "confirm transfer {signature}")
+            }
+            JournalOperation::SwapLots {
+                lot_number1,
+                lot_number2,
+            } => write!(f, "swap lots {lot_number1} and {lot_number2}"),
+            JournalOperation::DeleteLot { lot_number } => write!(f, "delete lot {lot_number}"),
+            JournalOperation::MoveLot {
+                lot_number,
+                to_address,
+            } => write!(f, "move lot {lot_number} to {to_address}"),
+            JournalOperation::Import => write!(f, "import database"),
+            JournalOperation::Undo { count } => write!(f, "undo {count} operation(s)"),
+            JournalOperation::CloseYear { year } => write!(f, "close tax year {year}"),
+            JournalOperation::SetIdentityAccount { address } => {
+                write!(f, "track block rewards of {address}")
+            }
+            JournalOperation::RemoveIdentityAccount { address } => {
+                write!(f, "stop tracking block rewards of {address}")
+            }
+            JournalOperation::SyncBlockRewards { address, last_slot } => {
+                write!(
+                    f,
+                    "sync block rewards of {address} through slot {last_slot}"
+                )
+            }
+            JournalOperation::ClosedYearOverride { year, operation } => {
+                write!(f, "{operation} (overriding closed tax year {year})")
+            }
+            JournalOperation::Other => write!(f, "other"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    pub command: String,
+    pub operation: JournalOperation,
+}
+
+/// Append-only log of `Db` mutations, stored as one JSON entry per line in `journal.jsonl`.
+///
+/// The database contents before each of the last `MAX_UNDO_SNAPSHOTS` entries are kept in
+/// `journal/<id>.json` so those operations can be undone.
+pub struct Journal {
+    filename: PathBuf,
+    snapshot_dir: PathBuf,
+    command: String,
+    last_id: Option<u64>,
+}
+
+impl Journal {
+    pub fn open(db_path: &Path) -> io::Result<Self> {
+        let mut journal = Self {
+            filename: db_path.join("journal.jsonl"),
+            snapshot_dir: db_path.join("journal"),
+            command: std::env::args().collect::<Vec<_>>().join(" "),
+            last_id: None,
+        };
+        journal.last_id = journal.read_last_id()?;
+        Ok(journal)
+    }
+
+    fn snapshot_filename(&self, id: u64) -> PathBuf {
+        self.snapshot_dir.join(format!("{id}.json"))
+    }
+
+    /// Record `operation`, with `snapshot` being the serialized database contents prior to the
+    /// operation
+    pub fn append(
+        &mut self,
+        operation: JournalOperation,
+        snapshot: &str,
+    ) -> io::Result<JournalEntry> {
+        let timestamp = Utc::now();
+        let id = self
+            .last_id
+            .map(|last_id| last_id + 1)
+            .unwrap_or_default()
+            .max(timestamp.timestamp_micros() as u64);
+
+        fs::create_dir_all(&self.snapshot_dir)?;
+        fs::write(self.snapshot_filename(id), snapshot)?;
+
+        let entry = JournalEntry {
+            id,
+            timestamp,
+            command: self.command.clone(),
+            operation,
+        };
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.filename)?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        self.last_id = Some(id);
+
+        self.prune_snapshots()?;
+        Ok(entry)
+    }
+
+    // Only the last entry is parsed
+    fn read_last_id(&self) -> io::Result<Option<u64>> {
+        if !self.filename.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&self.filename)?;
+        match contents.lines().rev().find(|line| !line.trim().is_empty()) {
+            Some(line) => serde_json::from_str::<JournalEntry>(line)
+                .map(|entry| Some(entry.id))
+                .map_err(|err| io::Error::other(format!("Invalid journal entry: {err:?}"))),
+            None => Ok(None),
+        }
+    }
+
+    fn prune_snapshots(&self) -> io::Result<()> {
+        let mut ids = fs::read_dir(&self.snapshot_dir)?
+            .filter_map(|dir_entry| {
+                dir_entry
+                    .ok()?
+                    .path()
+                    .file_stem()?
+                    .to_str()?
+                    .parse::<u64>()
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        for id in ids.iter().rev().skip(MAX_UNDO_SNAPSHOTS) {
+            fs::remove_file(self.snapshot_filename(*id))?;
+        }
+        Ok(())
+    }
+
+    /// All journal entries, oldest first
+    pub fn entries(&self) -> io::Result<Vec<JournalEntry>> {
+        if !self.filename.exists() {
+            return Ok(vec![]);
+        }
+
+        io::BufReader::new(fs::File::open(&self.filename)?)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|line| {
+                serde_json::from_str(&line?)
+                    .map_err(|err| io::Error::other(format!("Invalid journal entry: {err:?}")))
+            })
+            .collect()
+    }
+
+    /// The database contents prior to journal entry `id`, if still available
+    pub fn snapshot(&self, id: u64) -> io::Result<Option<String>> {
+        let snapshot_filename = self.snapshot_filename(id);
+        if snapshot_filename.exists() {
+            fs::read_to_string(snapshot_filename).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
@@ -1,15 +1,65 @@
 use {
-    crate::{exchange::*, token::MaybeToken},
+    crate::{
+        exchange::*,
+        token::{MaybeToken, Token},
+    },
     async_trait::async_trait,
+    base64::{engine::general_purpose::STANDARD as BASE64, Engine},
     chrono::prelude::*,
+    hmac::{Hmac, Mac},
     kraken_sdk_rest::Client,
     rust_decimal::prelude::*,
+    serde::{de::DeserializeOwned, Deserialize},
+    sha2::{Digest, Sha256, Sha512},
     solana_sdk::pubkey::Pubkey,
-    std::collections::HashMap,
+    std::{
+        collections::HashMap,
+        time::{SystemTime, UNIX_EPOCH},
+    },
 };
 
+const API_URL: &str = "https://api.kraken.com";
+
 pub struct KrakenExchangeClient {
     client: Client,
+    http: reqwest::Client,
+    api_key: String,
+    secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KrakenResponse<T> {
+    error: Vec<String>,
+    result: Option<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WithdrawAddress {
+    address: String,
+    key: String,
+    verified: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct WithdrawInfo {
+    limit: String,
+    fee: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Withdraw {
+    refid: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WithdrawStatus {
+    refid: String,
+    txid: Option<String>,
+    info: String,
+    amount: String,
+    status: String,
+    #[serde(rename = "status-prop")]
+    status_prop: Option<String>,
 }
 
 fn normalize_coin_name(kraken_coin: &str) -> &str {
@@ -28,6 +78,63 @@ fn deposit_methods() -> HashMap</*coin: */ &'static str, /* method: */ &'static
     ])
 }
 
+fn token_of(kraken_coin: &str) -> Option<MaybeToken> {
+    if kraken_coin == "SOL" {
+        Some(MaybeToken::SOL())
+    } else {
+        kraken_coin.parse::<Token>().ok().map(|token| token.into())
+    }
+}
+
+impl KrakenExchangeClient {
+    async fn private_request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[(&str, &str)],
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let path = format!("/0/private/{method}");
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            .to_string();
+
+        let mut form = vec![("nonce", nonce.as_str())];
+        form.extend_from_slice(params);
+        let post_data = serde_urlencoded::to_string(&form)?;
+
+        // API-Sign = HMAC-SHA512 of (URI path + SHA256(nonce + POST data)) using the base64
+        // decoded secret
+        let mut message = path.as_bytes().to_vec();
+        message.extend(Sha256::digest(format!("{nonce}{post_data}").as_bytes()));
+        let mut mac = Hmac::<Sha512>::new_from_slice(&BASE64.decode(&self.secret)?)
+            .expect("HMAC can take key of any size");
+        mac.update(&message);
+        let signature = BASE64.encode(mac.finalize().into_bytes());
+
+        let response = self
+            .http
+            .post(format!("{API_URL}{path}"))
+            .header("API-Key", &self.api_key)
+            .header("API-Sign", signature)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(post_data)
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        let response = serde_json::from_str::<KrakenResponse<T>>(&response)
+            .map_err(|err| format!("Invalid {method} response: {err}: {response}"))?;
+        if !response.error.is_empty() {
+            return Err(format!("{method} failed: {}", response.error.join(", ")).into());
+        }
+        response
+            .result
+            .ok_or_else(|| format!("{method} returned no result").into())
+    }
+}
+
 #[async_trait]
 impl ExchangeClient for KrakenExchangeClient {
     async fn deposit_address(
@@ -137,20 +244,105 @@ impl ExchangeClient for KrakenExchangeClient {
     }
 
     async fn recent_withdrawals(&self) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
-        // Withdrawals not currently supported for Kraken
-        Ok(vec![])
+        let mut withdrawals = vec![];
+
+        for coin in deposit_methods().keys() {
+            let token = token_of(coin).ok_or_else(|| format!("Unknown coin: {coin}"))?;
+
+            for withdraw_status in self
+                .private_request::<Vec<WithdrawStatus>>("WithdrawStatus", &[("asset", coin)])
+                .await?
+            {
+                //dbg!(&withdraw_status);
+                let cancelled = withdraw_status.status == "Failure"
+                    || withdraw_status.status_prop.as_deref() == Some("canceled");
+
+                let (completed, tx_id) = if cancelled {
+                    (true, None)
+                } else if withdraw_status.status == "Success" {
+                    (true, Some(withdraw_status.txid.expect("transaction id")))
+                } else {
+                    (false, None)
+                };
+
+                withdrawals.push(WithdrawalInfo {
+                    address: withdraw_status.info.parse::<Pubkey>().unwrap_or_default(),
+                    token,
+                    amount: withdraw_status.amount.parse::<f64>().unwrap_or_default(),
+                    tag: withdraw_status.refid,
+                    completed,
+                    tx_id,
+                });
+            }
+        }
+        Ok(withdrawals)
     }
 
     async fn request_withdraw(
         &self,
-        _address: Pubkey,
-        _token: MaybeToken,
-        _amount: f64,
+        address: Pubkey,
+        token: MaybeToken,
+        amount: f64,
         _password: Option<String>,
         _code: Option<String>,
     ) -> Result<(/* withdraw_id: */ String, /*withdraw_fee: */ f64), Box<dyn std::error::Error>>
     {
-        Err("Withdrawals not currently supported for Kraken".into())
+        let asset = token.to_string();
+        let method = *deposit_methods()
+            .get(token.name())
+            .ok_or_else(|| format!("Unsupported withdrawal token: {}", token.name()))?;
+
+        // Kraken only withdraws to addresses that have been pre-registered as a withdrawal key
+        // through the web interface
+        let withdraw_addresses = self
+            .private_request::<Vec<WithdrawAddress>>(
+                "WithdrawAddresses",
+                &[("asset", &asset), ("method", method)],
+            )
+            .await?;
+        let withdraw_address = withdraw_addresses
+            .into_iter()
+            .find(|withdraw_address| withdraw_address.address == address.to_string())
+            .ok_or_else(|| format!("No Kraken withdrawal key found for {address}"))?;
+        if !withdraw_address.verified {
+            return Err(format!(
+                "Kraken withdrawal key \"{}\" for {address} is not verified",
+                withdraw_address.key
+            )
+            .into());
+        }
+
+        let amount = amount.to_string();
+        let withdraw_info = self
+            .private_request::<WithdrawInfo>(
+                "WithdrawInfo",
+                &[
+                    ("asset", &asset),
+                    ("key", &withdraw_address.key),
+                    ("amount", &amount),
+                ],
+            )
+            .await?;
+
+        let limit = withdraw_info.limit.parse::<f64>()?;
+        if amount.parse::<f64>()? > limit {
+            return Err(format!("Withdrawal request exceeds the limit of {limit} {asset}").into());
+        }
+        let withdraw_fee = withdraw_info.fee.parse::<f64>()?;
+
+        let withdraw = self
+            .private_request::<Withdraw>(
+                "Withdraw",
+                &[
+                    ("asset", &asset),
+                    ("key", &withdraw_address.key),
+                    ("amount", &amount),
+                    ("address", &withdraw_address.address),
+                ],
+            )
+            .await?;
+
+        Ok((withdraw.refid, withdraw_fee))
     }
 
     async fn print_market_info(
@@ -404,5 +596,8 @@ pub fn new(
 
     Ok(KrakenExchangeClient {
         client: Client::new(&api_key, &secret),
+        http: reqwest::Client::builder().user_agent("sys").build()?,
+        api_key,
+        secret,
     })
 }
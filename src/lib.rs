@@ -15,16 +15,23 @@ pub mod amount;
 pub mod binance_exchange;
 pub mod coin_gecko;
 pub mod coinbase_exchange;
+pub mod credentials_db;
 pub mod db;
 pub mod exchange;
 pub mod field_as_string;
 pub mod get_transaction_balance_change;
 pub mod helius_rpc;
+pub mod journal;
 pub mod kraken_exchange;
 pub mod metrics;
 pub mod notifier;
+pub mod price_cache;
+pub mod price_source;
 pub mod priority_fee;
+pub mod pyth;
 pub mod rpc_client_utils;
+pub mod tax_export;
+pub mod tax_jurisdiction;
 pub mod token;
 pub mod vendor;
 
@@ -34,6 +34,10 @@ pub async fn push(point: Point) {
     POINTS.write().await.push(point);
 }
 
+pub async fn has_points() -> bool {
+    !POINTS.read().await.is_empty()
+}
+
 pub async fn send(config: Option<MetricsConfig>) {
     if let Some(config) = config {
         let client = Client::new(config.url, config.token)
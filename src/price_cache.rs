@@ -0,0 +1,180 @@
+use {
+    crate::{price_source::PriceSourceKind, token::MaybeToken},
+    chrono::prelude::*,
+    rust_decimal::prelude::*,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        fs, io,
+        path::{Path, PathBuf},
+        sync::RwLock,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PriceCacheEntry {
+    pub when: NaiveDate,
+    pub token: MaybeToken,
+    pub source: PriceSourceKind,
+    pub price: Decimal,
+    #[serde(default)]
+    pub overridden: bool, // Manually set, never replaced by the price source
+}
+
+type PriceCacheKey = (NaiveDate, MaybeToken, PriceSourceKind);
+
+#[derive(Default, Serialize, Deserialize)]
+struct PriceCacheData {
+    entries: Vec<PriceCacheEntry>,
+}
+
+/// Historical prices by (date, token, source), persisted in `price_cache.json` within the
+/// database directory
+struct PriceCache {
+    filename: PathBuf,
+    entries: BTreeMap<PriceCacheKey, PriceCacheEntry>,
+}
+
+impl PriceCache {
+    fn load(filename: PathBuf) -> io::Result<Self> {
+        let entries = if filename.exists() {
+            let data: PriceCacheData = serde_json::from_str(&fs::read_to_string(&filename)?)
+                .map_err(|err| io::Error::other(format!("JSON parse failed: {err:?}")))?;
+            data.entries
+                .into_iter()
+                .map(|entry| ((entry.when, entry.token, entry.source), entry))
+                .collect()
+        } else {
+            BTreeMap::default()
+        };
+        Ok(Self { filename, entries })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let data = PriceCacheData {
+            entries: self.entries.values().cloned().collect(),
+        };
+        let bytes = serde_json::to_string_pretty(&data)?.into_bytes();
+
+        let temp_filename = format!(
+            "{}.temp.{}",
+            self.filename.display(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        );
+        fs::write(&temp_filename, bytes)?;
+        fs::rename(temp_filename, &self.filename)?;
+        Ok(())
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref PRICE_CACHE: RwLock<Option<PriceCache>> = RwLock::new(None);
+}
+
+/// Enable the persistent price cache, stored in `db_path`. Until this is called prices are not
+/// cached across invocations
+pub fn open<P: AsRef<Path>>(db_path: P) -> io::Result<()> {
+    let price_cache = PriceCache::load(db_path.as_ref().join("price_cache.json"))?;
+    *PRICE_CACHE.write().unwrap() = Some(price_cache);
+    Ok(())
+}
+
+pub fn get(when: NaiveDate, token: &MaybeToken, source: PriceSourceKind) -> Option<Decimal> {
+    PRICE_CACHE
+        .read()
+        .unwrap()
+        .as_ref()?
+        .entries
+        .get(&(when, *token, source))
+        .map(|entry| entry.price)
+}
+
+/// Cache a price obtained from `source`. Overridden entries are left untouched
+pub fn insert(
+    when: NaiveDate,
+    token: &MaybeToken,
+    source: PriceSourceKind,
+    price: Decimal,
+) -> io::Result<()> {
+    let mut price_cache = PRICE_CACHE.write().unwrap();
+    let Some(price_cache) = price_cache.as_mut() else {
+        return Ok(());
+    };
+
+    let key = (when, *token, source);
+    if price_cache
+        .entries
+        .get(&key)
+        .is_some_and(|entry| entry.overridden)
+    {
+        return Ok(());
+    }
+    price_cache.entries.insert(
+        key,
+        PriceCacheEntry {
+            when,
+            token: *token,
+            source,
+            price,
+            overridden: false,
+        },
+    );
+    price_cache.save()
+}
+
+/// Manually set the price for a day, replacing whatever `source` provided
+pub fn set_override(
+    when: NaiveDate,
+    token: &MaybeToken,
+    source: PriceSourceKind,
+    price: Decimal,
+) -> io::Result<()> {
+    let mut price_cache = PRICE_CACHE.write().unwrap();
+    let price_cache = price_cache
+        .as_mut()
+        .ok_or_else(|| io::Error::other("Price cache not open"))?;
+
+    price_cache.entries.insert(
+        (when, *token, source),
+        PriceCacheEntry {
+            when,
+            token: *token,
+            source,
+            price,
+            overridden: true,
+        },
+    );
+    price_cache.save()
+}
+
+/// Remove a cached price, returning `false` if there was no such entry
+pub fn remove(when: NaiveDate, token: &MaybeToken, source: PriceSourceKind) -> io::Result<bool> {
+    let mut price_cache = PRICE_CACHE.write().unwrap();
+    let price_cache = price_cache
+        .as_mut()
+        .ok_or_else(|| io::Error::other("Price cache not open"))?;
+
+    if price_cache
+        .entries
+        .remove(&(when, *token, source))
+        .is_none()
+    {
+        return Ok(false);
+    }
+    price_cache.save()?;
+    Ok(true)
+}
+
+/// All cached prices, ordered by date
+pub fn entries() -> Vec<PriceCacheEntry> {
+    PRICE_CACHE
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|price_cache| price_cache.entries.values().cloned().collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,229 @@
+use {
+    crate::{coin_gecko, price_cache, pyth, token::MaybeToken},
+    async_trait::async_trait,
+    chrono::prelude::*,
+    rust_decimal::prelude::*,
+    serde::{Deserialize, Serialize},
+    solana_client::rpc_client::RpcClient,
+    std::{collections::HashMap, env, sync::RwLock},
+    strum::{EnumString, IntoStaticStr},
+};
+
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    EnumString,
+    IntoStaticStr,
+)]
+pub enum PriceSourceKind {
+    #[strum(serialize = "coingecko")]
+    CoinGecko,
+    #[strum(serialize = "pyth")]
+    Pyth,
+    #[strum(serialize = "jupiter")]
+    Jupiter,
+}
+
+pub const POSSIBLE_PRICE_SOURCE_VALUES: &[&str] = &["coingecko", "pyth", "jupiter"];
+
+impl std::fmt::Display for PriceSourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name: &'static str = self.into();
+        write!(f, "{name}")
+    }
+}
+
+impl PriceSourceKind {
+    pub fn price_source(&self) -> Box<dyn PriceSource> {
+        match self {
+            PriceSourceKind::CoinGecko => Box::new(CoinGeckoPriceSource),
+            PriceSourceKind::Pyth => Box::new(PythPriceSource),
+            PriceSourceKind::Jupiter => Box::new(JupiterPriceSource),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+pub trait PriceSource {
+    async fn get_current_price(
+        &self,
+        rpc_client: &RpcClient,
+        token: &MaybeToken,
+    ) -> Result<Decimal, Box<dyn std::error::Error>>;
+
+    async fn get_historical_price(
+        &self,
+        rpc_client: &RpcClient,
+        when: NaiveDate,
+        token: &MaybeToken,
+    ) -> Result<Decimal, Box<dyn std::error::Error>>;
+}
+
+pub struct CoinGeckoPriceSource;
+
+#[async_trait(?Send)]
+impl PriceSource for CoinGeckoPriceSource {
+    async fn get_current_price(
+        &self,
+        _rpc_client: &RpcClient,
+        token: &MaybeToken,
+    ) -> Result<Decimal, Box<dyn std::error::Error>> {
+        coin_gecko::get_current_price(token).await
+    }
+
+    async fn get_historical_price(
+        &self,
+        _rpc_client: &RpcClient,
+        when: NaiveDate,
+        token: &MaybeToken,
+    ) -> Result<Decimal, Box<dyn std::error::Error>> {
+        coin_gecko::get_historical_price(when, token).await
+    }
+}
+
+pub struct PythPriceSource;
+
+#[async_trait(?Send)]
+impl PriceSource for PythPriceSource {
+    async fn get_current_price(
+        &self,
+        rpc_client: &RpcClient,
+        token: &MaybeToken,
+    ) -> Result<Decimal, Box<dyn std::error::Error>> {
+        pyth::get_current_price(rpc_client, token)
+    }
+
+    async fn get_historical_price(
+        &self,
+        _rpc_client: &RpcClient,
+        _when: NaiveDate,
+        token: &MaybeToken,
+    ) -> Result<Decimal, Box<dyn std::error::Error>> {
+        Err(format!("Pyth historical price data not available for {token}").into())
+    }
+}
+
+pub struct JupiterPriceSource;
+
+#[async_trait(?Send)]
+impl PriceSource for JupiterPriceSource {
+    async fn get_current_price(
+        &self,
+        _rpc_client: &RpcClient,
+        token: &MaybeToken,
+    ) -> Result<Decimal, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Price {
+            usd_price: f64,
+        }
+
+        let mint = token.mint().to_string();
+        let client = reqwest::Client::builder().user_agent("sys").build()?;
+        let request = match env::var("JUP_API_KEY") {
+            Ok(jup_api_key) => client
+                .get("https://api.jup.ag/price/v3")
+                .header("x-api-key", jup_api_key),
+            Err(_) => client.get("https://lite-api.jup.ag/price/v3"),
+        };
+
+        let prices = request
+            .query(&[("ids", &mint)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<HashMap<String, Price>>()
+            .await?;
+
+        prices
+            .get(&mint)
+            .and_then(|price| Decimal::from_f64(price.usd_price))
+            .ok_or_else(|| format!("Jupiter price data not available for {token}").into())
+    }
+
+    async fn get_historical_price(
+        &self,
+        _rpc_client: &RpcClient,
+        _when: NaiveDate,
+        token: &MaybeToken,
+    ) -> Result<Decimal, Box<dyn std::error::Error>> {
+        Err(format!("Jupiter historical price data not available for {token}").into())
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref PRICE_SOURCES: RwLock<Vec<PriceSourceKind>> = RwLock::new(vec![
+        PriceSourceKind::CoinGecko,
+        PriceSourceKind::Pyth,
+        PriceSourceKind::Jupiter,
+    ]);
+}
+
+/// Set the order in which price sources are consulted, the first to produce a price wins
+pub fn set_price_sources(price_sources: Vec<PriceSourceKind>) {
+    assert!(!price_sources.is_empty());
+    *PRICE_SOURCES.write().unwrap() = price_sources;
+}
+
+pub fn price_sources() -> Vec<PriceSourceKind> {
+    PRICE_SOURCES.read().unwrap().clone()
+}
+
+pub async fn get_current_price(
+    rpc_client: &RpcClient,
+    token: &MaybeToken,
+) -> Result<(Decimal, PriceSourceKind), Box<dyn std::error::Error>> {
+    let mut errors = vec![];
+    for kind in price_sources() {
+        match kind
+            .price_source()
+            .get_current_price(rpc_client, token)
+            .await
+        {
+            Ok(price) => return Ok((price, kind)),
+            Err(err) => errors.push(format!("{kind}: {err}")),
+        }
+    }
+    Err(format!(
+        "Current price not available for {token}: {}",
+        errors.join(", ")
+    )
+    .into())
+}
+
+pub async fn get_historical_price(
+    rpc_client: &RpcClient,
+    when: NaiveDate,
+    token: &MaybeToken,
+) -> Result<(Decimal, PriceSourceKind), Box<dyn std::error::Error>> {
+    let mut errors = vec![];
+    for kind in price_sources() {
+        if let Some(price) = price_cache::get(when, token, kind) {
+            return Ok((price, kind));
+        }
+        match kind
+            .price_source()
+            .get_historical_price(rpc_client, when, token)
+            .await
+        {
+            Ok(price) => {
+                price_cache::insert(when, token, kind, price)?;
+                return Ok((price, kind));
+            }
+            Err(err) => errors.push(format!("{kind}: {err}")),
+        }
+    }
+    Err(format!(
+        "Historical price not available for {token} on {when}: {}",
+        errors.join(", ")
+    )
+    .into())
+}
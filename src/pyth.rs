@@ -0,0 +1,101 @@
+use {
+    crate::token::{MaybeToken, Token},
+    rust_decimal::prelude::*,
+    solana_client::rpc_client::RpcClient,
+    solana_sdk::{pubkey, pubkey::Pubkey},
+    std::time::{SystemTime, UNIX_EPOCH},
+};
+
+// Pyth Solana Receiver program, the owner of all push oracle price update accounts
+const PYTH_RECEIVER_PROGRAM_ID: Pubkey = pubkey!("rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ");
+
+// Prices older than this are considered stale
+const MAX_PRICE_AGE_SECS: i64 = 300;
+
+/// Sponsored (shard 0) Pyth push oracle price feed account for `token`, priced in USD
+fn price_feed_account(token: &MaybeToken) -> Option<Pubkey> {
+    match token.token() {
+        None | Some(Token::wSOL) => Some(pubkey!("7UVimffxr9ow1uXYxsr4LHAcV58mLzhmwaeKvJ1pjLiE")),
+        Some(Token::USDC) => Some(pubkey!("Dpw1EAVrSB1ibxiDQyTAW6Zip3J4Btk2x4SgApQCeFbX")),
+        Some(Token::USDT) => Some(pubkey!("HT2PLQBcG5EiCcNSaMHAjSgd9F98ecpATbk4Sk5oYuM")),
+        Some(_) => None,
+    }
+}
+
+struct PriceMessage {
+    price: i64,
+    exponent: i32,
+    publish_time: i64,
+}
+
+// Decode a `PriceUpdateV2` account:
+//   discriminator: [u8; 8]
+//   write_authority: Pubkey
+//   verification_level: enum { Partial { num_signatures: u8 }, Full }
+//   price_message: { feed_id: [u8; 32], price: i64, conf: u64, exponent: i32, publish_time: i64, .. }
+//   posted_slot: u64
+fn decode_price_update(data: &[u8]) -> Result<PriceMessage, Box<dyn std::error::Error>> {
+    let read = |offset: usize, len: usize| {
+        data.get(offset..offset + len)
+            .ok_or_else(|| "Price update account data too short".to_string())
+    };
+
+    let mut offset = 8 + 32;
+    match read(offset, 1)?[0] {
+        0 => return Err("Price update is only partially verified".into()),
+        1 => offset += 1,
+        verification_level => {
+            return Err(format!("Unknown verification level: {verification_level}").into())
+        }
+    }
+    offset += 32; // feed_id
+
+    let price = i64::from_le_bytes(read(offset, 8)?.try_into().unwrap());
+    offset += 8 + 8; // price, conf
+    let exponent = i32::from_le_bytes(read(offset, 4)?.try_into().unwrap());
+    offset += 4;
+    let publish_time = i64::from_le_bytes(read(offset, 8)?.try_into().unwrap());
+
+    Ok(PriceMessage {
+        price,
+        exponent,
+        publish_time,
+    })
+}
+
+pub fn get_current_price(
+    rpc_client: &RpcClient,
+    token: &MaybeToken,
+) -> Result<Decimal, Box<dyn std::error::Error>> {
+    let address = price_feed_account(token)
+        .ok_or_else(|| format!("Pyth price feed not available for {token}"))?;
+
+    let account = rpc_client.get_account(&address)?;
+    if account.owner != PYTH_RECEIVER_PROGRAM_ID {
+        return Err(format!("{address} is not a Pyth price update account").into());
+    }
+
+    let PriceMessage {
+        price,
+        exponent,
+        publish_time,
+    } = decode_price_update(&account.data)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    if now.saturating_sub(publish_time) > MAX_PRICE_AGE_SECS {
+        return Err(format!("Pyth price for {token} is stale").into());
+    }
+
+    let price = if exponent < 0 {
+        Decimal::try_new(price, exponent.unsigned_abs())?
+    } else {
+        Decimal::from(price) * Decimal::from(10i64.pow(exponent as u32))
+    };
+    if price <= Decimal::ZERO {
+        return Err(format!("Invalid Pyth price for {token}: {price}").into());
+    }
+    Ok(price)
+}
@@ -1,14 +1,25 @@
 use {
     chrono::prelude::*,
-    solana_client::rpc_client::RpcClient,
+    solana_client::{
+        client_error::ClientErrorKind,
+        rpc_client::RpcClient,
+        rpc_config::{RpcBlockConfig, RpcLeaderScheduleConfig, RpcTransactionConfig},
+        rpc_request::RpcError,
+        rpc_response::RpcInflationReward,
+    },
     solana_pubkey::Pubkey,
     solana_sdk::{
         account::Account,
         account_utils::StateMut,
-        clock::Slot,
+        clock::{Epoch, Slot},
+        epoch_schedule::EpochSchedule,
         signature::Signature,
         stake::state::{Authorized, StakeStateV2},
     },
+    solana_transaction_status::{
+        EncodedTransaction, RewardType, TransactionDetails, UiTransactionEncoding,
+    },
+    std::collections::HashMap,
 };
 
 #[derive(Clone, Debug, PartialEq)]
@@ -24,13 +35,145 @@ pub async fn get_block_date(
     slot: Slot,
 ) -> Result<NaiveDate, Box<dyn std::error::Error>> {
     let block_time = rpc_client.get_block_time(slot)?;
+    Ok(block_time_to_date(block_time))
+}
+
+pub fn block_time_to_date(block_time: i64) -> NaiveDate {
     let local_timestamp = Local.timestamp_opt(block_time, 0).unwrap();
-    Ok(NaiveDate::from_ymd_opt(
+    NaiveDate::from_ymd_opt(
         local_timestamp.year(),
         local_timestamp.month(),
         local_timestamp.day(),
     )
-    .unwrap())
+    .unwrap()
+}
+
+/// Inflation rewards of `addresses` for `epoch`, along with the date of each slot the rewards were
+/// credited in
+#[allow(clippy::type_complexity)]
+pub fn get_inflation_rewards_with_dates(
+    rpc_client: &RpcClient,
+    addresses: &[Pubkey],
+    epoch: Epoch,
+) -> Result<(Vec<Option<RpcInflationReward>>, HashMap<Slot, NaiveDate>), Box<dyn std::error::Error>>
+{
+    let inflation_rewards = rpc_client.get_inflation_reward(addresses, Some(epoch))?;
+
+    let mut block_dates = HashMap::default();
+    for inflation_reward in inflation_rewards.iter().flatten() {
+        let slot = inflation_reward.effective_slot;
+        if let std::collections::hash_map::Entry::Vacant(entry) = block_dates.entry(slot) {
+            entry.insert(block_time_to_date(rpc_client.get_block_time(slot)?));
+        }
+    }
+    Ok((inflation_rewards, block_dates))
+}
+
+/// Slots of `epoch` that `identity` is scheduled to produce a block in. `None` if the leader
+/// schedule of `epoch` is no longer available
+pub fn get_leader_slots(
+    rpc_client: &RpcClient,
+    epoch_schedule: &EpochSchedule,
+    epoch: Epoch,
+    identity: &Pubkey,
+) -> Result<Option<Vec<Slot>>, Box<dyn std::error::Error>> {
+    let first_slot_in_epoch = epoch_schedule.get_first_slot_in_epoch(epoch);
+    let leader_schedule = rpc_client.get_leader_schedule_with_config(
+        Some(first_slot_in_epoch),
+        RpcLeaderScheduleConfig {
+            identity: Some(identity.to_string()),
+            commitment: None,
+        },
+    )?;
+
+    Ok(leader_schedule.map(|leader_schedule| {
+        leader_schedule
+            .get(&identity.to_string())
+            .map(|slot_indices| {
+                slot_indices
+                    .iter()
+                    .map(|slot_index| first_slot_in_epoch + *slot_index as Slot)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }))
+}
+
+#[derive(Debug)]
+pub struct BlockFeeReward {
+    pub when: NaiveDate,
+    pub fee: u64,          // the leader's share of the base transaction fees
+    pub priority_fee: u64, // the priority fees paid to the leader
+}
+
+/// The fee reward credited to `identity` for producing the block in `slot`. `None` if the slot
+/// was skipped
+pub fn get_block_fee_reward(
+    rpc_client: &RpcClient,
+    slot: Slot,
+    identity: &Pubkey,
+) -> Result<Option<BlockFeeReward>, Box<dyn std::error::Error>> {
+    const LAMPORTS_PER_SIGNATURE: u64 = 5_000;
+
+    let block = match rpc_client.get_block_with_config(
+        slot,
+        RpcBlockConfig {
+            encoding: Some(UiTransactionEncoding::Base64),
+            transaction_details: Some(TransactionDetails::Accounts),
+            rewards: Some(true),
+            commitment: None,
+            max_supported_transaction_version: Some(0),
+        },
+    ) {
+        Ok(block) => block,
+        Err(err) => {
+            return match err.kind() {
+                // Slot skipped, or skipped and missing from long-term storage
+                ClientErrorKind::RpcError(RpcError::RpcResponseError {
+                    code: -32007 | -32009,
+                    ..
+                }) => Ok(None),
+                _ => Err(format!("Failed to get block {slot}: {err}").into()),
+            };
+        }
+    };
+
+    let reward = block
+        .rewards
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|reward| {
+            reward.reward_type == Some(RewardType::Fee) && reward.pubkey == identity.to_string()
+        })
+        .map(|reward| reward.lamports.max(0) as u64)
+        .sum::<u64>();
+
+    // Priority fees are paid to the leader in full, while part of the base fee is burned. Whatever
+    // remains of the reward once the priority fees are accounted for is from base fees
+    let priority_fees = block
+        .transactions
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|transaction| {
+            let fee = transaction.meta?.fee;
+            let num_signatures = match transaction.transaction {
+                EncodedTransaction::Accounts(accounts_list) => accounts_list.signatures.len(),
+                _ => return None,
+            };
+            Some(fee.saturating_sub(LAMPORTS_PER_SIGNATURE * num_signatures as u64))
+        })
+        .sum::<u64>();
+    let priority_fee = priority_fees.min(reward);
+
+    let block_time = block
+        .block_time
+        .ok_or_else(|| format!("Block time not available for slot {slot}"))?;
+
+    Ok(Some(BlockFeeReward {
+        when: block_time_to_date(block_time),
+        fee: reward - priority_fee,
+        priority_fee,
+    }))
 }
 
 pub fn get_stake_activation_state(
@@ -106,6 +249,52 @@ pub fn get_stake_authorized(
     }
 }
 
+/// The date that the lockup of a stake account expires, or `None` if `address` is not a stake
+/// account with a lockup, including when it no longer exists. Epoch based lockups are estimated
+/// from the current slot time
+pub fn get_stake_unlock_date(
+    rpc_client: &RpcClient,
+    address: &Pubkey,
+) -> Result<Option<NaiveDate>, Box<dyn std::error::Error>> {
+    const ESTIMATED_SLOT_MS: i64 = 400;
+
+    let account = match rpc_client
+        .get_account_with_commitment(address, rpc_client.commitment())?
+        .value
+    {
+        Some(account) => account,
+        None => return Ok(None),
+    };
+    if account.owner != solana_sdk::stake::program::id() {
+        return Ok(None);
+    }
+    let lockup = match account.state() {
+        Ok(StakeStateV2::Initialized(meta)) | Ok(StakeStateV2::Stake(meta, _, _)) => meta.lockup,
+        _ => return Ok(None),
+    };
+    if lockup.unix_timestamp <= 0 && lockup.epoch == 0 {
+        return Ok(None);
+    }
+
+    let mut unlock_timestamp = lockup.unix_timestamp;
+    if lockup.epoch > 0 {
+        let current_slot = rpc_client.get_slot()?;
+        let unlock_slot = rpc_client
+            .get_epoch_schedule()?
+            .get_first_slot_in_epoch(lockup.epoch);
+        let unlock_epoch_timestamp = Utc::now().timestamp()
+            + (unlock_slot as i64 - current_slot as i64) * ESTIMATED_SLOT_MS / 1000;
+        unlock_timestamp = unlock_timestamp.max(unlock_epoch_timestamp);
+    }
+
+    Ok(Some(
+        Local
+            .timestamp_opt(unlock_timestamp, 0)
+            .unwrap()
+            .date_naive(),
+    ))
+}
+
 pub fn stake_accounts_have_same_credits_observed(
     stake_account1: &Account,
     stake_account2: &Account,
@@ -153,3 +342,31 @@ pub async fn get_signature_date(
         Err(format!("Unknown signature: {signature}").into())
     }
 }
+
+/// Returns the fee payer and the fee, in lamports, paid by a confirmed transaction
+pub fn get_transaction_fee(
+    rpc_client: &RpcClient,
+    signature: Signature,
+) -> Result<(Pubkey, u64), Box<dyn std::error::Error>> {
+    let result = rpc_client.get_transaction_with_config(
+        &signature,
+        RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::Base64),
+            commitment: Some(rpc_client.commitment()),
+            max_supported_transaction_version: Some(0),
+        },
+    )?;
+
+    let fee = result
+        .transaction
+        .meta
+        .ok_or_else(|| format!("Transaction metadata not available for {signature}"))?
+        .fee;
+    let fee_payer = result
+        .transaction
+        .transaction
+        .decode()
+        .and_then(|transaction| transaction.message.static_account_keys().first().copied())
+        .ok_or_else(|| format!("Unable to decode transaction {signature}"))?;
+    Ok((fee_payer, fee))
+}
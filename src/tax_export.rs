@@ -0,0 +1,554 @@
+use {
+    crate::{
+        db::{exchange_fee_in_usd, Db, DisposedLot, Lot, LotAcquistionKind, LotDisposalKind},
+        token::MaybeToken,
+    },
+    chrono::prelude::*,
+    rust_decimal::prelude::*,
+    solana_sdk::signature::Signature,
+    strum::{EnumString, IntoStaticStr},
+};
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, EnumString, IntoStaticStr)]
+pub enum CsvFormat {
+    #[default]
+    #[strum(serialize = "turbotax")]
+    TurboTax,
+    #[strum(serialize = "koinly")]
+    Koinly,
+    #[strum(serialize = "cointracker")]
+    CoinTracker,
+    #[strum(serialize = "coinledger")]
+    CoinLedger,
+}
+
+pub const POSSIBLE_CSV_FORMAT_VALUES: &[&str] =
+    &["turbotax", "koinly", "cointracker", "coinledger"];
+
+impl std::fmt::Display for CsvFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name: &'static str = self.into();
+        write!(f, "{name}")
+    }
+}
+
+impl CsvFormat {
+    pub fn exporter(&self) -> Box<dyn CsvExporter> {
+        match self {
+            CsvFormat::TurboTax => Box::new(TurboTaxCsvExporter),
+            CsvFormat::Koinly => Box::new(KoinlyCsvExporter),
+            CsvFormat::CoinTracker => Box::new(CoinTrackerCsvExporter),
+            CsvFormat::CoinLedger => Box::new(CoinLedgerCsvExporter),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IncomeSource {
+    Staking, // inflation rewards
+    MevTips, // Jito tip distribution claims
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaxEventKind {
+    // Tokens received as income, valued at `value` USD
+    Income {
+        source: IncomeSource,
+        value: f64,
+    },
+    // Tokens bought for `cost` USD, fees included
+    Buy {
+        cost: f64,
+    },
+    // Tokens sold for `proceeds` USD, before the `fee` USD charged by the exchange
+    Sell {
+        acquired: NaiveDate,
+        basis: f64,
+        income: f64,
+        proceeds: f64,
+        fee: f64,
+    },
+    // Tokens spent on fees or otherwise given up for nothing in return, worth `value` USD
+    Cost {
+        acquired: NaiveDate,
+        basis: f64,
+        income: f64,
+        value: f64,
+    },
+}
+
+/// An acquisition or disposal of a lot, valued in USD
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaxEvent {
+    pub when: NaiveDate,
+    pub token: MaybeToken,
+    pub amount: u64,
+    pub kind: TaxEventKind,
+    pub description: String,
+    pub signature: Option<Signature>,
+}
+
+impl TaxEvent {
+    fn ui_amount(&self) -> String {
+        format!(
+            "{:.*}",
+            self.token.decimals() as usize,
+            self.token.ui_amount(self.amount)
+        )
+    }
+
+    // Tax software knows wrapped SOL as SOL
+    fn currency(&self) -> &'static str {
+        if self.token.is_sol_or_wsol() {
+            "SOL"
+        } else {
+            self.token.name()
+        }
+    }
+
+    fn tx_hash(&self) -> String {
+        self.signature
+            .map(|signature| signature.to_string())
+            .unwrap_or_default()
+    }
+}
+
+fn usd(value: f64) -> String {
+    format!("{value:.2}")
+}
+
+fn acquisition_event(token: MaybeToken, lot: &Lot) -> TaxEvent {
+    let income = lot.income(token);
+    let signature = match lot.acquisition.kind {
+        LotAcquistionKind::Transaction { signature, .. }
+        | LotAcquistionKind::Swap { signature, .. }
+        | LotAcquistionKind::MevReward { signature, .. } => Some(signature),
+        _ => None,
+    };
+    TaxEvent {
+        when: lot.acquisition.when,
+        token,
+        amount: lot.amount,
+        kind: if income > 0. {
+            TaxEventKind::Income {
+                source: match lot.acquisition.kind {
+                    LotAcquistionKind::EpochReward { .. } => IncomeSource::Staking,
+                    LotAcquistionKind::MevReward { .. } => IncomeSource::MevTips,
+                    _ => IncomeSource::Other,
+                },
+                value: income,
+            }
+        } else {
+            TaxEventKind::Buy {
+                cost: lot.basis(token),
+            }
+        },
+        description: format!("Lot {}: {}", lot.lot_number, lot.acquisition.kind),
+        signature,
+    }
+}
+
+fn disposal_event(disposed_lot: &DisposedLot) -> TaxEvent {
+    let token = disposed_lot.token;
+    let ui_amount = Decimal::from_f64(token.ui_amount(disposed_lot.lot.amount)).unwrap();
+    let fee = disposed_lot
+        .kind
+        .fee()
+        .and_then(|fee| exchange_fee_in_usd(fee, token, disposed_lot.gross_price()))
+        .unwrap_or_default();
+    let signature = match disposed_lot.kind {
+        LotDisposalKind::Swap { signature, .. } | LotDisposalKind::TransactionFee { signature } => {
+            Some(signature)
+        }
+        _ => None,
+    };
+    let acquired = disposed_lot.lot.acquisition.when;
+    let basis = disposed_lot.lot.basis(token);
+    let income = disposed_lot.lot.income(token);
+    let value = (disposed_lot.gross_price() * ui_amount).try_into().unwrap();
+    TaxEvent {
+        when: disposed_lot.when,
+        token,
+        amount: disposed_lot.lot.amount,
+        kind: match &disposed_lot.kind {
+            // Fiat-fungible lots that left for fiat are sold, at $1
+            LotDisposalKind::Other { description } if description == "fiat" => TaxEventKind::Sell {
+                acquired,
+                basis,
+                income,
+                proceeds: value,
+                fee: 0.,
+            },
+            LotDisposalKind::TransactionFee { .. }
+            | LotDisposalKind::WithdrawalFee { .. }
+            | LotDisposalKind::Other { .. } => TaxEventKind::Cost {
+                acquired,
+                basis,
+                income,
+                value,
+            },
+            LotDisposalKind::Usd { .. } | LotDisposalKind::Swap { .. } | LotDisposalKind::Fiat => {
+                TaxEventKind::Sell {
+                    acquired,
+                    basis,
+                    income,
+                    proceeds: value,
+                    fee: fee.try_into().unwrap(),
+                }
+            }
+        },
+        description: format!("Lot {}: {}", disposed_lot.lot.lot_number, disposed_lot.kind),
+        signature,
+    }
+}
+
+/// Every acquisition and disposal of the lots in `db`, ordered by date. Swaps are represented as
+/// a sale of one token and a purchase of the other
+pub fn tax_events(db: &Db) -> Vec<TaxEvent> {
+    let mut tax_events = vec![];
+    for (token, lot) in db.held_lots() {
+        tax_events.push(acquisition_event(token, &lot));
+    }
+    for disposed_lot in db.disposed_lots() {
+        tax_events.push(acquisition_event(disposed_lot.token, &disposed_lot.lot));
+        tax_events.push(disposal_event(&disposed_lot));
+    }
+    tax_events.sort_by_key(|tax_event| tax_event.when);
+    tax_events
+}
+
+/// A CSV layout importable by a particular tax software package
+pub trait CsvExporter {
+    fn header(&self) -> &'static [&'static str];
+
+    /// The CSV record of `tax_event`, if the format has a place for it
+    fn record(&self, tax_event: &TaxEvent) -> Option<Vec<String>>;
+
+    /// Whether `tax_event` belongs in an export limited to `year`
+    fn in_year(&self, tax_event: &TaxEvent, year: i32) -> bool {
+        tax_event.when.year() == year
+    }
+}
+
+// Realized gains only, one row per disposed lot
+pub struct TurboTaxCsvExporter;
+
+impl CsvExporter for TurboTaxCsvExporter {
+    fn header(&self) -> &'static [&'static str] {
+        &[
+            "Transaction Type",
+            "Asset Name",
+            "Amount",
+            "Date Acquired",
+            "Cost basis (USD)",
+            "Date of Disposition",
+            "Proceeds (USD)",
+        ]
+    }
+
+    fn record(&self, tax_event: &TaxEvent) -> Option<Vec<String>> {
+        match tax_event.kind {
+            TaxEventKind::Sell {
+                acquired,
+                basis,
+                proceeds,
+                fee,
+                ..
+            } => Some(vec![
+                "Sell".into(),
+                tax_event.token.to_string(),
+                tax_event.ui_amount(),
+                acquired.to_string(),
+                usd(basis),
+                tax_event.when.to_string(),
+                usd(proceeds - fee),
+            ]),
+            // Spending tokens on fees is also a disposal, at their value when spent
+            TaxEventKind::Cost {
+                acquired,
+                basis,
+                value,
+                ..
+            } => Some(vec![
+                "Sell".into(),
+                tax_event.token.to_string(),
+                tax_event.ui_amount(),
+                acquired.to_string(),
+                usd(basis),
+                tax_event.when.to_string(),
+                usd(value),
+            ]),
+            _ => None,
+        }
+    }
+
+    // Also include disposals of lots that were acquired as income in `year`
+    fn in_year(&self, tax_event: &TaxEvent, year: i32) -> bool {
+        match tax_event.kind {
+            TaxEventKind::Sell {
+                acquired, income, ..
+            }
+            | TaxEventKind::Cost {
+                acquired, income, ..
+            } => tax_event.when.year() == year || (acquired.year() == year && income > 0.),
+            _ => tax_event.when.year() == year,
+        }
+    }
+}
+
+// Koinly universal format
+pub struct KoinlyCsvExporter;
+
+impl CsvExporter for KoinlyCsvExporter {
+    fn header(&self) -> &'static [&'static str] {
+        &[
+            "Date",
+            "Sent Amount",
+            "Sent Currency",
+            "Received Amount",
+            "Received Currency",
+            "Fee Amount",
+            "Fee Currency",
+            "Net Worth Amount",
+            "Net Worth Currency",
+            "Label",
+            "Description",
+            "TxHash",
+        ]
+    }
+
+    fn record(&self, tax_event: &TaxEvent) -> Option<Vec<String>> {
+        let date = tax_event.when.format("%Y-%m-%d 00:00 UTC").to_string();
+        let amount = tax_event.ui_amount();
+        let currency = tax_event.currency().to_string();
+        let usd_currency = "USD".to_string();
+
+        let (sent, received, fee, net_worth, label) = match tax_event.kind {
+            TaxEventKind::Income { source, value } => (
+                None,
+                Some((amount, currency)),
+                None,
+                value,
+                match source {
+                    IncomeSource::Staking => "staking",
+                    IncomeSource::MevTips => "reward",
+                    IncomeSource::Other => "income",
+                },
+            ),
+            TaxEventKind::Buy { cost } => (
+                Some((usd(cost), usd_currency.clone())),
+                Some((amount, currency)),
+                None,
+                cost,
+                "",
+            ),
+            TaxEventKind::Sell { proceeds, fee, .. } => (
+                Some((amount, currency)),
+                Some((usd(proceeds), usd_currency.clone())),
+                (fee > 0.).then(|| (usd(fee), usd_currency.clone())),
+                proceeds,
+                "",
+            ),
+            TaxEventKind::Cost { value, .. } => {
+                (Some((amount, currency)), None, None, value, "cost")
+            }
+        };
+        let (sent_amount, sent_currency) = sent.unwrap_or_default();
+        let (received_amount, received_currency) = received.unwrap_or_default();
+        let (fee_amount, fee_currency) = fee.unwrap_or_default();
+
+        Some(vec![
+            date,
+            sent_amount,
+            sent_currency,
+            received_amount,
+            received_currency,
+            fee_amount,
+            fee_currency,
+            usd(net_worth),
+            usd_currency,
+            label.into(),
+            tax_event.description.clone(),
+            tax_event.tx_hash(),
+        ])
+    }
+}
+
+// CoinTracker transaction import format
+pub struct CoinTrackerCsvExporter;
+
+impl CsvExporter for CoinTrackerCsvExporter {
+    fn header(&self) -> &'static [&'static str] {
+        &[
+            "Date",
+            "Received Quantity",
+            "Received Currency",
+            "Sent Quantity",
+            "Sent Currency",
+            "Fee Amount",
+            "Fee Currency",
+            "Tag",
+        ]
+    }
+
+    fn record(&self, tax_event: &TaxEvent) -> Option<Vec<String>> {
+        let date = tax_event.when.format("%m/%d/%Y 00:00:00").to_string();
+        let amount = tax_event.ui_amount();
+        let currency = tax_event.currency().to_string();
+
+        Some(match tax_event.kind {
+            TaxEventKind::Income { source, .. } => vec![
+                date,
+                amount,
+                currency,
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                match source {
+                    IncomeSource::Staking => "staked",
+                    IncomeSource::MevTips | IncomeSource::Other => "income",
+                }
+                .into(),
+            ],
+            TaxEventKind::Buy { cost } => vec![
+                date,
+                amount,
+                currency,
+                usd(cost),
+                "USD".into(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ],
+            TaxEventKind::Sell { proceeds, fee, .. } => vec![
+                date,
+                usd(proceeds),
+                "USD".into(),
+                amount,
+                currency,
+                if fee > 0. { usd(fee) } else { String::new() },
+                if fee > 0. {
+                    "USD".into()
+                } else {
+                    String::new()
+                },
+                String::new(),
+            ],
+            // A fee-only transaction
+            TaxEventKind::Cost { .. } => vec![
+                date,
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                amount,
+                currency,
+                String::new(),
+            ],
+        })
+    }
+}
+
+// CoinLedger universal import format
+pub struct CoinLedgerCsvExporter;
+
+impl CsvExporter for CoinLedgerCsvExporter {
+    fn header(&self) -> &'static [&'static str] {
+        &[
+            "Date (UTC)",
+            "Platform (Optional)",
+            "Asset Sent",
+            "Amount Sent",
+            "Asset Received",
+            "Amount Received",
+            "Fee Currency (Optional)",
+            "Fee Amount (Optional)",
+            "Type",
+            "Description (Optional)",
+            "TxHash (Optional)",
+        ]
+    }
+
+    fn record(&self, tax_event: &TaxEvent) -> Option<Vec<String>> {
+        let date = tax_event.when.format("%m/%d/%Y 00:00:00").to_string();
+        let amount = tax_event.ui_amount();
+        let currency = tax_event.currency().to_string();
+
+        let (sent, received, fee, kind) = match tax_event.kind {
+            TaxEventKind::Income { source, .. } => (
+                (String::new(), String::new()),
+                (currency, amount),
+                None,
+                match source {
+                    IncomeSource::Staking => "Staking",
+                    IncomeSource::MevTips | IncomeSource::Other => "Income",
+                },
+            ),
+            TaxEventKind::Buy { cost } => (
+                ("USD".to_string(), usd(cost)),
+                (currency, amount),
+                None,
+                "Trade",
+            ),
+            TaxEventKind::Sell { proceeds, fee, .. } => (
+                (currency, amount),
+                ("USD".to_string(), usd(proceeds)),
+                (fee > 0.).then_some(fee),
+                "Trade",
+            ),
+            TaxEventKind::Cost { .. } => (
+                (currency, amount),
+                (String::new(), String::new()),
+                None,
+                "Fee",
+            ),
+        };
+        let (fee_currency, fee_amount) = match fee {
+            Some(fee) => ("USD".to_string(), usd(fee)),
+            None => (String::new(), String::new()),
+        };
+
+        Some(vec![
+            date,
+            "Solana".into(),
+            sent.0,
+            sent.1,
+            received.0,
+            received.1,
+            fee_currency,
+            fee_amount,
+            kind.into(),
+            tax_event.description.clone(),
+            tax_event.tx_hash(),
+        ])
+    }
+}
+
+/// Write the tax events of `db` to `outfile` in `format`, returning the number of records written
+pub fn export_csv(
+    db: &Db,
+    format: CsvFormat,
+    outfile: &str,
+    filter_by_year: Option<i32>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let exporter = format.exporter();
+    let mut wtr = csv::Writer::from_path(outfile)?;
+    wtr.write_record(exporter.header())?;
+
+    let mut records = 0;
+    for tax_event in tax_events(db) {
+        if let Some(year) = filter_by_year {
+            if !exporter.in_year(&tax_event, year) {
+                continue;
+            }
+        }
+        if let Some(record) = exporter.record(&tax_event) {
+            wtr.write_record(&record)?;
+            records += 1;
+        }
+    }
+    wtr.flush()?;
+    Ok(records)
+}
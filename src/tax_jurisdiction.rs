@@ -0,0 +1,790 @@
+use {
+    crate::{
+        db::{is_long_term_holding, Db, DisposedLot},
+        token::{MaybeToken, Token},
+    },
+    chrono::{prelude::*, Months},
+    rust_decimal::prelude::*,
+    serde::{Deserialize, Serialize},
+    std::collections::BTreeMap,
+    strum::{EnumString, IntoStaticStr},
+};
+
+#[derive(
+    Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, EnumString, IntoStaticStr,
+)]
+pub enum TaxJurisdiction {
+    #[default]
+    #[strum(serialize = "us")]
+    UnitedStates,
+    #[strum(serialize = "uk")]
+    UnitedKingdom,
+    #[strum(serialize = "canada")]
+    Canada,
+    #[strum(serialize = "germany")]
+    Germany,
+}
+
+pub const POSSIBLE_TAX_JURISDICTION_VALUES: &[&str] = &["us", "uk", "canada", "germany"];
+
+impl std::fmt::Display for TaxJurisdiction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name: &'static str = self.into();
+        write!(f, "{name}")
+    }
+}
+
+impl TaxJurisdiction {
+    pub fn tax_rules(&self) -> Box<dyn TaxRules> {
+        match self {
+            TaxJurisdiction::UnitedStates => Box::new(UnitedStatesTaxRules),
+            TaxJurisdiction::UnitedKingdom => Box::new(UnitedKingdomTaxRules),
+            TaxJurisdiction::Canada => Box::new(CanadaTaxRules),
+            TaxJurisdiction::Germany => Box::new(GermanyTaxRules),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HoldingPeriod {
+    // The jurisdiction taxes gains the same regardless of how long the asset was held
+    NotApplicable,
+    ShortTerm,
+    LongTerm,
+    // Held long enough for the gain to be tax free
+    Exempt,
+}
+
+/// A token acquisition available to be matched against disposals
+#[derive(Debug, Clone)]
+pub struct Acquisition {
+    pub when: NaiveDate,
+    pub amount: u64,
+    pub price: Decimal,
+}
+
+/// A token disposal, with the lots that `sys` selected for it
+#[derive(Debug, Clone)]
+pub struct Disposal {
+    pub when: NaiveDate,
+    pub amount: u64,
+    pub price: Decimal,
+    pub disposed_lots: Vec<DisposedLot>,
+}
+
+/// A disposal, or part of one, as matched against acquisitions by the jurisdiction's rules
+#[derive(Debug, Clone)]
+pub struct TaxableDisposal {
+    pub token: MaybeToken,
+    pub when: NaiveDate,
+    pub amount: u64,
+    pub acquired: Option<NaiveDate>, // `None` when matched against a pooled cost
+    pub proceeds: f64,
+    pub basis: f64,
+    pub holding_period: HoldingPeriod,
+    pub rule: &'static str,
+}
+
+impl TaxableDisposal {
+    pub fn gain(&self) -> f64 {
+        self.proceeds - self.basis
+    }
+}
+
+pub trait TaxRules {
+    fn jurisdiction(&self) -> TaxJurisdiction;
+
+    fn holding_period(&self, acquired: NaiveDate, disposed: NaiveDate) -> HoldingPeriod;
+
+    /// Match the disposals of a single token against its acquisitions. Both are sorted by date.
+    /// Fails if a disposal exceeds the acquisitions available to match it
+    fn match_disposals(
+        &self,
+        token: MaybeToken,
+        acquisitions: Vec<Acquisition>,
+        disposals: Vec<Disposal>,
+    ) -> Result<Vec<TaxableDisposal>, String>;
+
+    /// The portion of a capital gain that is subject to tax
+    fn taxable_gain(&self, gain: f64) -> f64 {
+        gain
+    }
+
+    /// Whether long-term gains are taxed at a different rate than short-term gains
+    fn has_long_term_rate(&self) -> bool {
+        false
+    }
+
+    /// Summary lines of a tax year report, laid out as the jurisdiction's tax return expects
+    fn report_summary(&self, taxable_disposals: &[TaxableDisposal]) -> Vec<(&'static str, f64)>;
+}
+
+fn sum_of(
+    taxable_disposals: &[TaxableDisposal],
+    f: impl Fn(&TaxableDisposal) -> Option<f64>,
+) -> f64 {
+    taxable_disposals.iter().filter_map(f).sum()
+}
+
+fn ui_value(token: MaybeToken, amount: u64, price: Decimal) -> f64 {
+    (price * Decimal::from_f64(token.ui_amount(amount)).unwrap())
+        .try_into()
+        .unwrap()
+}
+
+fn unmatched_disposal(
+    token: MaybeToken,
+    when: NaiveDate,
+    amount: u64,
+    available_amount: u64,
+) -> String {
+    format!(
+        "{when} disposal of {} exceeds the {} acquired by then",
+        token.format_amount(amount),
+        token.format_amount(available_amount),
+    )
+}
+
+// Consume up to `amount` from `acquisitions` that satisfy `filter`, earliest first, returning
+// the acquisition date, amount and cost of each match
+fn take_acquisitions(
+    token: MaybeToken,
+    acquisitions: &mut [Acquisition],
+    mut amount: u64,
+    filter: impl Fn(&Acquisition) -> bool,
+) -> Vec<(NaiveDate, u64, f64)> {
+    let mut matches = vec![];
+    for acquisition in acquisitions.iter_mut() {
+        if amount == 0 {
+            break;
+        }
+        if acquisition.amount == 0 || !filter(acquisition) {
+            continue;
+        }
+        let matched_amount = acquisition.amount.min(amount);
+        acquisition.amount -= matched_amount;
+        amount -= matched_amount;
+        matches.push((
+            acquisition.when,
+            matched_amount,
+            ui_value(token, matched_amount, acquisition.price),
+        ));
+    }
+    matches
+}
+
+/// Lots are matched individually, as selected by the `--lot-selection` method. Gains on assets
+/// held for at least a year are long-term
+pub struct UnitedStatesTaxRules;
+
+impl TaxRules for UnitedStatesTaxRules {
+    fn jurisdiction(&self) -> TaxJurisdiction {
+        TaxJurisdiction::UnitedStates
+    }
+
+    fn holding_period(&self, acquired: NaiveDate, disposed: NaiveDate) -> HoldingPeriod {
+        if is_long_term_holding(acquired, disposed) {
+            HoldingPeriod::LongTerm
+        } else {
+            HoldingPeriod::ShortTerm
+        }
+    }
+
+    fn match_disposals(
+        &self,
+        token: MaybeToken,
+        _acquisitions: Vec<Acquisition>,
+        disposals: Vec<Disposal>,
+    ) -> Result<Vec<TaxableDisposal>, String> {
+        Ok(disposals
+            .into_iter()
+            .flat_map(|disposal| disposal.disposed_lots)
+            .map(|disposed_lot| TaxableDisposal {
+                token,
+                when: disposed_lot.when,
+                amount: disposed_lot.lot.amount,
+                acquired: Some(disposed_lot.lot.acquisition.when),
+                proceeds: ui_value(token, disposed_lot.lot.amount, disposed_lot.price()),
+                basis: disposed_lot.lot.basis(token),
+                holding_period: self
+                    .holding_period(disposed_lot.lot.acquisition.when, disposed_lot.when),
+                rule: "specific identification",
+            })
+            .collect())
+    }
+
+    fn has_long_term_rate(&self) -> bool {
+        true
+    }
+
+    fn report_summary(&self, taxable_disposals: &[TaxableDisposal]) -> Vec<(&'static str, f64)> {
+        let term_gain = |holding_period: HoldingPeriod| {
+            sum_of(taxable_disposals, |td| {
+                (td.holding_period == holding_period).then(|| td.gain())
+            })
+        };
+        vec![
+            (
+                "Proceeds",
+                sum_of(taxable_disposals, |td| Some(td.proceeds)),
+            ),
+            ("Cost basis", sum_of(taxable_disposals, |td| Some(td.basis))),
+            ("Short-term gain", term_gain(HoldingPeriod::ShortTerm)),
+            ("Long-term gain", term_gain(HoldingPeriod::LongTerm)),
+        ]
+    }
+}
+
+/// HMRC share matching: disposals are matched first against acquisitions on the same day, then
+/// against acquisitions within the following 30 days, and finally against the Section 104 pool
+/// at its average cost
+pub struct UnitedKingdomTaxRules;
+
+impl TaxRules for UnitedKingdomTaxRules {
+    fn jurisdiction(&self) -> TaxJurisdiction {
+        TaxJurisdiction::UnitedKingdom
+    }
+
+    fn holding_period(&self, _acquired: NaiveDate, _disposed: NaiveDate) -> HoldingPeriod {
+        HoldingPeriod::NotApplicable
+    }
+
+    fn match_disposals(
+        &self,
+        token: MaybeToken,
+        mut acquisitions: Vec<Acquisition>,
+        disposals: Vec<Disposal>,
+    ) -> Result<Vec<TaxableDisposal>, String> {
+        // All disposals on the same day are treated as a single disposal
+        let mut disposals_by_day = BTreeMap::<NaiveDate, (u64, f64)>::default();
+        for disposal in disposals {
+            let (amount, proceeds) = disposals_by_day.entry(disposal.when).or_default();
+            *amount += disposal.amount;
+            *proceeds += ui_value(token, disposal.amount, disposal.price);
+        }
+
+        let mut taxable_disposals = vec![];
+        let mut remaining_disposals = disposals_by_day
+            .into_iter()
+            .map(|(when, (amount, proceeds))| (when, amount, proceeds / amount as f64))
+            .collect::<Vec<_>>();
+
+        let mut record = |when: NaiveDate,
+                          proceeds_per_unit: f64,
+                          matches: Vec<(NaiveDate, u64, f64)>,
+                          rule: &'static str| {
+            for (acquired, amount, basis) in matches {
+                taxable_disposals.push(TaxableDisposal {
+                    token,
+                    when,
+                    amount,
+                    acquired: (rule != "section 104").then_some(acquired),
+                    proceeds: proceeds_per_unit * amount as f64,
+                    basis,
+                    holding_period: HoldingPeriod::NotApplicable,
+                    rule,
+                });
+            }
+        };
+
+        // Same-day rule
+        for (when, amount, proceeds_per_unit) in remaining_disposals.iter_mut() {
+            let disposal_day = *when;
+            let matches = take_acquisitions(token, &mut acquisitions, *amount, |acquisition| {
+                acquisition.when == disposal_day
+            });
+            *amount -= matches.iter().map(|(_, amount, _)| amount).sum::<u64>();
+            record(*when, *proceeds_per_unit, matches, "same day");
+        }
+
+        // 30-day ("bed and breakfast") rule
+        for (when, amount, proceeds_per_unit) in remaining_disposals.iter_mut() {
+            let disposal_day = *when;
+            let matches = take_acquisitions(token, &mut acquisitions, *amount, |acquisition| {
+                acquisition.when > disposal_day
+                    && acquisition.when <= disposal_day + chrono::Duration::try_days(30).unwrap()
+            });
+            *amount -= matches.iter().map(|(_, amount, _)| amount).sum::<u64>();
+            record(*when, *proceeds_per_unit, matches, "30 day");
+        }
+
+        // Section 104 pool
+        let mut pool_amount = 0u64;
+        let mut pool_cost = 0f64;
+        let mut acquisitions = acquisitions.into_iter().peekable();
+        for (when, amount, proceeds_per_unit) in remaining_disposals {
+            while let Some(acquisition) = acquisitions.next_if(|a| a.when <= when) {
+                pool_amount += acquisition.amount;
+                pool_cost += ui_value(token, acquisition.amount, acquisition.price);
+            }
+            if amount == 0 {
+                continue;
+            }
+            if amount > pool_amount {
+                return Err(unmatched_disposal(token, when, amount, pool_amount));
+            }
+
+            let basis = pool_cost * amount as f64 / pool_amount as f64;
+            pool_amount -= amount;
+            pool_cost -= basis;
+            record(
+                when,
+                proceeds_per_unit,
+                vec![(when, amount, basis)],
+                "section 104",
+            );
+        }
+
+        Ok(taxable_disposals)
+    }
+
+    fn report_summary(&self, taxable_disposals: &[TaxableDisposal]) -> Vec<(&'static str, f64)> {
+        let disposal_days = taxable_disposals
+            .iter()
+            .map(|td| (td.token, td.when))
+            .collect::<std::collections::BTreeSet<_>>();
+        vec![
+            ("Number of disposals", disposal_days.len() as f64),
+            (
+                "Disposal proceeds",
+                sum_of(taxable_disposals, |td| Some(td.proceeds)),
+            ),
+            (
+                "Allowable costs",
+                sum_of(taxable_disposals, |td| Some(td.basis)),
+            ),
+            (
+                "Gains in the year, before losses",
+                sum_of(taxable_disposals, |td| Some(td.gain().max(0.))),
+            ),
+            (
+                "Losses in the year",
+                sum_of(taxable_disposals, |td| Some((-td.gain()).max(0.))),
+            ),
+        ]
+    }
+}
+
+/// Adjusted cost base: every disposal takes the average cost of all units held at the time.
+/// Only half of a capital gain is taxable. The superficial loss rule is not applied
+pub struct CanadaTaxRules;
+
+impl TaxRules for CanadaTaxRules {
+    fn jurisdiction(&self) -> TaxJurisdiction {
+        TaxJurisdiction::Canada
+    }
+
+    fn holding_period(&self, _acquired: NaiveDate, _disposed: NaiveDate) -> HoldingPeriod {
+        HoldingPeriod::NotApplicable
+    }
+
+    fn match_disposals(
+        &self,
+        token: MaybeToken,
+        acquisitions: Vec<Acquisition>,
+        disposals: Vec<Disposal>,
+    ) -> Result<Vec<TaxableDisposal>, String> {
+        let mut total_amount = 0u64;
+        let mut total_cost = 0f64;
+        let mut acquisitions = acquisitions.into_iter().peekable();
+
+        let mut taxable_disposals = vec![];
+        for disposal in disposals {
+            while let Some(acquisition) = acquisitions.next_if(|a| a.when <= disposal.when) {
+                total_amount += acquisition.amount;
+                total_cost += ui_value(token, acquisition.amount, acquisition.price);
+            }
+
+            if disposal.amount > total_amount {
+                return Err(unmatched_disposal(
+                    token,
+                    disposal.when,
+                    disposal.amount,
+                    total_amount,
+                ));
+            }
+            let basis = total_cost * disposal.amount as f64 / total_amount as f64;
+            total_amount -= disposal.amount;
+            total_cost -= basis;
+
+            taxable_disposals.push(TaxableDisposal {
+                token,
+                when: disposal.when,
+                amount: disposal.amount,
+                acquired: None,
+                proceeds: ui_value(token, disposal.amount, disposal.price),
+                basis,
+                holding_period: HoldingPeriod::NotApplicable,
+                rule: "adjusted cost base",
+            });
+        }
+        Ok(taxable_disposals)
+    }
+
+    fn taxable_gain(&self, gain: f64) -> f64 {
+        gain * 0.5
+    }
+
+    fn report_summary(&self, taxable_disposals: &[TaxableDisposal]) -> Vec<(&'static str, f64)> {
+        let gain = sum_of(taxable_disposals, |td| Some(td.gain()));
+        vec![
+            (
+                "Proceeds of disposition",
+                sum_of(taxable_disposals, |td| Some(td.proceeds)),
+            ),
+            (
+                "Adjusted cost base",
+                sum_of(taxable_disposals, |td| Some(td.basis)),
+            ),
+            ("Capital gain (loss)", gain),
+            ("Taxable capital gain (loss)", self.taxable_gain(gain)),
+        ]
+    }
+}
+
+/// Private sales (§ 23 EStG): disposals are matched first-in first-out, and gains on tokens
+/// held for more than one year are tax free
+pub struct GermanyTaxRules;
+
+impl TaxRules for GermanyTaxRules {
+    fn jurisdiction(&self) -> TaxJurisdiction {
+        TaxJurisdiction::Germany
+    }
+
+    fn holding_period(&self, acquired: NaiveDate, disposed: NaiveDate) -> HoldingPeriod {
+        if disposed > acquired + Months::new(12) {
+            HoldingPeriod::Exempt
+        } else {
+            HoldingPeriod::ShortTerm
+        }
+    }
+
+    fn match_disposals(
+        &self,
+        token: MaybeToken,
+        mut acquisitions: Vec<Acquisition>,
+        disposals: Vec<Disposal>,
+    ) -> Result<Vec<TaxableDisposal>, String> {
+        let mut taxable_disposals = vec![];
+        for disposal in disposals {
+            let proceeds_per_unit =
+                ui_value(token, disposal.amount, disposal.price) / disposal.amount as f64;
+            let matches =
+                take_acquisitions(token, &mut acquisitions, disposal.amount, |acquisition| {
+                    acquisition.when <= disposal.when
+                });
+            let matched_amount = matches.iter().map(|(_, amount, _)| amount).sum::<u64>();
+            if matched_amount < disposal.amount {
+                return Err(unmatched_disposal(
+                    token,
+                    disposal.when,
+                    disposal.amount,
+                    matched_amount,
+                ));
+            }
+            for (acquired, amount, basis) in matches {
+                taxable_disposals.push(TaxableDisposal {
+                    token,
+                    when: disposal.when,
+                    amount,
+                    acquired: Some(acquired),
+                    proceeds: proceeds_per_unit * amount as f64,
+                    basis,
+                    holding_period: self.holding_period(acquired, disposal.when),
+                    rule: "fifo",
+                });
+            }
+        }
+        Ok(taxable_disposals)
+    }
+
+    fn report_summary(&self, taxable_disposals: &[TaxableDisposal]) -> Vec<(&'static str, f64)> {
+        let gain = |exempt: bool| {
+            sum_of(taxable_disposals, |td| {
+                ((td.holding_period == HoldingPeriod::Exempt) == exempt).then(|| td.gain())
+            })
+        };
+        vec![
+            (
+                "Sale proceeds, held one year or less",
+                sum_of(taxable_disposals, |td| {
+                    (td.holding_period != HoldingPeriod::Exempt).then_some(td.proceeds)
+                }),
+            ),
+            (
+                "Acquisition costs, held one year or less",
+                sum_of(taxable_disposals, |td| {
+                    (td.holding_period != HoldingPeriod::Exempt).then_some(td.basis)
+                }),
+            ),
+            ("Taxable private sales gain (loss)", gain(false)),
+            ("Tax-free gain, held over one year", gain(true)),
+        ]
+    }
+}
+
+/// Match every disposal recorded in `db` according to the database's tax jurisdiction
+pub fn taxable_disposals(db: &Db) -> Result<Vec<TaxableDisposal>, String> {
+    let tax_rules = db.get_tax_jurisdiction().tax_rules();
+
+    // wSOL and SOL are the same asset
+    let asset = |token: MaybeToken| {
+        if token == Token::wSOL.into() {
+            MaybeToken::SOL()
+        } else {
+            token
+        }
+    };
+
+    let mut acquisitions = BTreeMap::<MaybeToken, Vec<Acquisition>>::default();
+    let mut disposals = BTreeMap::<MaybeToken, Vec<Disposal>>::default();
+
+    for (token, lot) in db.held_lots() {
+        acquisitions
+            .entry(asset(token))
+            .or_default()
+            .push(Acquisition {
+                when: lot.acquisition.when,
+                amount: lot.amount,
+                price: lot.acquisition.price(),
+            });
+    }
+    for disposed_lot in db.disposed_lots() {
+        let token = asset(disposed_lot.token);
+        acquisitions.entry(token).or_default().push(Acquisition {
+            when: disposed_lot.lot.acquisition.when,
+            amount: disposed_lot.lot.amount,
+            price: disposed_lot.lot.acquisition.price(),
+        });
+        disposals.entry(token).or_default().push(Disposal {
+            when: disposed_lot.when,
+            amount: disposed_lot.lot.amount,
+            price: disposed_lot.price(),
+            disposed_lots: vec![disposed_lot],
+        });
+    }
+
+    let mut taxable_disposals = vec![];
+    for (token, mut disposals) in disposals {
+        let mut acquisitions = acquisitions.remove(&token).unwrap_or_default();
+        acquisitions.sort_by_key(|acquisition| acquisition.when);
+        disposals.sort_by_key(|disposal| disposal.when);
+
+        taxable_disposals.extend(
+            tax_rules
+                .match_disposals(token, acquisitions, disposals)
+                .map_err(|err| format!("{}: {token}: {err}", tax_rules.jurisdiction()))?,
+        );
+    }
+    taxable_disposals.sort_by_key(|taxable_disposal| taxable_disposal.when);
+    Ok(taxable_disposals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAMPORTS_PER_SOL: u64 = 1_000_000_000;
+
+    fn ymd(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn acquisition(when: NaiveDate, sol: u64, price: u64) -> Acquisition {
+        Acquisition {
+            when,
+            amount: sol * LAMPORTS_PER_SOL,
+            price: Decimal::from(price),
+        }
+    }
+
+    fn disposal(when: NaiveDate, sol: u64, price: u64) -> Disposal {
+        Disposal {
+            when,
+            amount: sol * LAMPORTS_PER_SOL,
+            price: Decimal::from(price),
+            disposed_lots: vec![],
+        }
+    }
+
+    fn assert_taxable_disposal(
+        taxable_disposal: &TaxableDisposal,
+        sol: u64,
+        acquired: Option<NaiveDate>,
+        proceeds: f64,
+        basis: f64,
+        rule: &str,
+    ) {
+        assert_eq!(taxable_disposal.amount, sol * LAMPORTS_PER_SOL);
+        assert_eq!(taxable_disposal.acquired, acquired);
+        assert!(
+            (taxable_disposal.proceeds - proceeds).abs() < 1e-6,
+            "proceeds: {} != {proceeds}",
+            taxable_disposal.proceeds
+        );
+        assert!(
+            (taxable_disposal.basis - basis).abs() < 1e-6,
+            "basis: {} != {basis}",
+            taxable_disposal.basis
+        );
+        assert_eq!(taxable_disposal.rule, rule);
+    }
+
+    #[test]
+    fn test_uk_same_day() {
+        let taxable_disposals = UnitedKingdomTaxRules
+            .match_disposals(
+                MaybeToken::SOL(),
+                vec![
+                    acquisition(ymd(2023, 1, 1), 10, 10),
+                    acquisition(ymd(2023, 6, 1), 5, 20),
+                ],
+                vec![disposal(ymd(2023, 6, 1), 5, 30)],
+            )
+            .unwrap();
+
+        assert_eq!(taxable_disposals.len(), 1);
+        assert_taxable_disposal(
+            &taxable_disposals[0],
+            5,
+            Some(ymd(2023, 6, 1)),
+            150.,
+            100.,
+            "same day",
+        );
+    }
+
+    #[test]
+    fn test_uk_thirty_day_then_section_104() {
+        let taxable_disposals = UnitedKingdomTaxRules
+            .match_disposals(
+                MaybeToken::SOL(),
+                vec![
+                    acquisition(ymd(2023, 1, 1), 10, 10),
+                    acquisition(ymd(2023, 6, 15), 4, 25),
+                    acquisition(ymd(2023, 7, 15), 1, 50), // Outside the 30 day window
+                ],
+                vec![disposal(ymd(2023, 6, 1), 6, 30)],
+            )
+            .unwrap();
+
+        assert_eq!(taxable_disposals.len(), 2);
+        assert_taxable_disposal(
+            &taxable_disposals[0],
+            4,
+            Some(ymd(2023, 6, 15)),
+            120.,
+            100.,
+            "30 day",
+        );
+        assert_taxable_disposal(&taxable_disposals[1], 2, None, 60., 20., "section 104");
+    }
+
+    #[test]
+    fn test_uk_unmatched_disposal() {
+        assert!(UnitedKingdomTaxRules
+            .match_disposals(
+                MaybeToken::SOL(),
+                vec![acquisition(ymd(2023, 1, 1), 1, 10)],
+                vec![disposal(ymd(2023, 1, 1), 2, 30)],
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_canada_adjusted_cost_base() {
+        let taxable_disposals = CanadaTaxRules
+            .match_disposals(
+                MaybeToken::SOL(),
+                vec![
+                    acquisition(ymd(2023, 1, 1), 10, 10),
+                    acquisition(ymd(2023, 2, 1), 10, 20),
+                    acquisition(ymd(2023, 4, 1), 5, 40),
+                ],
+                vec![
+                    disposal(ymd(2023, 3, 1), 5, 30),
+                    disposal(ymd(2023, 5, 1), 10, 50),
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(taxable_disposals.len(), 2);
+        // Average cost of $15 over the first 20 SOL
+        assert_taxable_disposal(
+            &taxable_disposals[0],
+            5,
+            None,
+            150.,
+            75.,
+            "adjusted cost base",
+        );
+        // The remaining 15 SOL cost $225, plus 5 SOL at $40
+        assert_taxable_disposal(
+            &taxable_disposals[1],
+            10,
+            None,
+            500.,
+            212.5,
+            "adjusted cost base",
+        );
+        assert_eq!(CanadaTaxRules.taxable_gain(287.5), 143.75);
+    }
+
+    #[test]
+    fn test_canada_unmatched_disposal() {
+        assert!(CanadaTaxRules
+            .match_disposals(
+                MaybeToken::SOL(),
+                vec![acquisition(ymd(2023, 2, 1), 10, 10)],
+                vec![disposal(ymd(2023, 1, 1), 1, 30)],
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_germany_one_year_exemption() {
+        let germany = GermanyTaxRules;
+        assert_eq!(
+            germany.holding_period(ymd(2022, 3, 15), ymd(2023, 3, 15)),
+            HoldingPeriod::ShortTerm
+        );
+        assert_eq!(
+            germany.holding_period(ymd(2022, 3, 15), ymd(2023, 3, 16)),
+            HoldingPeriod::Exempt
+        );
+
+        let taxable_disposals = germany
+            .match_disposals(
+                MaybeToken::SOL(),
+                vec![
+                    acquisition(ymd(2022, 3, 15), 1, 10),
+                    acquisition(ymd(2022, 3, 16), 1, 20),
+                ],
+                vec![disposal(ymd(2023, 3, 16), 2, 30)],
+            )
+            .unwrap();
+
+        assert_eq!(taxable_disposals.len(), 2);
+        assert_taxable_disposal(
+            &taxable_disposals[0],
+            1,
+            Some(ymd(2022, 3, 15)),
+            30.,
+            10.,
+            "fifo",
+        );
+        assert_eq!(taxable_disposals[0].holding_period, HoldingPeriod::Exempt);
+        assert_taxable_disposal(
+            &taxable_disposals[1],
+            1,
+            Some(ymd(2022, 3, 16)),
+            30.,
+            20.,
+            "fifo",
+        );
+        assert_eq!(
+            taxable_disposals[1].holding_period,
+            HoldingPeriod::ShortTerm
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use {
-    crate::coin_gecko,
+    crate::price_source::{self, PriceSourceKind},
     chrono::prelude::*,
     rust_decimal::prelude::*,
     separator::FixedPlaceSeparatable,
@@ -264,10 +264,10 @@ impl Token {
     #[async_recursion::async_recursion(?Send)]
     pub async fn get_current_price(
         &self,
-        _rpc_client: &RpcClient,
-    ) -> Result<Decimal, Box<dyn std::error::Error>> {
+        rpc_client: &RpcClient,
+    ) -> Result<(Decimal, Option<PriceSourceKind>), Box<dyn std::error::Error>> {
         if self.fiat_fungible() {
-            return Ok(Decimal::from_f64(1.).unwrap());
+            return Ok((Decimal::from_f64(1.).unwrap(), None));
         }
         match self {
             Token::USDC
@@ -288,7 +288,9 @@ impl Token {
             | Token::PYTH
             | Token::WEN
             | Token::WIF
-            | Token::PYUSD => coin_gecko::get_current_price(&MaybeToken(Some(*self))).await,
+            | Token::PYUSD => price_source::get_current_price(rpc_client, &MaybeToken(Some(*self)))
+                .await
+                .map(|(price, source)| (price, Some(source))),
             Token::tuUSDC | Token::tuSOL | Token::tumSOL | Token::tustSOL => {
                 Err("tulip support disabled".into())
                 //crate::tulip::get_current_price(rpc_client, self).await
@@ -298,15 +300,17 @@ impl Token {
 
     pub async fn get_historical_price(
         &self,
-        _rpc_client: &RpcClient,
+        rpc_client: &RpcClient,
         when: NaiveDate,
-    ) -> Result<Decimal, Box<dyn std::error::Error>> {
+    ) -> Result<(Decimal, Option<PriceSourceKind>), Box<dyn std::error::Error>> {
         if self.fiat_fungible() {
-            return Ok(Decimal::from_f64(1.).unwrap());
+            return Ok((Decimal::from_f64(1.).unwrap(), None));
         }
         match self {
             Token::USDC | Token::PYUSD => {
-                coin_gecko::get_historical_price(when, &MaybeToken(Some(*self))).await
+                price_source::get_historical_price(rpc_client, when, &MaybeToken(Some(*self)))
+                    .await
+                    .map(|(price, source)| (price, Some(source)))
             }
             unsupported_token => Err(format!(
                 "Historical price data is not available for {}",
@@ -369,6 +373,13 @@ impl MaybeToken {
         self.is_sol() || self.token() == Some(Token::wSOL)
     }
 
+    pub fn decimals(&self) -> u8 {
+        match self.0 {
+            None => 9,
+            Some(token) => token.decimals(),
+        }
+    }
+
     pub fn ui_amount(&self, amount: u64) -> f64 {
         match self.0 {
             None => lamports_to_sol(amount),
@@ -447,8 +458,21 @@ impl MaybeToken {
         &self,
         rpc_client: &RpcClient,
     ) -> Result<Decimal, Box<dyn std::error::Error>> {
+        self.get_current_price_with_source(rpc_client)
+            .await
+            .map(|(price, _)| price)
+    }
+
+    /// Current price along with the price source that provided it, `None` for fiat fungible
+    /// tokens
+    pub async fn get_current_price_with_source(
+        &self,
+        rpc_client: &RpcClient,
+    ) -> Result<(Decimal, Option<PriceSourceKind>), Box<dyn std::error::Error>> {
         match self.0 {
-            None => coin_gecko::get_current_price(self).await,
+            None => price_source::get_current_price(rpc_client, self)
+                .await
+                .map(|(price, source)| (price, Some(source))),
             Some(token) => token.get_current_price(rpc_client).await,
         }
     }
@@ -458,8 +482,22 @@ impl MaybeToken {
         rpc_client: &RpcClient,
         when: NaiveDate,
     ) -> Result<Decimal, Box<dyn std::error::Error>> {
+        self.get_historical_price_with_source(rpc_client, when)
+            .await
+            .map(|(price, _)| price)
+    }
+
+    /// Historical price along with the price source that provided it, `None` for fiat fungible
+    /// tokens
+    pub async fn get_historical_price_with_source(
+        &self,
+        rpc_client: &RpcClient,
+        when: NaiveDate,
+    ) -> Result<(Decimal, Option<PriceSourceKind>), Box<dyn std::error::Error>> {
         match self.0 {
-            None => coin_gecko::get_historical_price(when, self).await,
+            None => price_source::get_historical_price(rpc_client, when, self)
+                .await
+                .map(|(price, source)| (price, Some(source))),
             Some(token) => token.get_historical_price(rpc_client, when).await,
         }
     }